@@ -11,17 +11,42 @@
 
 use anchor_lang::prelude::*;
 use anchor_lang::solana_program::pubkey::Pubkey;
+use anchor_spl::token::{self, Mint, Token, TokenAccount, Transfer};
 
 declare_id!("FqzkXZdwYjurnUKetJCAvaUw5WAqbwzU6gZEwydeEfqS");
 
 const STAKING_APY_BASIS_POINTS: u64 = 500; // 5% APY
 const LENDING_INTEREST_RATE: u64 = 13; // 13% interest rate
+const LENDING_INTEREST_RATE_BPS: u64 = LENDING_INTEREST_RATE * (BASIS_POINTS_DIVISOR / PERCENTAGE_DIVISOR);
 const PERCENTAGE_DIVISOR: u64 = 100;
 const BASIS_POINTS_DIVISOR: u64 = 10000;
 const COLLATERAL_RATIO: u64 = 80; // 80% collateral requirement
+const STAKE_COLLATERAL_FACTOR: u64 = 90; // % of staked balance counted as loan collateral
 const SLOTS_PER_YEAR: u64 = 432000 * 365;
+const MIN_STAKING_SLOTS: u64 = 216_000; // ~1 day at 400ms slots
+const SLOTS_PER_DAY: u64 = SLOTS_PER_YEAR / 365;
+// (minimum lock slots, APY multiplier in bps); longer commitments earn more
+const LOCK_TIERS: [(u64, u64); 3] = [
+    (0, 10_000),
+    (30 * SLOTS_PER_DAY, 12_500),
+    (90 * SLOTS_PER_DAY, 15_000),
+];
+const SECONDS_PER_YEAR: u64 = 365 * 24 * 60 * 60;
+const LIQUIDATION_BONUS_BASIS_POINTS: u64 = 500; // 5% of the liquidated debt
+const LIQUIDATION_HEALTH_FACTOR_BPS: u64 = 10_000; // Loans below a 1.0 health factor may be liquidated
+const EARLY_UNSTAKE_PENALTY_BPS: u64 = 1_000; // 10% of principal for breaking a lock
+const COMPOUND_PERIOD_SECONDS: u64 = 24 * 60 * 60; // Daily compounding
+const REWARD_FUNDING_PERIOD_SECONDS: i64 = 24 * 60 * 60; // Window for the `pull_reward_funding` limit
+const MAX_COMPOUND_PERIODS: u64 = 3 * 365; // Bounds compute; later time accrues simple interest
+const COMPOUND_PRECISION: u128 = 1_000_000_000; // Fixed-point scale so daily interest is not floored each period
+const MIN_DEPOSIT_AMOUNT: u64 = 1_000_000; // 0.001 tokens with 9 decimals
 const MAX_DEPOSIT_AMOUNT: u64 = 1_000_000 * 1_000_000_000; // 1M tokens with 9 decimals
-const INITIAL_BANK_BALANCE: u64 = 5000 * 1_000_000_000; // 5000 tokens with 9 decimals
+const MAX_BATCH_SIZE: usize = 16; // Accounts a single batch instruction may process
+const MAX_WITHDRAW_WHITELIST: usize = 4; // Destinations a user may pre-approve for withdrawals
+const MAX_BUCKET_NAME_LEN: usize = 32; // Bucket names double as PDA seeds
+const INSURANCE_BUCKET_NAME: &str = "insurance"; // Receives liquidation bonuses above `max_liquidator_bonus`
+const EVENT_SCHEMA_VERSION: u8 = 11; // Bump whenever an event's fields change
+const REWARD_PRECISION: u128 = 1_000_000_000_000; // Fixed-point scale for the per-share accumulator
 
 #[error_code]
 pub enum ErrorCode {
@@ -53,42 +78,223 @@ pub enum ErrorCode {
     NoActiveLoan,
     #[msg("Minimum staking period not met")]
     MinimumStakingPeriodNotMet,
+    #[msg("Total value locked cap exceeded")]
+    TvlCapExceeded,
+    #[msg("Stake amount too small to earn rewards")]
+    StakeTooSmallForRewards,
+    #[msg("Delegate allowance exceeded")]
+    AllowanceExceeded,
+    #[msg("Account is frozen")]
+    AccountFrozen,
+    #[msg("Maximum number of open loans reached")]
+    TooManyOpenLoans,
+    #[msg("Batch exceeds maximum size")]
+    BatchTooLarge,
+    #[msg("A rate-changing proposal is active")]
+    RateProposalActive,
+    #[msg("Interest rate exceeds the maximum acceptable rate")]
+    RateAboveMax,
+    #[msg("Collateral ratio was changed too recently")]
+    RatioChangeCooldown,
+    #[msg("Token mint does not match the bank's mint")]
+    InvalidMint,
+    #[msg("Deposit amount is below the minimum")]
+    DepositTooSmall,
+    #[msg("Withdrawal destination is not whitelisted")]
+    DestinationNotWhitelisted,
+    #[msg("Too many whitelisted destinations")]
+    WhitelistTooLarge,
+    #[msg("Repayment exceeds the amount owed")]
+    RepaymentExceedsDebt,
+    #[msg("Bucket name is empty or too long")]
+    InvalidBucketName,
+    #[msg("Loan is sufficiently collateralized")]
+    NotLiquidatable,
+    #[msg("Program upgrade in progress")]
+    UpgradeInProgress,
+    #[msg("Claim pending rewards before deleting the account")]
+    UnclaimedRewards,
+    #[msg("Operation is paused")]
+    OperationPaused,
+    #[msg("Dynamic APY is not configured")]
+    DynamicApyDisabled,
+    #[msg("Deposit exceeds the allowed fraction of the pool")]
+    DepositTooLargeRelative,
+    #[msg("Unstake all tokens before deleting the account")]
+    ActiveStakeExists,
+    #[msg("Outflow limit for this transaction exceeded")]
+    TxOutflowExceeded,
+    #[msg("Loan exceeds the user's borrowing cap")]
+    UserLoanCapExceeded,
+    #[msg("Maximum number of users reached")]
+    UserCapReached,
+    #[msg("Lock has already ended")]
+    LockExpired,
+    #[msg("Loan accounts must list each open loan exactly once")]
+    IncompleteLoanSet,
+    #[msg("Pending reward is below the minimum claimable amount")]
+    RewardBelowMinimum,
+    #[msg("Reward funding limit for this period exceeded")]
+    RewardFundingLimitExceeded,
 }
 
 #[program]
 pub mod banking {
     use super::*;
 
-    /// Initialize the bank with admin controls
-    pub fn initialize_bank(ctx: Context<InitializeBank>) -> Result<()> {
+    /// Initialize the bank with admin controls; `max_users` of 0 allows unlimited accounts
+    pub fn initialize_bank(ctx: Context<InitializeBank>, max_users: u64) -> Result<()> {
         let bank = &mut ctx.accounts.bank_account;
         bank.admin = ctx.accounts.admin.key();
-        bank.balance = INITIAL_BANK_BALANCE;
+        bank.balance = 0; // Funded through add_bank_funds once the vault exists
         bank.lent_balance = 0;
         bank.staked_balance = 0;
         bank.total_users = 0;
-        bank.is_operational = true;
+        bank.paused_operations = 0;
+        bank.reward_per_slot = 0;
+        bank.acc_reward_per_share = 0;
+        bank.last_reward_slot = 0;
+        bank.total_deposits = 0;
+        bank.max_tvl = 0;
+        bank.early_repay_rebate_bps = 0;
+        bank.early_repay_window_seconds = 0;
+        bank.reject_unrewarded_stakes = false;
+        bank.protocol_owned_balance = 0;
+        bank.min_health_factor_bps = 0;
+        bank.intro_rate_bps = LENDING_INTEREST_RATE_BPS;
+        bank.open_loans = 0;
+        bank.max_open_loans = 0;
+        bank.decimals = ctx.accounts.mint.decimals;
+        bank.rate_proposal_active = false;
+        bank.collateral_ratio = COLLATERAL_RATIO;
+        bank.last_ratio_change_slot = 0;
+        bank.ratio_change_cooldown_slots = 0;
+        bank.report_deposits = 0;
+        bank.report_withdrawals = 0;
+        bank.report_interest_collected = 0;
+        bank.report_rewards_paid = 0;
+        bank.report_new_users = 0;
+        bank.report_closed_users = 0;
+        bank.last_report_slot = 0;
+        bank.mint = ctx.accounts.mint.key();
+        bank.min_residual_balance = 0;
+        bank.interest_free_loan_threshold = 0;
+        bank.bucketed_balance = 0;
+        bank.staking_apy_bps = STAKING_APY_BASIS_POINTS;
+        bank.apy_index = 0;
+        bank.apy_index_slot = 0;
+        bank.upgrade_mode = false;
+        bank.pending_admin = Pubkey::default();
+        bank.pay_rewards_on_delete = false;
+        bank.target_utilization_bps = 0;
+        bank.min_staking_apy_bps = 0;
+        bank.max_staking_apy_bps = 0;
+        bank.max_deposit_fraction_bps = 0;
+        bank.max_tx_outflow = 0;
+        bank.welcome_bonus = 0;
+        bank.total_interest_collected = 0;
+        bank.total_rewards_paid = 0;
+        bank.loan_rate_weight = 0;
+        bank.compound_interest = false;
+        bank.max_users = max_users;
+        bank.close_factor_bps = 0;
+        bank.max_liquidator_bonus = 0;
+        bank.clean_record_bonus_bps = 0;
+        bank.min_claimable_reward = 0;
+        bank.reward_funding_source = Pubkey::default();
+        bank.reward_funding_limit = 0;
+        bank.reward_funding_window_start = 0;
+        bank.reward_funded_in_window = 0;
+        bank.max_deposit = 0;
+        bank.deposit_cap_supply_bps = 0;
         
         msg!("Bank initialized with admin: {}", ctx.accounts.admin.key());
         Ok(())
     }
 
+    /// Admin function to create the program-owned vault holding deposited tokens
+    pub fn initialize_vault(ctx: Context<InitializeVault>) -> Result<()> {
+        msg!("Vault {} initialized for mint: {}", ctx.accounts.vault_token_account.key(), ctx.accounts.mint.key());
+        Ok(())
+    }
+
     /// Create a new user account
     pub fn create_user(ctx: Context<InitializeUser>) -> Result<()> {
         let user = &mut ctx.accounts.user_account;
         let bank = &mut ctx.accounts.bank_account;
         
+        // Check the participant cap
+        if bank.max_users > 0 && bank.total_users >= bank.max_users {
+            return Err(ErrorCode::UserCapReached.into());
+        }
+        
         user.balance = 0;
         user.staked_balance = 0;
         user.lent_balance = 0;
         user.stake_slot = 0;
         user.loan_timestamp = 0;
         user.owner = ctx.accounts.payer.key();
+        user.emit_balance_events = true;
+        user.reward_debt = 0;
+        user.reward_recipient = user.key();
+        user.delegate = Pubkey::default();
+        user.delegate_allowance = 0;
+        user.delegate_period_slots = 0;
+        user.delegate_period_start_slot = 0;
+        user.delegate_spent = 0;
+        user.is_frozen = false;
+        user.boost_bps = 0;
+        user.boost_start_slot = 0;
+        user.boost_until_slot = 0;
+        user.granted_stake = 0;
+        user.grant_unlock_slot = 0;
+        user.has_borrowed_before = false;
+        user.loan_rate_bps = 0;
+        user.vote_credits = 0;
+        user.auto_repay = false;
+        user.stop_loss_enabled = false;
+        user.stop_loss_solvency_bps = 0;
+        user.accumulated_apy_weight = 0;
+        user.accumulated_boost_weight = 0;
+        user.last_update_slot = 0;
+        user.withdraw_whitelist = Vec::new();
+        user.stake_start_slot = 0;
+        user.lifetime_interest_paid = 0;
+        user.loan_count = 0;
+        user.loan_rate_weight = 0;
+        user.loan_rate_time_weight = 0;
+        user.locked_balance = 0;
+        user.pending_reward = 0;
+        user.apy_index_checkpoint = 0;
+        user.auto_stake_enabled = false;
+        user.auto_stake_threshold = 0;
+        user.lock_end_slot = 0;
+        user.lock_multiplier_bps = BASIS_POINTS_DIVISOR;
+        user.has_staked_before = false;
+        user.max_loan_override = 0;
+        user.reward_cap = 0;
+        user.loan_carried_interest = 0;
+        user.compound_loan_principal = 0;
+        user.compound_rate_weight = 0;
+        user.compound_rate_time_weight = 0;
+        user.liquidation_count = 0;
+        user.locked_staked_balance = 0;
         
         // Update bank statistics
         bank.total_users = bank.total_users
             .checked_add(1)
             .ok_or(ErrorCode::ArithmeticOverflow)?;
+        bank.report_new_users = bank.report_new_users
+            .checked_add(1)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        
+        if bank.max_users > 0 && bank.total_users == bank.max_users {
+            emit!(UserCapReached {
+                schema_version: EVENT_SCHEMA_VERSION,
+                total_users: bank.total_users,
+                max_users: bank.max_users,
+            });
+        }
         
         msg!("User account created for: {}", ctx.accounts.payer.key());
         Ok(())
@@ -96,50 +302,128 @@ pub mod banking {
 
     /// Delete user account (close account and return rent)
     pub fn delete_user(ctx: Context<DeleteUser>) -> Result<()> {
-        let user = &ctx.accounts.user_account;
+        let vault = VaultAccounts::new(
+            &ctx.accounts.token_program,
+            &ctx.accounts.vault_token_account,
+            &ctx.accounts.bank_account
+        );
+        let user = &mut ctx.accounts.user_account;
         let bank = &mut ctx.accounts.bank_account;
+        let mut outflow = 0;
+        
+        // Open loans must be repaid before the account can go
+        if user.lent_balance > 0 {
+            return Err(ErrorCode::ActiveLoanExists.into());
+        }
+        
+        // Staked funds must be unstaked first
+        if user.staked_balance > 0 {
+            return Err(ErrorCode::ActiveStakeExists.into());
+        }
         
-        // Ensure user has no active balances
-        if user.balance > 0 || user.staked_balance > 0 || user.lent_balance > 0 {
+        // Ensure user has no remaining balance
+        if user.balance > 0 {
             return Err(ErrorCode::InsufficientBalance.into());
         }
         
+        // Rewards still owed would vanish with the account, so pay them out or refuse
+        let pending_reward = calculate_pending_reward(bank, user, ctx.accounts.clock.slot)?;
+        if pending_reward > 0 {
+            if !bank.pay_rewards_on_delete {
+                return Err(ErrorCode::UnclaimedRewards.into());
+            }
+            
+            // Check if bank can pay reward
+            if available_bank_balance(bank) < pending_reward {
+                return Err(ErrorCode::BankInsufficientFunds.into());
+            }
+            
+            bank.balance = bank.balance
+                .checked_sub(pending_reward)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+            record_outflow(bank, &mut outflow, pending_reward)?;
+            bank.report_rewards_paid = bank.report_rewards_paid
+                .checked_add(pending_reward)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+            bank.total_rewards_paid = bank.total_rewards_paid
+                .checked_add(pending_reward)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+            
+            // A designated recipient is credited like any other payout; the closing account itself
+            // has no balance left to credit, so its own reward goes straight to the owner's wallet
+            if user.reward_recipient == user.key() {
+                transfer_from_vault(&vault, ctx.bumps.bank_account, ctx.accounts.user_token_account.to_account_info(), pending_reward)?;
+            } else {
+                credit_reward(user, &mut ctx.accounts.reward_recipient, pending_reward)?;
+            }
+            
+            msg!("Paid {} pending reward on deletion for user: {}", pending_reward, ctx.accounts.payer.key());
+        }
+        
         // Update bank statistics
         bank.total_users = bank.total_users
             .checked_sub(1)
             .ok_or(ErrorCode::ArithmeticOverflow)?;
+        bank.report_closed_users = bank.report_closed_users
+            .checked_add(1)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
         
         msg!("User account deleted for: {}", ctx.accounts.payer.key());
         Ok(())
     }
 
     /// Deposit funds into user account
-    pub fn deposit(ctx: Context<Operations>, amount: u64) -> Result<()> {
+    pub fn deposit<'info>(ctx: Context<'_, '_, 'info, 'info, Operations<'info>>, amount: u64) -> Result<()> {
         // Validate input
-        if amount <= 0 {
-            return Err(ErrorCode::InvalidAmount.into());
+        validate_deposit_amount(amount)?;
+        
+        let transfer_ctx = CpiContext::new(
+            ctx.accounts.token_program.to_account_info(),
+            Transfer {
+                from: ctx.accounts.user_token_account.to_account_info(),
+                to: ctx.accounts.vault_token_account.to_account_info(),
+                authority: ctx.accounts.payer.to_account_info(),
+            },
+        );
+        let user = &mut ctx.accounts.user_account;
+        let bank = &mut ctx.accounts.bank_account;
+        
+        // Check if deposit is paused
+        if is_paused(bank, OpKind::Deposit) {
+            return Err(ErrorCode::OperationPaused.into());
         }
         
-        if amount > MAX_DEPOSIT_AMOUNT {
-            return Err(ErrorCode::AmountTooLarge.into());
+        // Check if account is frozen
+        if user.is_frozen {
+            return Err(ErrorCode::AccountFrozen.into());
         }
         
-        let user = &mut ctx.accounts.user_account;
-        let bank = &ctx.accounts.bank_account;
+        check_tvl_cap(bank, amount)?;
+        check_deposit_fraction(bank, amount)?;
+        check_max_deposit(bank, amount)?;
         
-        // Check if bank is operational
-        if !bank.is_operational {
-            return Err(ErrorCode::NotEligible.into());
-        }
+        // Move the tokens into the vault before crediting the ledger
+        token::transfer(transfer_ctx, amount)?;
         
         // Update user balance with overflow protection
         user.balance = user.balance
             .checked_add(amount)
             .ok_or(ErrorCode::ArithmeticOverflow)?;
+        bank.total_deposits = bank.total_deposits
+            .checked_add(amount)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        bank.report_deposits = bank.report_deposits
+            .checked_add(amount)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        
+        update_apy_index(bank, ctx.accounts.clock.slot)?;
+        try_auto_repay(bank, user, ctx.remaining_accounts, ctx.accounts.clock.unix_timestamp)?;
         
         emit!(DepositEvent {
+            schema_version: EVENT_SCHEMA_VERSION,
             user: ctx.accounts.payer.key(),
             amount,
+            ui_amount: format_ui_amount(amount, bank.decimals)?,
             new_balance: user.balance,
         });
         
@@ -154,12 +438,23 @@ pub mod banking {
             return Err(ErrorCode::InvalidAmount.into());
         }
         
+        let vault = VaultAccounts::new(
+            &ctx.accounts.token_program,
+            &ctx.accounts.vault_token_account,
+            &ctx.accounts.bank_account
+        );
         let user = &mut ctx.accounts.user_account;
-        let bank = &ctx.accounts.bank_account;
+        let bank = &mut ctx.accounts.bank_account;
+        let mut outflow = 0;
         
-        // Check if bank is operational
-        if !bank.is_operational {
-            return Err(ErrorCode::NotEligible.into());
+        // Check if withdraw is paused
+        if is_paused(bank, OpKind::Withdraw) {
+            return Err(ErrorCode::OperationPaused.into());
+        }
+        
+        // Check if account is frozen
+        if user.is_frozen {
+            return Err(ErrorCode::AccountFrozen.into());
         }
         
         // Check sufficient balance
@@ -167,14 +462,40 @@ pub mod banking {
             return Err(ErrorCode::InsufficientBalance.into());
         }
         
+        // Collateral locked by loans stays in the account
+        if spendable_balance(user) < amount {
+            return Err(ErrorCode::InvalidCollateralRatio.into());
+        }
+        
+        // Check the destination against the user's whitelist, if one is set
+        if !user.withdraw_whitelist.is_empty()
+            && !user.withdraw_whitelist.contains(&ctx.accounts.user_token_account.key())
+        {
+            return Err(ErrorCode::DestinationNotWhitelisted.into());
+        }
+        
+        // Release the tokens from the vault before debiting the ledger
+        transfer_from_vault(&vault, ctx.bumps.bank_account, ctx.accounts.user_token_account.to_account_info(), amount)?;
+        
         // Update user balance with underflow protection
         user.balance = user.balance
             .checked_sub(amount)
             .ok_or(ErrorCode::ArithmeticOverflow)?;
+        // Withdrawals can include rewards and loan proceeds, so only net deposits are released
+        bank.total_deposits = bank.total_deposits.saturating_sub(amount);
+        record_outflow(bank, &mut outflow, amount)?;
+        bank.report_withdrawals = bank.report_withdrawals
+            .checked_add(amount)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        
+        // Keep any active loan above the minimum health factor
+        assert_health_factor(bank, user, ctx.accounts.clock.unix_timestamp)?;
         
         emit!(WithdrawEvent {
+            schema_version: EVENT_SCHEMA_VERSION,
             user: ctx.accounts.payer.key(),
             amount,
+            ui_amount: format_ui_amount(amount, bank.decimals)?,
             new_balance: user.balance,
         });
         
@@ -182,653 +503,7345 @@ pub mod banking {
         Ok(())
     }
 
-    /// Check user balance and emit event
-    pub fn check_balance(ctx: Context<CheckBalance>) -> Result<()> {
-        let user = &ctx.accounts.user_account;
+    /// Authorize a delegate to withdraw up to `allowance` per `period_slots` (0 = never resets)
+    pub fn set_delegate_allowance(
+        ctx: Context<UserSettings>,
+        delegate: Pubkey,
+        allowance: u64,
+        period_slots: u64,
+    ) -> Result<()> {
+        let user = &mut ctx.accounts.user_account;
         
-        emit!(BalanceChecked {
-            user: user.owner,
-            balance: user.balance,
-            staked_balance: user.staked_balance,
-            lent_balance: user.lent_balance,
-        });
+        user.delegate = delegate;
+        user.delegate_allowance = allowance;
+        user.delegate_period_slots = period_slots;
+        user.delegate_period_start_slot = 0;
+        user.delegate_spent = 0;
         
+        msg!("Delegate {} allowed {} per {} slots for user: {}", delegate, allowance, period_slots, ctx.accounts.payer.key());
         Ok(())
     }
 
-    /// Stake tokens for rewards
-    pub fn stake(ctx: Context<Staking>, amount: u64) -> Result<()> {
+    /// Withdraw from a user's account as their delegate, within the remaining allowance
+    pub fn delegated_withdraw(ctx: Context<DelegatedWithdraw>, amount: u64) -> Result<()> {
         // Validate input
-        if amount <= 0 {
+        if amount == 0 {
             return Err(ErrorCode::InvalidAmount.into());
         }
         
+        let vault = VaultAccounts::new(
+            &ctx.accounts.token_program,
+            &ctx.accounts.vault_token_account,
+            &ctx.accounts.bank_account
+        );
         let user = &mut ctx.accounts.user_account;
         let bank = &mut ctx.accounts.bank_account;
+        let mut outflow = 0;
+        let current_slot = ctx.accounts.clock.slot;
         
-        // Check if bank is operational
-        if !bank.is_operational {
-            return Err(ErrorCode::NotEligible.into());
+        // Check if withdraw is paused
+        if is_paused(bank, OpKind::Withdraw) {
+            return Err(ErrorCode::OperationPaused.into());
         }
         
-        // Check sufficient balance
-        if user.balance < amount {
-            return Err(ErrorCode::InsufficientBalance.into());
+        // Check if account is frozen
+        if user.is_frozen {
+            return Err(ErrorCode::AccountFrozen.into());
         }
         
-        // If user already has staked balance, calculate and add rewards first
-        if user.staked_balance > 0 {
-            let reward = calculate_staking_reward(
-                user.staked_balance,
-                ctx.accounts.clock.slot,
-                user.stake_slot
-            )?;
-            
-            if reward > 0 {
-                // Check if bank can pay reward
-                if bank.balance < reward {
-                    return Err(ErrorCode::BankInsufficientFunds.into());
-                }
-                
-                user.balance = user.balance
-                    .checked_add(reward)
-                    .ok_or(ErrorCode::ArithmeticOverflow)?;
-                bank.balance = bank.balance
-                    .checked_sub(reward)
-                    .ok_or(ErrorCode::ArithmeticOverflow)?;
+        // Start a fresh allowance period once the previous one has elapsed
+        if user.delegate_period_slots > 0 {
+            let period_end = user.delegate_period_start_slot
+                .checked_add(user.delegate_period_slots)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+            if current_slot >= period_end {
+                user.delegate_period_start_slot = current_slot;
+                user.delegate_spent = 0;
             }
         }
         
-        // Update staking information
-        user.stake_slot = ctx.accounts.clock.slot;
-        user.balance = user.balance
-            .checked_sub(amount)
-            .ok_or(ErrorCode::ArithmeticOverflow)?;
-        user.staked_balance = user.staked_balance
-            .checked_add(amount)
-            .ok_or(ErrorCode::ArithmeticOverflow)?;
-        bank.staked_balance = bank.staked_balance
+        let spent = user.delegate_spent
             .checked_add(amount)
             .ok_or(ErrorCode::ArithmeticOverflow)?;
-        
-        emit!(StakeEvent {
-            user: ctx.accounts.payer.key(),
-            amount,
-            total_staked: user.staked_balance,
-        });
-        
-        msg!("Staked {} tokens for user: {}", amount, ctx.accounts.payer.key());
-        Ok(())
-    }
-
-    /// Unstake tokens and claim rewards
-    pub fn unstake(ctx: Context<Staking>, amount: u64) -> Result<()> {
-        // Validate input
-        if amount == 0 {
-            return Err(ErrorCode::InvalidAmount.into());
+        if spent > user.delegate_allowance {
+            return Err(ErrorCode::AllowanceExceeded.into());
         }
         
-        let user = &mut ctx.accounts.user_account;
-        let bank = &mut ctx.accounts.bank_account;
-        
-        // Check if user has sufficient staked balance
-        if user.staked_balance < amount {
+        // Check sufficient balance; collateral locked by loans is not spendable
+        if spendable_balance(user) < amount {
             return Err(ErrorCode::InsufficientBalance.into());
         }
         
-        // Calculate rewards
-        let reward = calculate_staking_reward(
-            amount,
-            ctx.accounts.clock.slot,
-            user.stake_slot
-        )?;
-        
-        // Check if bank can pay reward
-        if bank.balance < reward {
-            return Err(ErrorCode::BankInsufficientFunds.into());
+        // Delegates are bound by the owner's whitelist just like the owner
+        if !user.withdraw_whitelist.is_empty()
+            && !user.withdraw_whitelist.contains(&ctx.accounts.destination_token_account.key())
+        {
+            return Err(ErrorCode::DestinationNotWhitelisted.into());
         }
         
-        // Update balances
-        user.staked_balance = user.staked_balance
-            .checked_sub(amount)
-            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        // Release the tokens from the vault before debiting the ledger
+        transfer_from_vault(&vault, ctx.bumps.bank_account, ctx.accounts.destination_token_account.to_account_info(), amount)?;
+        
+        user.delegate_spent = spent;
         user.balance = user.balance
-            .checked_add(amount)
-            .ok_or(ErrorCode::ArithmeticOverflow)?
-            .checked_add(reward)
-            .ok_or(ErrorCode::ArithmeticOverflow)?;
-        bank.staked_balance = bank.staked_balance
             .checked_sub(amount)
             .ok_or(ErrorCode::ArithmeticOverflow)?;
-        bank.balance = bank.balance
-            .checked_sub(reward)
+        bank.total_deposits = bank.total_deposits.saturating_sub(amount);
+        record_outflow(bank, &mut outflow, amount)?;
+        bank.report_withdrawals = bank.report_withdrawals
+            .checked_add(amount)
             .ok_or(ErrorCode::ArithmeticOverflow)?;
         
-        emit!(UnstakeEvent {
-            user: ctx.accounts.payer.key(),
+        // Keep any active loan above the minimum health factor
+        assert_health_factor(bank, user, ctx.accounts.clock.unix_timestamp)?;
+        
+        emit!(DelegatedWithdrawEvent {
+            schema_version: EVENT_SCHEMA_VERSION,
+            user: user.owner,
+            delegate: ctx.accounts.delegate.key(),
             amount,
-            reward,
-            remaining_staked: user.staked_balance,
+            remaining_allowance: user.delegate_allowance.saturating_sub(spent),
         });
         
-        msg!("Unstaked {} tokens with {} reward for user: {}", amount, reward, ctx.accounts.payer.key());
+        msg!("Delegate {} withdrew {} tokens for user: {}", ctx.accounts.delegate.key(), amount, user.owner);
         Ok(())
     }
 
-    /// Borrow funds from the bank
-    pub fn borrow(ctx: Context<LoanOperations>, amount: u64) -> Result<()> {
-        // Validate input
-        if amount == 0 {
-            return Err(ErrorCode::InvalidAmount.into());
-        }
-        
-        let user = &mut ctx.accounts.user_account;
-        let bank = &mut ctx.accounts.bank_account;
+    /// Check user balance and emit event
+    pub fn check_balance(ctx: Context<CheckBalance>) -> Result<()> {
+        let user = &ctx.accounts.user_account;
         
-        // Check if bank is operational
-        if !bank.is_operational {
-            return Err(ErrorCode::NotEligible.into());
+        // Respect the user's opt-out from balance event emission
+        if !user.emit_balance_events {
+            return Ok(());
         }
         
-        // Check if user already has an active loan
-        if user.lent_balance > 0 {
-            return Err(ErrorCode::ActiveLoanExists.into());
-        }
+        emit!(BalanceChecked {
+            schema_version: EVENT_SCHEMA_VERSION,
+            user: user.owner,
+            balance: user.balance,
+            staked_balance: user.staked_balance,
+            lent_balance: user.lent_balance,
+        });
         
-        // Check if bank has sufficient funds
-        if bank.balance < amount {
-            return Err(ErrorCode::BankInsufficientFunds.into());
-        }
+        Ok(())
+    }
+
+    /// Report how much a user could withdraw right now without breaching bank or loan limits
+    pub fn get_withdrawable(ctx: Context<UserView>) -> Result<()> {
+        let user = &ctx.accounts.user_account;
+        let bank = &ctx.accounts.bank_account;
         
-        // Calculate maximum borrowing amount based on collateral (80% of balance)
-        let max_borrow = user.balance
-            .checked_mul(COLLATERAL_RATIO)
-            .ok_or(ErrorCode::ArithmeticOverflow)?
-            .checked_div(PERCENTAGE_DIVISOR)
-            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        let withdrawable = if is_paused(bank, OpKind::Withdraw) || user.is_frozen {
+            0
+        } else if user.lent_balance == 0 || bank.min_health_factor_bps == 0 {
+            user.balance
+        } else {
+            // Collateral that must stay behind to keep the loan at the minimum health factor
+            let debt = calculate_loan_debt(user, ctx.accounts.clock.unix_timestamp)?;
+            let numerator = (debt as u128)
+                .checked_mul(bank.min_health_factor_bps as u128)
+                .ok_or(ErrorCode::ArithmeticOverflow)?
+                .checked_mul(PERCENTAGE_DIVISOR as u128)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+            let denominator = (bank.collateral_ratio as u128)
+                .checked_mul(BASIS_POINTS_DIVISOR as u128)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+            let required = numerator.div_ceil(denominator);
+            
+            u64::try_from(total_collateral(user).saturating_sub(required).min(user.balance as u128))
+                .map_err(|_| ErrorCode::ArithmeticOverflow)?
+        };
+        let withdrawable = withdrawable.min(spendable_balance(user));
         
-        if amount > max_borrow {
-            return Err(ErrorCode::InvalidCollateralRatio.into());
-        }
+        emit!(Withdrawable {
+            schema_version: EVENT_SCHEMA_VERSION,
+            user: user.owner,
+            balance: user.balance,
+            withdrawable,
+        });
         
-        // Update balances
-        user.lent_balance = amount;
-        user.balance = user.balance
-            .checked_add(amount)
-            .ok_or(ErrorCode::ArithmeticOverflow)?;
-        user.loan_timestamp = ctx.accounts.clock.unix_timestamp;
-        bank.balance = bank.balance
-            .checked_sub(amount)
-            .ok_or(ErrorCode::ArithmeticOverflow)?;
-        bank.lent_balance = bank.lent_balance
-            .checked_add(amount)
-            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        Ok(())
+    }
+
+    /// Check a single user's position for internally inconsistent state
+    pub fn verify_user(ctx: Context<VerifyUser>) -> Result<()> {
+        let user = &ctx.accounts.user_account;
+        let clock = &ctx.accounts.clock;
         
-        emit!(BorrowEvent {
-            user: ctx.accounts.payer.key(),
-            amount,
-            collateral_used: user.balance.checked_sub(amount).unwrap_or(0),
+        let stake_slot_in_future = user.stake_slot > clock.slot;
+        let stake_slot_unset = user.staked_balance > 0 && user.stake_slot == 0;
+        let loan_timestamp_mismatch = (user.lent_balance > 0) != (user.loan_timestamp > 0)
+            || user.loan_timestamp > clock.unix_timestamp;
+        // Collateral locked by loans must still be held as balance or stake
+        let collateral_shortfall = user.locked_balance as u128 > total_collateral(user);
+        
+        emit!(UserVerification {
+            schema_version: EVENT_SCHEMA_VERSION,
+            user: user.owner,
+            is_consistent: !(stake_slot_in_future || stake_slot_unset || loan_timestamp_mismatch || collateral_shortfall),
+            stake_slot_in_future,
+            stake_slot_unset,
+            loan_timestamp_mismatch,
+            collateral_shortfall,
         });
         
-        msg!("Borrowed {} tokens for user: {}", amount, ctx.accounts.payer.key());
         Ok(())
     }
 
-    /// Repay loan with interest
-    pub fn repay_loan(ctx: Context<LoanOperations>) -> Result<()> {
+    /// Opt in or out of `BalanceChecked` emission for this user
+    pub fn set_balance_events(ctx: Context<UserSettings>, enabled: bool) -> Result<()> {
         let user = &mut ctx.accounts.user_account;
-        let bank = &mut ctx.accounts.bank_account;
+        user.emit_balance_events = enabled;
         
-        // Check if user has an active loan
-        if user.lent_balance == 0 {
-            return Err(ErrorCode::NoActiveLoan.into());
-        }
+        msg!("Balance events set to {} for user: {}", enabled, ctx.accounts.payer.key());
+        Ok(())
+    }
+
+    /// Opt in or out of repaying the loan automatically once incoming funds cover it
+    pub fn set_auto_repay(ctx: Context<UserSettings>, enabled: bool) -> Result<()> {
+        let user = &mut ctx.accounts.user_account;
+        user.auto_repay = enabled;
         
-        // Calculate interest based on time elapsed
-        let time_elapsed = ctx.accounts.clock.unix_timestamp
-            .checked_sub(user.loan_timestamp)
-            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        msg!("Auto repay set to {} for user: {}", enabled, ctx.accounts.payer.key());
+        Ok(())
+    }
+
+    /// Configure the bank solvency (reserves over stakes, in bps) below which a stop-loss may unstake
+    pub fn set_stop_loss(ctx: Context<UserSettings>, enabled: bool, solvency_threshold_bps: u64) -> Result<()> {
+        let user = &mut ctx.accounts.user_account;
+        user.stop_loss_enabled = enabled;
+        user.stop_loss_solvency_bps = solvency_threshold_bps;
         
-        // Calculate interest (simple interest for demonstration)
-        let interest = calculate_loan_interest(user.lent_balance, time_elapsed)?;
-        let total_repayment = user.lent_balance
-            .checked_add(interest)
-            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        msg!("Stop-loss set to {} at {} bps for user: {}", enabled, solvency_threshold_bps, ctx.accounts.payer.key());
+        Ok(())
+    }
+
+    /// Opt in to keeper sweeps that stake any spendable balance above `threshold`
+    pub fn set_auto_stake(ctx: Context<UserSettings>, enabled: bool, threshold: u64) -> Result<()> {
+        let user = &mut ctx.accounts.user_account;
+        user.auto_stake_enabled = enabled;
+        user.auto_stake_threshold = threshold;
         
-        // Check if user has sufficient balance to repay
-        if user.balance < total_repayment {
-            return Err(ErrorCode::InsufficientBalance.into());
+        msg!("Auto-stake set to {} above {} for user: {}", enabled, threshold, ctx.accounts.payer.key());
+        Ok(())
+    }
+
+    /// Restrict withdrawals to the given destination token accounts (empty list allows any)
+    pub fn set_withdraw_whitelist(ctx: Context<UserSettings>, destinations: Vec<Pubkey>) -> Result<()> {
+        if destinations.len() > MAX_WITHDRAW_WHITELIST {
+            return Err(ErrorCode::WhitelistTooLarge.into());
         }
         
-        // Update balances
-        let principal = user.lent_balance;
-        bank.lent_balance = bank.lent_balance
-            .checked_sub(principal)
-            .ok_or(ErrorCode::ArithmeticOverflow)?;
-        bank.balance = bank.balance
-            .checked_add(total_repayment)
-            .ok_or(ErrorCode::ArithmeticOverflow)?;
-        user.balance = user.balance
-            .checked_sub(total_repayment)
-            .ok_or(ErrorCode::ArithmeticOverflow)?;
-        user.lent_balance = 0;
-        user.loan_timestamp = 0;
+        let user = &mut ctx.accounts.user_account;
+        user.withdraw_whitelist = destinations;
         
-        emit!(RepayEvent {
-            user: ctx.accounts.payer.key(),
-            principal,
-            interest,
-            total_repayment,
-        });
+        msg!("Withdraw whitelist set to {} destinations for user: {}", user.withdraw_whitelist.len(), ctx.accounts.payer.key());
+        Ok(())
+    }
+
+    /// Route staking rewards to another user's account (pass your own account to reset)
+    pub fn set_reward_recipient(ctx: Context<SetRewardRecipient>) -> Result<()> {
+        let user = &mut ctx.accounts.user_account;
+        user.reward_recipient = ctx.accounts.recipient_account.key();
         
-        msg!("Repaid loan: {} principal + {} interest for user: {}", principal, interest, ctx.accounts.payer.key());
+        msg!("Reward recipient set to {} for user: {}", user.reward_recipient, ctx.accounts.payer.key());
         Ok(())
     }
 
-    /// Transfer funds between users
-    pub fn transfer_funds(ctx: Context<FundTransfer>, amount: u64) -> Result<()> {
+    /// Stake tokens for rewards
+    pub fn stake(ctx: Context<Staking>, amount: u64) -> Result<()> {
+        stake_locked(ctx, amount, 0)
+    }
+
+    /// Stake tokens and lock the whole position for at least `lock_slots` in exchange for a
+    /// higher APY tier. An existing lock is never shortened; its remaining slots count toward the tier.
+    pub fn stake_locked(ctx: Context<Staking>, amount: u64, lock_slots: u64) -> Result<()> {
         // Validate input
         if amount == 0 {
             return Err(ErrorCode::InvalidAmount.into());
         }
         
-        let from_user = &mut ctx.accounts.from_user;
-        let to_user = &mut ctx.accounts.to_user;
+        let user = &mut ctx.accounts.user_account;
+        let bank = &mut ctx.accounts.bank_account;
+        let mut outflow = 0;
         
-        // Check sufficient balance
-        if from_user.balance < amount {
-            return Err(ErrorCode::InsufficientBalance.into());
+        // Check if stake is paused
+        if is_paused(bank, OpKind::Stake) {
+            return Err(ErrorCode::OperationPaused.into());
         }
         
-        // Prevent self-transfer
-        if from_user.key() == to_user.key() {
-            return Err(ErrorCode::InvalidAddress.into());
+        // New positions would lock in terms that a pending proposal may change
+        if bank.rate_proposal_active {
+            return Err(ErrorCode::RateProposalActive.into());
         }
         
-        // Update balances
-        from_user.balance = from_user.balance
-            .checked_sub(amount)
-            .ok_or(ErrorCode::ArithmeticOverflow)?;
-        to_user.balance = to_user.balance
-            .checked_add(amount)
-            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        let current_slot = ctx.accounts.clock.slot;
+        let plan = plan_stake(bank, user, amount, lock_slots, current_slot, ctx.accounts.clock.unix_timestamp, true)?;
+        let payout = commit_stake(bank, user, &plan, amount, current_slot, true, &mut outflow)?;
+        credit_reward(user, &mut ctx.accounts.reward_recipient, payout)?;
         
-        emit!(TransferEvent {
-            from: from_user.owner,
-            to: to_user.owner,
+        emit!(StakeEvent {
+            schema_version: EVENT_SCHEMA_VERSION,
+            user: ctx.accounts.payer.key(),
             amount,
+            ui_amount: format_ui_amount(amount, bank.decimals)?,
+            total_staked: user.staked_balance,
+            lock_tier: plan.lock_tier,
+            lock_end_slot: plan.lock_end_slot,
         });
         
-        msg!("Transferred {} tokens from {} to {}", amount, from_user.owner, to_user.owner);
+        msg!("Staked {} tokens for user: {}", amount, ctx.accounts.payer.key());
         Ok(())
     }
 
-    /// Admin function to toggle bank operational status
-    pub fn toggle_bank_status(ctx: Context<AdminOperation>) -> Result<()> {
-        let bank = &mut ctx.accounts.bank_account;
+    /// Report the annualized yield a user's current stake has realized so far
+    pub fn get_realized_apy(ctx: Context<UserView>) -> Result<()> {
+        let user = &ctx.accounts.user_account;
+        let bank = &ctx.accounts.bank_account;
+        let current_slot = ctx.accounts.clock.slot;
         
-        // Check if caller is admin
-        if ctx.accounts.admin.key() != bank.admin {
-            return Err(ErrorCode::Unauthorized.into());
+        let accrued_reward = calculate_pending_reward(bank, user, current_slot)?;
+        let slots_elapsed = current_slot.saturating_sub(user.stake_slot);
+        
+        // Nothing has been realized without a stake or elapsed time
+        let realized_apy_bps = if user.staked_balance == 0 || slots_elapsed == 0 {
+            0
+        } else {
+            let apy = (accrued_reward as u128)
+                .checked_mul(BASIS_POINTS_DIVISOR as u128)
+                .ok_or(ErrorCode::ArithmeticOverflow)?
+                .checked_mul(SLOTS_PER_YEAR as u128)
+                .ok_or(ErrorCode::ArithmeticOverflow)?
+                .checked_div(
+                    (user.staked_balance as u128)
+                        .checked_mul(slots_elapsed as u128)
+                        .ok_or(ErrorCode::ArithmeticOverflow)?
+                )
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+            u64::try_from(apy).map_err(|_| ErrorCode::ArithmeticOverflow)?
+        };
+        
+        emit!(RealizedApy {
+            schema_version: EVENT_SCHEMA_VERSION,
+            user: user.owner,
+            staked_balance: user.staked_balance,
+            accrued_reward,
+            slots_elapsed,
+            realized_apy_bps,
+        });
+        
+        Ok(())
+    }
+
+    /// Report any user's pending staking reward without mutating state
+    pub fn peek_rewards(ctx: Context<PeekRewards>, target: Pubkey) -> Result<()> {
+        let user = &ctx.accounts.user_account;
+        let current_slot = ctx.accounts.clock.slot;
+        
+        let pending_reward = calculate_pending_reward(&ctx.accounts.bank_account, user, current_slot)?;
+        
+        emit!(PendingRewardPeek {
+            schema_version: EVENT_SCHEMA_VERSION,
+            user: target,
+            staked_balance: user.staked_balance,
+            pending_reward,
+            slot: current_slot,
+        });
+        
+        Ok(())
+    }
+
+    /// Report the caller's accrued but unclaimed staking reward; zero when nothing is staked
+    pub fn get_pending_reward(ctx: Context<UserView>) -> Result<()> {
+        let user = &ctx.accounts.user_account;
+        let current_slot = ctx.accounts.clock.slot;
+        
+        let pending_reward = calculate_pending_reward(&ctx.accounts.bank_account, user, current_slot)?;
+        let slots_elapsed = if user.staked_balance == 0 {
+            0
+        } else {
+            current_slot.saturating_sub(user.stake_slot)
+        };
+        
+        emit!(PendingRewardEvent {
+            schema_version: EVENT_SCHEMA_VERSION,
+            user: user.owner,
+            staked_balance: user.staked_balance,
+            pending_reward,
+            slots_elapsed,
+        });
+        
+        Ok(())
+    }
+
+    /// Quote the reward `unstake(amount)` would pay right now, failing where `unstake` would.
+    /// Unstaking carries no reward fees or early-exit penalties, so the reward is paid in full.
+    pub fn quote_net_reward(ctx: Context<UserView>, amount: u64) -> Result<()> {
+        // Validate input
+        if amount == 0 {
+            return Err(ErrorCode::InvalidAmount.into());
         }
         
-        bank.is_operational = !bank.is_operational;
+        let user = &ctx.accounts.user_account;
+        let bank = &ctx.accounts.bank_account;
+        let current_slot = ctx.accounts.clock.slot;
         
-        emit!(BankStatusChanged {
-            admin: ctx.accounts.admin.key(),
-            is_operational: bank.is_operational,
+        // Check if unstake is paused
+        if is_paused(bank, OpKind::Unstake) {
+            return Err(ErrorCode::OperationPaused.into());
+        }
+        
+        check_unstake_allowed(user, amount, current_slot, false)?;
+        
+        let net_reward = calculate_pending_reward(bank, user, current_slot)?;
+        
+        // Check if bank can pay reward
+        if available_bank_balance(bank) < net_reward {
+            return Err(ErrorCode::BankInsufficientFunds.into());
+        }
+        
+        // Mirror the checks `process_unstake` makes after paying out, without writing anything
+        check_outflow(bank, 0, net_reward)?;
+        let mut projected = (**user).clone();
+        if current_slot >= projected.grant_unlock_slot {
+            projected.granted_stake = 0;
+        }
+        projected.staked_balance = projected.staked_balance
+            .checked_sub(amount)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        projected.balance = projected.balance
+            .checked_add(amount)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        if projected.reward_recipient == user.key() {
+            projected.balance = projected.balance
+                .checked_add(net_reward)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+        }
+        assert_collateral_covered(&projected)?;
+        
+        emit!(NetRewardQuote {
+            schema_version: EVENT_SCHEMA_VERSION,
+            user: user.owner,
+            amount,
+            net_reward,
         });
         
-        msg!("Bank operational status changed to: {}", bank.is_operational);
         Ok(())
     }
 
-    /// Admin function to add funds to bank
-    pub fn add_bank_funds(ctx: Context<AdminOperation>, amount: u64) -> Result<()> {
+    /// Unstake tokens and claim rewards
+    pub fn unstake(ctx: Context<Staking>, amount: u64) -> Result<()> {
+        // Validate input
+        if amount == 0 {
+            return Err(ErrorCode::InvalidAmount.into());
+        }
+        
+        let user = &mut ctx.accounts.user_account;
         let bank = &mut ctx.accounts.bank_account;
+        let mut outflow = 0;
         
-        // Check if caller is admin
-        if ctx.accounts.admin.key() != bank.admin {
-            return Err(ErrorCode::Unauthorized.into());
+        // Check if unstake is paused
+        if is_paused(bank, OpKind::Unstake) {
+            return Err(ErrorCode::OperationPaused.into());
         }
         
+        let reward = process_unstake(
+            bank,
+            user,
+            &mut ctx.accounts.reward_recipient,
+            amount,
+            ctx.accounts.clock.slot,
+            &mut outflow
+        )?;
+        
+        emit!(UnstakeEvent {
+            schema_version: EVENT_SCHEMA_VERSION,
+            user: ctx.accounts.payer.key(),
+            amount,
+            ui_amount: format_ui_amount(amount, bank.decimals)?,
+            reward,
+            remaining_staked: user.staked_balance,
+        });
+        
+        msg!("Unstaked {} tokens with {} reward for user: {}", amount, reward, ctx.accounts.payer.key());
+        Ok(())
+    }
+
+    /// Unstake ahead of `lock_end_slot`, forfeiting the reward accrued since the last
+    /// settlement and paying `EARLY_UNSTAKE_PENALTY_BPS` of the principal into the bank.
+    /// Reward a keeper already settled into `pending_reward` is kept.
+    pub fn force_unstake(ctx: Context<Staking>, amount: u64) -> Result<()> {
+        // Validate input
         if amount == 0 {
             return Err(ErrorCode::InvalidAmount.into());
         }
         
+        let user = &mut ctx.accounts.user_account;
+        let bank = &mut ctx.accounts.bank_account;
+        let current_slot = ctx.accounts.clock.slot;
+        
+        // Check if unstake is paused
+        if is_paused(bank, OpKind::Unstake) {
+            return Err(ErrorCode::OperationPaused.into());
+        }
+        
+        check_unstake_allowed(user, amount, current_slot, true)?;
+        
+        // Once the lock has ended a regular unstake is penalty-free
+        if current_slot >= user.lock_end_slot {
+            return Err(ErrorCode::LockExpired.into());
+        }
+        
+        // An expired grant no longer restricts the position
+        if current_slot >= user.grant_unlock_slot {
+            user.granted_stake = 0;
+        }
+        
+        // Bring the emission accumulator up to date before the staked total changes
+        update_reward_accumulator(bank, current_slot)?;
+        
+        // Unsettled reward is never paid, so it simply stays in the bank
+        let settled_reward = user.pending_reward;
+        let forfeited_reward = calculate_pending_reward(bank, user, current_slot)?
+            .saturating_sub(settled_reward);
+        
+        let penalty = amount
+            .checked_mul(EARLY_UNSTAKE_PENALTY_BPS)
+            .ok_or(ErrorCode::ArithmeticOverflow)?
+            .checked_div(BASIS_POINTS_DIVISOR)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        let returned = amount
+            .checked_sub(penalty)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        
+        // Update balances
+        user.staked_balance = user.staked_balance
+            .checked_sub(amount)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        user.balance = user.balance
+            .checked_add(returned)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        bank.staked_balance = bank.staked_balance
+            .checked_sub(amount)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
         bank.balance = bank.balance
-            .checked_add(amount)
+            .checked_add(penalty)
             .ok_or(ErrorCode::ArithmeticOverflow)?;
+        user.reward_debt = calculate_reward_debt(user.staked_balance, bank.acc_reward_per_share)?;
+        user.stake_slot = current_slot;
+        reset_token_slots(bank, user, current_slot)?;
+        user.pending_reward = settled_reward;
         
-        emit!(BankFundsAdded {
-            admin: ctx.accounts.admin.key(),
+        // The penalty can leave less collateral than the stake it replaces
+        assert_collateral_covered(user)?;
+        assert_health_factor(bank, user, ctx.accounts.clock.unix_timestamp)?;
+        
+        emit!(ForceUnstakeEvent {
+            schema_version: EVENT_SCHEMA_VERSION,
+            user: ctx.accounts.payer.key(),
             amount,
-            new_balance: bank.balance,
+            penalty,
+            forfeited_reward,
         });
         
-        msg!("Added {} tokens to bank balance", amount);
+        msg!("Force-unstaked {} tokens with {} penalty for user: {}", amount, penalty, ctx.accounts.payer.key());
         Ok(())
     }
-}
 
-// Helper functions
-fn calculate_staking_reward(staked_amount: u64, current_slot: u64, stake_slot: u64) -> Result<u64> {
-    let slots_staked = current_slot
-        .checked_sub(stake_slot)
-        .ok_or(ErrorCode::ArithmeticOverflow)?;
-    
-    // Calculate reward based on APY
-    let reward = staked_amount
-        .checked_mul(STAKING_APY_BASIS_POINTS)
-        .ok_or(ErrorCode::ArithmeticOverflow)?
-        .checked_mul(slots_staked)
-        .ok_or(ErrorCode::ArithmeticOverflow)?
-        .checked_div(BASIS_POINTS_DIVISOR)
-        .ok_or(ErrorCode::ArithmeticOverflow)?
-        .checked_div(SLOTS_PER_YEAR)
-        .ok_or(ErrorCode::ArithmeticOverflow)?;
-    
-    Ok(reward)
-}
+    /// Claim accrued staking rewards without reducing the staked position
+    pub fn claim_rewards(ctx: Context<Staking>) -> Result<()> {
+        let user = &mut ctx.accounts.user_account;
+        let bank = &mut ctx.accounts.bank_account;
+        let mut outflow = 0;
+        let current_slot = ctx.accounts.clock.slot;
+        
+        // Reward payouts are gated with unstaking
+        if is_paused(bank, OpKind::Unstake) {
+            return Err(ErrorCode::OperationPaused.into());
+        }
+        
+        // Check if account is frozen
+        if user.is_frozen {
+            return Err(ErrorCode::AccountFrozen.into());
+        }
+        
+        // Bring the emission accumulator up to date before settling
+        update_reward_accumulator(bank, current_slot)?;
+        let reward = calculate_pending_reward(bank, user, current_slot)?;
+        
+        if reward == 0 {
+            msg!("No rewards to claim for user: {}", ctx.accounts.payer.key());
+            return Ok(());
+        }
+        
+        // Dust stays accruing until it is worth a claim
+        if reward < bank.min_claimable_reward {
+            return Err(ErrorCode::RewardBelowMinimum.into());
+        }
+        
+        // Check if bank can pay reward
+        if available_bank_balance(bank) < reward {
+            return Err(ErrorCode::BankInsufficientFunds.into());
+        }
+        
+        credit_reward(user, &mut ctx.accounts.reward_recipient, reward)?;
+        bank.balance = bank.balance
+            .checked_sub(reward)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        record_outflow(bank, &mut outflow, reward)?;
+        bank.report_rewards_paid = bank.report_rewards_paid
+            .checked_add(reward)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        bank.total_rewards_paid = bank.total_rewards_paid
+            .checked_add(reward)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        
+        // Restart accrual for the unchanged position
+        user.stake_slot = current_slot;
+        reset_token_slots(bank, user, current_slot)?;
+        user.reward_debt = calculate_reward_debt(user.staked_balance, bank.acc_reward_per_share)?;
+        
+        emit!(ClaimRewardEvent {
+            schema_version: EVENT_SCHEMA_VERSION,
+            user: ctx.accounts.payer.key(),
+            reward,
+            staked_balance: user.staked_balance,
+        });
+        
+        msg!("Claimed {} reward for user: {}", reward, ctx.accounts.payer.key());
+        Ok(())
+    }
 
-fn calculate_loan_interest(principal: u64, time_elapsed_seconds: i64) -> Result<u64> {
-    if time_elapsed_seconds <= 0 {
-        return Ok(0);
+    /// Settle the pending rewards of the users in remaining accounts into `pending_reward`
+    /// without paying them, advancing each accrual checkpoint; anyone may call
+    pub fn batch_settle_rewards<'info>(ctx: Context<'_, '_, 'info, 'info, SettleRewards<'info>>) -> Result<()> {
+        // Reject oversized batches before mutating anything
+        if ctx.remaining_accounts.len() > MAX_BATCH_SIZE {
+            return Err(ErrorCode::BatchTooLarge.into());
+        }
+        
+        let bank = &mut ctx.accounts.bank_account;
+        let current_slot = ctx.accounts.clock.slot;
+        update_reward_accumulator(bank, current_slot)?;
+        
+        let mut total_settled: u64 = 0;
+        for account_info in ctx.remaining_accounts.iter() {
+            // Deserializing as `Account<User>` verifies program ownership and the discriminator
+            let mut user: Account<'info, User> = Account::try_from(account_info)?;
+            if user.staked_balance == 0 {
+                continue;
+            }
+            
+            let pending = calculate_pending_reward(bank, &user, current_slot)?;
+            total_settled = total_settled
+                .checked_add(pending.saturating_sub(user.pending_reward))
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+            
+            user.stake_slot = current_slot;
+            reset_token_slots(bank, &mut user, current_slot)?;
+            user.pending_reward = pending;
+            user.reward_debt = calculate_reward_debt(user.staked_balance, bank.acc_reward_per_share)?;
+            user.exit(&crate::ID)?;
+        }
+        
+        emit!(RewardsSettled {
+            schema_version: EVENT_SCHEMA_VERSION,
+            slot: current_slot,
+            accounts_processed: ctx.remaining_accounts.len() as u64,
+            total_settled,
+        });
+        
+        msg!("Settled {} reward across {} accounts", total_settled, ctx.remaining_accounts.len());
+        Ok(())
     }
-    
-    let time_elapsed = time_elapsed_seconds as u64;
-    let seconds_per_year = 365 * 24 * 60 * 60;
-    
-    // Calculate simple interest
-    let interest = principal
-        .checked_mul(LENDING_INTEREST_RATE)
-        .ok_or(ErrorCode::ArithmeticOverflow)?
-        .checked_mul(time_elapsed)
-        .ok_or(ErrorCode::ArithmeticOverflow)?
-        .checked_div(PERCENTAGE_DIVISOR)
-        .ok_or(ErrorCode::ArithmeticOverflow)?
-        .checked_div(seconds_per_year)
-        .ok_or(ErrorCode::ArithmeticOverflow)?;
-    
-    Ok(interest)
-}
 
-// Account structures
-#[derive(Accounts)]
-pub struct InitializeBank<'info> {
-    #[account(mut)]
-    pub admin: Signer<'info>,
+    /// Keeper crank staking each opted-in user's spendable balance above their threshold,
+    /// on the same terms as `stake`. Prior rewards and any welcome bonus are settled into
+    /// `pending_reward`; users not eligible are skipped.
+    pub fn sweep_to_stake<'info>(ctx: Context<'_, '_, 'info, 'info, SweepToStake<'info>>) -> Result<()> {
+        // Reject oversized batches before mutating anything
+        if ctx.remaining_accounts.len() > MAX_BATCH_SIZE {
+            return Err(ErrorCode::BatchTooLarge.into());
+        }
+        
+        let bank = &mut ctx.accounts.bank_account;
+        let mut outflow = 0;
+        let current_slot = ctx.accounts.clock.slot;
+        let now = ctx.accounts.clock.unix_timestamp;
+        
+        // Check if stake is paused
+        if is_paused(bank, OpKind::Stake) {
+            return Err(ErrorCode::OperationPaused.into());
+        }
+        
+        // New positions would lock in terms that a pending proposal may change
+        if bank.rate_proposal_active {
+            return Err(ErrorCode::RateProposalActive.into());
+        }
+        
+        update_reward_accumulator(bank, current_slot)?;
+        
+        let mut users_swept: u64 = 0;
+        let mut total_staked: u64 = 0;
+        for account_info in ctx.remaining_accounts.iter() {
+            let mut user: Account<'info, User> = Account::try_from(account_info)?;
+            if !user.auto_stake_enabled || user.is_frozen {
+                continue;
+            }
+            
+            let amount = spendable_balance(&user).saturating_sub(user.auto_stake_threshold);
+            if amount == 0 {
+                continue;
+            }
+            
+            // A user who cannot stake right now, e.g. whose loan would fall below the minimum
+            // health factor, is left untouched and does not fail the rest of the batch
+            let plan = match plan_stake(bank, &user, amount, 0, current_slot, now, false) {
+                Ok(plan) => plan,
+                Err(_) => continue,
+            };
+            commit_stake(bank, &mut user, &plan, amount, current_slot, false, &mut outflow)?;
+            user.exit(&crate::ID)?;
+            
+            users_swept = users_swept
+                .checked_add(1)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+            total_staked = total_staked
+                .checked_add(amount)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+        }
+        
+        emit!(AutoStakeSwept {
+            schema_version: EVENT_SCHEMA_VERSION,
+            slot: current_slot,
+            accounts_processed: ctx.remaining_accounts.len() as u64,
+            users_swept,
+            total_staked,
+        });
+        
+        msg!("Swept {} tokens into stake for {} users", total_staked, users_swept);
+        Ok(())
+    }
+
+    /// Unstake a user's whole unlocked position once bank solvency falls below their threshold
+    pub fn execute_stop_loss(ctx: Context<StopLoss>) -> Result<()> {
+        let user = &mut ctx.accounts.user_account;
+        let bank = &mut ctx.accounts.bank_account;
+        let mut outflow = 0;
+        let current_slot = ctx.accounts.clock.slot;
+        
+        // Check if unstake is paused
+        if is_paused(bank, OpKind::Unstake) {
+            return Err(ErrorCode::OperationPaused.into());
+        }
+        
+        if !user.stop_loss_enabled {
+            return Err(ErrorCode::NotEligible.into());
+        }
+        
+        // Only trigger while the bank's reserves cover less of the stakes than the user accepts
+        let solvency_bps = calculate_solvency_bps(bank)?;
+        if solvency_bps >= user.stop_loss_solvency_bps {
+            return Err(ErrorCode::NotEligible.into());
+        }
+        
+        let amount = if current_slot < user.grant_unlock_slot {
+            user.staked_balance.saturating_sub(user.granted_stake)
+        } else {
+            user.staked_balance
+        };
+        if amount == 0 {
+            return Err(ErrorCode::InsufficientBalance.into());
+        }
+        
+        let reward = process_unstake(
+            bank,
+            user,
+            &mut ctx.accounts.reward_recipient,
+            amount,
+            current_slot,
+            &mut outflow
+        )?;
+        
+        emit!(StopLossExecuted {
+            schema_version: EVENT_SCHEMA_VERSION,
+            user: user.owner,
+            caller: ctx.accounts.caller.key(),
+            amount,
+            reward,
+            solvency_bps,
+        });
+        
+        msg!("Stop-loss unstaked {} tokens with {} reward for user: {}", amount, reward, user.owner);
+        Ok(())
+    }
+
+    /// Convert part of the accrued staking reward into non-transferable vote credits
+    pub fn redeem_rewards_for_votes(ctx: Context<Staking>, amount: u64) -> Result<()> {
+        // Validate input
+        if amount == 0 {
+            return Err(ErrorCode::InvalidAmount.into());
+        }
+        
+        let user = &mut ctx.accounts.user_account;
+        let bank = &mut ctx.accounts.bank_account;
+        let mut outflow = 0;
+        let current_slot = ctx.accounts.clock.slot;
+        
+        // Reward payouts are gated with unstaking
+        if is_paused(bank, OpKind::Unstake) {
+            return Err(ErrorCode::OperationPaused.into());
+        }
+        
+        // Check if account is frozen
+        if user.is_frozen {
+            return Err(ErrorCode::AccountFrozen.into());
+        }
+        
+        update_reward_accumulator(bank, current_slot)?;
+        let pending_reward = calculate_pending_reward(bank, user, current_slot)?;
+        
+        if amount > pending_reward {
+            return Err(ErrorCode::InsufficientBalance.into());
+        }
+        
+        // Accrual restarts below, so whatever is not redeemed is paid out now
+        let remainder = pending_reward
+            .checked_sub(amount)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        if available_bank_balance(bank) < remainder {
+            return Err(ErrorCode::BankInsufficientFunds.into());
+        }
+        
+        credit_reward(user, &mut ctx.accounts.reward_recipient, remainder)?;
+        bank.balance = bank.balance
+            .checked_sub(remainder)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        record_outflow(bank, &mut outflow, remainder)?;
+        bank.report_rewards_paid = bank.report_rewards_paid
+            .checked_add(remainder)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        bank.total_rewards_paid = bank.total_rewards_paid
+            .checked_add(remainder)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        
+        user.vote_credits = user.vote_credits
+            .checked_add(amount)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        user.stake_slot = current_slot;
+        reset_token_slots(bank, user, current_slot)?;
+        user.reward_debt = calculate_reward_debt(user.staked_balance, bank.acc_reward_per_share)?;
+        
+        emit!(RewardsRedeemedForVotes {
+            schema_version: EVENT_SCHEMA_VERSION,
+            user: ctx.accounts.payer.key(),
+            reward_redeemed: amount,
+            reward_paid: remainder,
+            vote_credits: user.vote_credits,
+        });
+        
+        msg!("Redeemed {} reward for vote credits for user: {}", amount, ctx.accounts.payer.key());
+        Ok(())
+    }
+
+    /// Borrow funds from the bank
+    pub fn borrow(ctx: Context<LoanOperations>, amount: u64, max_acceptable_rate_bps: u64) -> Result<()> {
+        // Validate input
+        if amount == 0 {
+            return Err(ErrorCode::InvalidAmount.into());
+        }
+        
+        let user = &mut ctx.accounts.user_account;
+        let bank = &mut ctx.accounts.bank_account;
+        let mut outflow = 0;
+        
+        // Check if borrow is paused
+        if is_paused(bank, OpKind::Borrow) {
+            return Err(ErrorCode::OperationPaused.into());
+        }
+        
+        // Lending moves utilization; close out the dynamic rate it implied so far
+        update_apy_index(bank, ctx.accounts.clock.slot)?;
+        
+        // Check if account is frozen
+        if user.is_frozen {
+            return Err(ErrorCode::AccountFrozen.into());
+        }
+        
+        // New loans would lock in terms that a pending proposal may change
+        if bank.rate_proposal_active {
+            return Err(ErrorCode::RateProposalActive.into());
+        }
+        
+        // Check the bank-wide open loan cap
+        if bank.max_open_loans > 0 && bank.open_loans >= bank.max_open_loans {
+            return Err(ErrorCode::TooManyOpenLoans.into());
+        }
+        
+        // Check if bank has sufficient funds
+        if available_bank_balance(bank) < amount {
+            return Err(ErrorCode::BankInsufficientFunds.into());
+        }
+        
+        // Calculate maximum borrowing amount based on unlocked collateral, excluding the reserved residual
+        let max_borrow = calculate_max_borrow(bank, user)?;
+        
+        // An admin override caps total outstanding principal, whatever the collateral allows
+        if user.max_loan_override > 0 {
+            let override_headroom = user.max_loan_override.saturating_sub(user.lent_balance);
+            if amount > override_headroom && override_headroom < max_borrow {
+                return Err(ErrorCode::UserLoanCapExceeded.into());
+            }
+        }
+        
+        if amount > max_borrow {
+            return Err(ErrorCode::InvalidCollateralRatio.into());
+        }
+        
+        // Loans below the interest-free threshold never accrue interest; otherwise
+        // first-time borrowers get the introductory rate, locked for the life of the loan
+        let loan_rate_bps = if amount < bank.interest_free_loan_threshold {
+            0
+        } else if user.has_borrowed_before {
+            LENDING_INTEREST_RATE_BPS
+        } else {
+            bank.intro_rate_bps
+        };
+        
+        // Guard clients quoting against a stale rate
+        if loan_rate_bps > max_acceptable_rate_bps {
+            return Err(ErrorCode::RateAboveMax.into());
+        }
+        
+        // Lock the collateral backing this loan until it is repaid
+        let locked_collateral = amount
+            .checked_mul(PERCENTAGE_DIVISOR)
+            .ok_or(ErrorCode::ArithmeticOverflow)?
+            .div_ceil(bank.collateral_ratio);
+        
+        let now = ctx.accounts.clock.unix_timestamp;
+        let loan_index = user.loan_count;
+        let loan = &mut ctx.accounts.loan_account;
+        loan.owner = user.owner;
+        loan.loan_index = loan_index;
+        loan.principal = amount;
+        loan.start_timestamp = now;
+        loan.interest_rate_snapshot = loan_rate_bps;
+        loan.locked_collateral = locked_collateral;
+        loan.compound_interest = bank.compound_interest;
+        loan.carried_interest = 0;
+        loan.locked_stake = calculate_stake_to_lock(user, locked_collateral)?;
+        user.locked_balance = user.locked_balance
+            .checked_add(locked_collateral)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        user.locked_staked_balance = user.locked_staked_balance
+            .checked_add(loan.locked_stake)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        
+        user.loan_rate_bps = loan_rate_bps;
+        user.has_borrowed_before = true;
+        user.loan_count = loan_index
+            .checked_add(1)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        
+        // The aggregate loan timestamp marks the oldest loan still open
+        if user.lent_balance == 0 {
+            user.loan_timestamp = now;
+        }
+        
+        // Update balances
+        user.lent_balance = user.lent_balance
+            .checked_add(amount)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        user.balance = user.balance
+            .checked_add(amount)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        add_loan_weights(bank, user, loan)?;
+        bank.balance = bank.balance
+            .checked_sub(amount)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        bank.lent_balance = bank.lent_balance
+            .checked_add(amount)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        record_outflow(bank, &mut outflow, amount)?;
+        bank.open_loans = bank.open_loans
+            .checked_add(1)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        
+        // Keep any active loan above the minimum health factor
+        assert_health_factor(bank, user, now)?;
+        
+        // Headroom above the liquidation threshold at origination
+        let collateral_buffer_bps = calculate_health_factor(bank, user, now)?
+            .saturating_sub(LIQUIDATION_HEALTH_FACTOR_BPS);
+        
+        emit!(BorrowEvent {
+            schema_version: EVENT_SCHEMA_VERSION,
+            user: ctx.accounts.payer.key(),
+            loan_index,
+            amount,
+            collateral_used: user.balance.saturating_sub(amount),
+            collateral_buffer_bps,
+        });
+        
+        msg!("Borrowed {} tokens on loan {} for user: {}", amount, loan_index, ctx.accounts.payer.key());
+        Ok(())
+    }
+
+    /// Report the borrow amount that would leave the user at exactly `target_health_bps`
+    pub fn max_borrow_for_health(ctx: Context<UserView>, target_health_bps: u64) -> Result<()> {
+        let user = &ctx.accounts.user_account;
+        let bank = &ctx.accounts.bank_account;
+        let debt = if user.lent_balance == 0 {
+            0
+        } else {
+            calculate_loan_debt(user, ctx.accounts.clock.unix_timestamp)?
+        };
+        
+        // Borrowed funds join the collateral, so health approaches ratio * 100 bps as the loan grows:
+        // (collateral + x) * ratio * 100 / (debt + x) = target
+        //   =>  x = (collateral * ratio * 100 - target * debt) / (target - ratio * 100)
+        let health_floor = bank.collateral_ratio
+            .checked_mul(PERCENTAGE_DIVISOR)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        let borrow_amount = if target_health_bps <= health_floor {
+            u64::MAX
+        } else {
+            let capacity = total_collateral(user)
+                .checked_mul(health_floor as u128)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+            let committed = (debt as u128)
+                .checked_mul(target_health_bps as u128)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+            let amount = capacity
+                .saturating_sub(committed)
+                .checked_div((target_health_bps - health_floor) as u128)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+            u64::try_from(amount).unwrap_or(u64::MAX)
+        };
+        
+        let max_borrow = calculate_max_borrow(bank, user)?;
+        
+        emit!(MaxBorrowForHealth {
+            schema_version: EVENT_SCHEMA_VERSION,
+            user: user.owner,
+            target_health_bps,
+            borrow_amount,
+            max_borrow,
+        });
+        
+        Ok(())
+    }
+
+    /// Repay loan `loan_index` with interest and close its account
+    pub fn repay_loan(ctx: Context<RepayLoan>, loan_index: u64) -> Result<()> {
+        let user = &mut ctx.accounts.user_account;
+        let bank = &mut ctx.accounts.bank_account;
+        
+        // Check if repay is paused
+        if is_paused(bank, OpKind::Repay) {
+            return Err(ErrorCode::OperationPaused.into());
+        }
+        
+        // Lending moves utilization; close out the dynamic rate it implied so far
+        update_apy_index(bank, ctx.accounts.clock.slot)?;
+        
+        let (principal, interest, total_repayment) = settle_loan(
+            bank,
+            user,
+            &ctx.accounts.loan_account,
+            ctx.accounts.clock.unix_timestamp
+        )?;
+        
+        emit!(RepayEvent {
+            schema_version: EVENT_SCHEMA_VERSION,
+            user: ctx.accounts.payer.key(),
+            loan_index,
+            principal,
+            interest,
+            total_repayment,
+        });
+        
+        msg!("Repaid loan {}: {} principal + {} interest for user: {}", loan_index, principal, interest, ctx.accounts.payer.key());
+        Ok(())
+    }
+
+    /// Repay every open loan of the user, passed as `remaining_accounts`, in one call. Each loan is
+    /// settled as `repay_loan` would and closed; nothing is repaid unless the balance covers them all.
+    pub fn repay_all_loans<'info>(ctx: Context<'_, '_, 'info, 'info, RepayAllLoans<'info>>) -> Result<()> {
+        // Reject oversized batches before mutating anything
+        if ctx.remaining_accounts.len() > MAX_BATCH_SIZE {
+            return Err(ErrorCode::BatchTooLarge.into());
+        }
+        
+        let user = &mut ctx.accounts.user_account;
+        let bank = &mut ctx.accounts.bank_account;
+        let now = ctx.accounts.clock.unix_timestamp;
+        
+        // Check if repay is paused
+        if is_paused(bank, OpKind::Repay) {
+            return Err(ErrorCode::OperationPaused.into());
+        }
+        
+        // Lending moves utilization; close out the dynamic rate it implied so far
+        update_apy_index(bank, ctx.accounts.clock.slot)?;
+        
+        let mut loans: Vec<Account<'info, Loan>> = Vec::with_capacity(ctx.remaining_accounts.len());
+        let mut principal: u64 = 0;
+        let mut total_owed: u64 = 0;
+        for account_info in ctx.remaining_accounts.iter() {
+            let loan: Account<'info, Loan> = Account::try_from(account_info)?;
+            if loan.owner != user.owner {
+                return Err(ErrorCode::Unauthorized.into());
+            }
+            if loans.iter().any(|seen| seen.loan_index == loan.loan_index) {
+                return Err(ErrorCode::IncompleteLoanSet.into());
+            }
+            
+            principal = principal
+                .checked_add(loan.principal)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+            total_owed = total_owed
+                .checked_add(loan.principal)
+                .ok_or(ErrorCode::ArithmeticOverflow)?
+                .checked_add(calculate_repayment_interest(bank, &loan, now)?)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+            loans.push(loan);
+        }
+        
+        // A partial set would leave debt behind that the caller believes cleared
+        if loans.is_empty() || principal != user.lent_balance {
+            return Err(ErrorCode::IncompleteLoanSet.into());
+        }
+        
+        // Check if user has sufficient balance to repay
+        if user.balance < total_owed {
+            return Err(ErrorCode::InsufficientBalance.into());
+        }
+        
+        for loan in loans.iter() {
+            let (principal, interest, total_repayment) = settle_loan(bank, user, loan, now)?;
+            
+            emit!(RepayEvent {
+                schema_version: EVENT_SCHEMA_VERSION,
+                user: ctx.accounts.payer.key(),
+                loan_index: loan.loan_index,
+                principal,
+                interest,
+                total_repayment,
+            });
+            
+            loan.close(ctx.accounts.payer.to_account_info())?;
+        }
+        
+        msg!("Repaid {} loans totalling {} for user: {}", loans.len(), total_owed, ctx.accounts.payer.key());
+        Ok(())
+    }
+
+    /// Repay part of loan `loan_index`, applied to accrued interest first and then principal.
+    /// A loan paid down to zero stays open until closed with `repay_loan`.
+    pub fn repay_partial(ctx: Context<PartialRepay>, loan_index: u64, amount: u64) -> Result<()> {
+        // Validate input
+        if amount == 0 {
+            return Err(ErrorCode::InvalidAmount.into());
+        }
+        
+        let user = &mut ctx.accounts.user_account;
+        let bank = &mut ctx.accounts.bank_account;
+        let loan = &mut ctx.accounts.loan_account;
+        let now = ctx.accounts.clock.unix_timestamp;
+        
+        // Check if repay is paused
+        if is_paused(bank, OpKind::Repay) {
+            return Err(ErrorCode::OperationPaused.into());
+        }
+        
+        // Lending moves utilization; close out the dynamic rate it implied so far
+        update_apy_index(bank, ctx.accounts.clock.slot)?;
+        
+        let interest = calculate_loan_owed_interest(loan, now)?;
+        let total_owed = loan.principal
+            .checked_add(interest)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        
+        if amount > total_owed {
+            return Err(ErrorCode::RepaymentExceedsDebt.into());
+        }
+        
+        // Check if user has sufficient balance to repay
+        if user.balance < amount {
+            return Err(ErrorCode::InsufficientBalance.into());
+        }
+        
+        let interest_paid = amount.min(interest);
+        let principal_paid = amount
+            .checked_sub(interest_paid)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        
+        // Release collateral in proportion to the principal repaid
+        let released_collateral = (loan.locked_collateral as u128)
+            .checked_mul(principal_paid as u128)
+            .ok_or(ErrorCode::ArithmeticOverflow)?
+            .checked_div(loan.principal as u128)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        let released_collateral = u64::try_from(released_collateral)
+            .map_err(|_| ErrorCode::ArithmeticOverflow)?;
+        loan.locked_collateral = loan.locked_collateral
+            .checked_sub(released_collateral)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        user.locked_balance = user.locked_balance.saturating_sub(released_collateral);
+        
+        // Pledged stake is released in the same proportion
+        let released_stake = (loan.locked_stake as u128)
+            .checked_mul(principal_paid as u128)
+            .ok_or(ErrorCode::ArithmeticOverflow)?
+            .checked_div(loan.principal as u128)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        let released_stake = u64::try_from(released_stake)
+            .map_err(|_| ErrorCode::ArithmeticOverflow)?;
+        loan.locked_stake = loan.locked_stake
+            .checked_sub(released_stake)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        user.locked_staked_balance = user.locked_staked_balance.saturating_sub(released_stake);
+        
+        remove_loan_weights(bank, user, loan)?;
+        loan.principal = loan.principal
+            .checked_sub(principal_paid)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        
+        // Carry the unpaid interest forward and restart accrual on the new principal
+        loan.carried_interest = interest
+            .checked_sub(interest_paid)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        loan.start_timestamp = now;
+        add_loan_weights(bank, user, loan)?;
+        
+        // Update balances
+        user.balance = user.balance
+            .checked_sub(amount)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        user.lent_balance = user.lent_balance
+            .checked_sub(principal_paid)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        if user.lent_balance == 0 {
+            user.loan_timestamp = 0;
+        }
+        user.lifetime_interest_paid = user.lifetime_interest_paid
+            .checked_add(interest_paid)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        bank.balance = bank.balance
+            .checked_add(amount)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        bank.lent_balance = bank.lent_balance
+            .checked_sub(principal_paid)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        bank.report_interest_collected = bank.report_interest_collected
+            .checked_add(interest_paid)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        bank.total_interest_collected = bank.total_interest_collected
+            .checked_add(interest_paid)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        
+        emit!(PartialRepayEvent {
+            schema_version: EVENT_SCHEMA_VERSION,
+            user: ctx.accounts.payer.key(),
+            loan_index,
+            amount,
+            interest_paid,
+            remaining_principal: loan.principal,
+        });
+        
+        msg!("Partially repaid loan {}: {} paid, {} principal remaining for user: {}", loan_index, amount, loan.principal, ctx.accounts.payer.key());
+        Ok(())
+    }
+
+    /// Liquidate loan `loan_index` of an undercollateralized user; anyone may call.
+    /// The liquidator's bonus is paid first out of the seized collateral.
+    pub fn liquidate(ctx: Context<Liquidation>, loan_index: u64) -> Result<()> {
+        let user = &mut ctx.accounts.user_account;
+        let bank = &mut ctx.accounts.bank_account;
+        let loan = &mut ctx.accounts.loan_account;
+        let now = ctx.accounts.clock.unix_timestamp;
+        
+        // Check if liquidation is paused
+        if is_paused(bank, OpKind::Liquidate) {
+            return Err(ErrorCode::OperationPaused.into());
+        }
+        
+        // Lending moves utilization; close out the dynamic rate it implied so far
+        update_apy_index(bank, ctx.accounts.clock.slot)?;
+        
+        // Liquidatable once total debt exceeds the collateral value of the balance and stake
+        if calculate_health_factor(bank, user, now)? >= LIQUIDATION_HEALTH_FACTOR_BPS {
+            return Err(ErrorCode::NotLiquidatable.into());
+        }
+        
+        let interest = calculate_loan_owed_interest(loan, now)?;
+        let debt = loan.principal
+            .checked_add(interest)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        
+        // Counted before any reward is settled below, so the seized stake's reward forfeits the clean-record bonus too
+        user.liquidation_count = user.liquidation_count
+            .checked_add(1)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        
+        // The close factor caps the share of the debt a single call repays
+        let repaid_debt = if bank.close_factor_bps > 0 {
+            let capped = (debt as u128)
+                .checked_mul(bank.close_factor_bps as u128)
+                .ok_or(ErrorCode::ArithmeticOverflow)?
+                .checked_div(BASIS_POINTS_DIVISOR as u128)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+            u64::try_from(capped).map_err(|_| ErrorCode::ArithmeticOverflow)?
+        } else {
+            debt
+        };
+        let bonus = repaid_debt
+            .checked_mul(LIQUIDATION_BONUS_BASIS_POINTS)
+            .ok_or(ErrorCode::ArithmeticOverflow)?
+            .checked_div(BASIS_POINTS_DIVISOR)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        
+        // Seize the repaid debt and its bonus from the balance, then from the staked collateral
+        let claim = repaid_debt
+            .checked_add(bonus)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        let seized = claim.min(user.balance.saturating_add(user.staked_balance));
+        let bonus = bonus.min(seized);
+        let recovered = seized
+            .checked_sub(bonus)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        let seized_balance = seized.min(user.balance);
+        let seized_stake = seized - seized_balance;
+        
+        // Bonus above the cap is earmarked in the insurance bucket instead of paid to the liquidator
+        let liquidator_bonus = if bank.max_liquidator_bonus > 0 {
+            bonus.min(bank.max_liquidator_bonus)
+        } else {
+            bonus
+        };
+        let insured = bonus - liquidator_bonus;
+        if insured > 0 {
+            let insurance_bucket = ctx.accounts.insurance_bucket
+                .as_mut()
+                .ok_or(ErrorCode::InvalidAddress)?;
+            insurance_bucket.balance = insurance_bucket.balance
+                .checked_add(insured)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+            bank.bucketed_balance = bank.bucketed_balance
+                .checked_add(insured)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+        }
+        
+        // The loan closes once its whole debt is repaid, or once no collateral is left to seize,
+        // writing off the principal it could not cover
+        let closes_loan = repaid_debt == debt || seized < claim;
+        
+        if seized_stake > 0 {
+            // Settle the position's reward into pending_reward before the stake shrinks
+            let current_slot = ctx.accounts.clock.slot;
+            update_reward_accumulator(bank, current_slot)?;
+            let pending = calculate_pending_reward(bank, user, current_slot)?;
+            reset_token_slots(bank, user, current_slot)?;
+            user.pending_reward = pending;
+            user.stake_slot = current_slot;
+            user.staked_balance = user.staked_balance
+                .checked_sub(seized_stake)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+            user.reward_debt = calculate_reward_debt(user.staked_balance, bank.acc_reward_per_share)?;
+            bank.staked_balance = bank.staked_balance
+                .checked_sub(seized_stake)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+        }
+        
+        // What was recovered pays the accrued interest first, as in `repay_partial`
+        let interest_collected = recovered.min(interest);
+        let principal_repaid = if closes_loan {
+            loan.principal
+        } else {
+            recovered - interest_collected
+        };
+        
+        // Release collateral in proportion to the principal repaid
+        let released_collateral = if closes_loan {
+            loan.locked_collateral
+        } else {
+            let released = (loan.locked_collateral as u128)
+                .checked_mul(principal_repaid as u128)
+                .ok_or(ErrorCode::ArithmeticOverflow)?
+                .checked_div(loan.principal as u128)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+            u64::try_from(released).map_err(|_| ErrorCode::ArithmeticOverflow)?
+        };
+        user.locked_balance = user.locked_balance.saturating_sub(released_collateral);
+        let released_stake = if closes_loan {
+            loan.locked_stake
+        } else {
+            let released = (loan.locked_stake as u128)
+                .checked_mul(principal_repaid as u128)
+                .ok_or(ErrorCode::ArithmeticOverflow)?
+                .checked_div(loan.principal as u128)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+            u64::try_from(released).map_err(|_| ErrorCode::ArithmeticOverflow)?
+        };
+        // Seized stake leaves the position, so no more can stay pledged than remains
+        user.locked_staked_balance = user.locked_staked_balance
+            .saturating_sub(released_stake)
+            .min(user.staked_balance);
+        
+        remove_loan_weights(bank, user, loan)?;
+        if !closes_loan {
+            loan.locked_collateral = loan.locked_collateral
+                .checked_sub(released_collateral)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+            loan.locked_stake = loan.locked_stake
+                .checked_sub(released_stake)
+                .ok_or(ErrorCode::ArithmeticOverflow)?
+                .min(user.locked_staked_balance);
+            loan.principal = loan.principal
+                .checked_sub(principal_repaid)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+            
+            // Carry the unpaid interest forward and restart accrual on the new principal
+            loan.carried_interest = interest
+                .checked_sub(interest_collected)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+            loan.start_timestamp = now;
+            add_loan_weights(bank, user, loan)?;
+        }
+        
+        // Update balances
+        user.balance = user.balance
+            .checked_sub(seized_balance)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        user.lent_balance = user.lent_balance
+            .checked_sub(principal_repaid)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        if user.lent_balance == 0 {
+            user.loan_timestamp = 0;
+        }
+        bank.lent_balance = bank.lent_balance
+            .checked_sub(principal_repaid)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        bank.balance = bank.balance
+            .checked_add(recovered)
+            .ok_or(ErrorCode::ArithmeticOverflow)?
+            .checked_add(insured)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        // The seized balance leaves the user's deposits like a withdrawal
+        bank.total_deposits = bank.total_deposits.saturating_sub(seized_balance);
+        
+        user.lifetime_interest_paid = user.lifetime_interest_paid
+            .checked_add(interest_collected)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        bank.report_interest_collected = bank.report_interest_collected
+            .checked_add(interest_collected)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        bank.total_interest_collected = bank.total_interest_collected
+            .checked_add(interest_collected)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        
+        let liquidator_account = &mut ctx.accounts.liquidator_account;
+        liquidator_account.balance = liquidator_account.balance
+            .checked_add(liquidator_bonus)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        
+        let remaining_principal = if closes_loan { 0 } else { loan.principal };
+        if closes_loan {
+            bank.open_loans = bank.open_loans.saturating_sub(1);
+            loan.close(user.to_account_info())?;
+        }
+        
+        emit!(LiquidationEvent {
+            schema_version: EVENT_SCHEMA_VERSION,
+            user: user.owner,
+            liquidator: ctx.accounts.liquidator.key(),
+            loan_index,
+            seized,
+            bonus: liquidator_bonus,
+            insured,
+            remaining_principal,
+        });
+        
+        msg!("Liquidated loan {} of user {}: {} seized, {} bonus", loan_index, user.owner, seized, liquidator_bonus);
+        Ok(())
+    }
+
+    /// Report whether a user's loans may be liquidated right now, with interest accrued to date
+    pub fn is_liquidatable(ctx: Context<UserView>) -> Result<()> {
+        let user = &ctx.accounts.user_account;
+        let health_factor = calculate_health_factor(
+            &ctx.accounts.bank_account,
+            user,
+            ctx.accounts.clock.unix_timestamp
+        )?;
+        
+        emit!(LiquidationEligibility {
+            schema_version: EVENT_SCHEMA_VERSION,
+            user: user.owner,
+            liquidatable: health_factor < LIQUIDATION_HEALTH_FACTOR_BPS,
+            health_factor,
+        });
+        
+        Ok(())
+    }
+
+    /// Report a user's borrowing power, weighting the liquid balance at the collateral ratio and
+    /// the staked position at `STAKE_COLLATERAL_FACTOR` of it, alongside what is still borrowable
+    pub fn get_total_borrowing_power(ctx: Context<UserView>) -> Result<()> {
+        let user = &ctx.accounts.user_account;
+        let bank = &ctx.accounts.bank_account;
+        
+        let liquid_power = (user.balance as u128)
+            .checked_mul(bank.collateral_ratio as u128)
+            .ok_or(ErrorCode::ArithmeticOverflow)?
+            .checked_div(PERCENTAGE_DIVISOR as u128)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        let staked_power = (staked_collateral(user) as u128)
+            .checked_mul(bank.collateral_ratio as u128)
+            .ok_or(ErrorCode::ArithmeticOverflow)?
+            .checked_div(PERCENTAGE_DIVISOR as u128)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        let total_power = liquid_power
+            .checked_add(staked_power)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        
+        emit!(BorrowingPower {
+            schema_version: EVENT_SCHEMA_VERSION,
+            user: user.owner,
+            liquid_power: u64::try_from(liquid_power).map_err(|_| ErrorCode::ArithmeticOverflow)?,
+            staked_power: u64::try_from(staked_power).map_err(|_| ErrorCode::ArithmeticOverflow)?,
+            total_power: u64::try_from(total_power).map_err(|_| ErrorCode::ArithmeticOverflow)?,
+            available: calculate_max_borrow(bank, user)?,
+        });
+        
+        Ok(())
+    }
+
+    /// Report the total interest a user has paid across all repaid loans
+    pub fn get_interest_history(ctx: Context<UserView>) -> Result<()> {
+        let user = &ctx.accounts.user_account;
+        
+        emit!(InterestHistory {
+            schema_version: EVENT_SCHEMA_VERSION,
+            user: user.owner,
+            lifetime_interest_paid: user.lifetime_interest_paid,
+        });
+        
+        Ok(())
+    }
+
+    /// Report what repaying every open loan would cost right now, without changing any state.
+    /// Each of the user's open `Loan` accounts must be passed as a remaining account.
+    pub fn get_loan_quote<'info>(ctx: Context<'_, '_, 'info, 'info, UserView<'info>>) -> Result<()> {
+        let user = &ctx.accounts.user_account;
+        let bank = &ctx.accounts.bank_account;
+        let now = ctx.accounts.clock.unix_timestamp;
+        
+        if ctx.remaining_accounts.len() > MAX_BATCH_SIZE {
+            return Err(ErrorCode::BatchTooLarge.into());
+        }
+        
+        // Quote each loan exactly as `repay_loan` would settle it, rebate included
+        let mut principal: u64 = 0;
+        let mut accrued_interest: u64 = 0;
+        let mut seen = Vec::with_capacity(ctx.remaining_accounts.len());
+        for account_info in ctx.remaining_accounts.iter() {
+            let loan: Account<'info, Loan> = Account::try_from(account_info)?;
+            if loan.owner != user.owner {
+                return Err(ErrorCode::Unauthorized.into());
+            }
+            if seen.contains(&loan.loan_index) {
+                return Err(ErrorCode::IncompleteLoanSet.into());
+            }
+            seen.push(loan.loan_index);
+            
+            principal = principal
+                .checked_add(loan.principal)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+            accrued_interest = accrued_interest
+                .checked_add(calculate_repayment_interest(bank, &loan, now)?)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+        }
+        
+        // A partial set would understate the payoff
+        if principal != user.lent_balance {
+            return Err(ErrorCode::IncompleteLoanSet.into());
+        }
+        let total_owed = principal
+            .checked_add(accrued_interest)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        
+        emit!(LoanQuoteEvent {
+            schema_version: EVENT_SCHEMA_VERSION,
+            user: user.owner,
+            principal,
+            accrued_interest,
+            total_owed,
+            timestamp: now,
+        });
+        
+        Ok(())
+    }
+
+    /// Report how long a loan can be held before accrued interest reaches `max_interest_budget`
+    pub fn loan_break_even(ctx: Context<UserView>, max_interest_budget: u64) -> Result<()> {
+        let user = &ctx.accounts.user_account;
+        
+        // Check if user has an active loan
+        if user.lent_balance == 0 {
+            return Err(ErrorCode::NoActiveLoan.into());
+        }
+        
+        // Earliest time at which simple interest across all open loans reaches the budget
+        // left after carried interest: (t * rate_weight - rate_time_weight) / (bps * seconds_per_year) = budget.
+        // Compounded loans are estimated at simple interest, so their true break-even comes sooner.
+        let break_even_timestamp = if user.loan_rate_weight == 0 {
+            i64::MAX
+        } else {
+            let budget_scaled = (max_interest_budget.saturating_sub(user.loan_carried_interest) as u128)
+                .checked_mul(BASIS_POINTS_DIVISOR as u128)
+                .ok_or(ErrorCode::ArithmeticOverflow)?
+                .checked_mul(SECONDS_PER_YEAR as u128)
+                .ok_or(ErrorCode::ArithmeticOverflow)?
+                .checked_add(user.loan_rate_time_weight)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+            i64::try_from(budget_scaled.div_ceil(user.loan_rate_weight)).unwrap_or(i64::MAX)
+        };
+        
+        // Holding time measured from the oldest open loan
+        let seconds = u64::try_from(break_even_timestamp.saturating_sub(user.loan_timestamp))
+            .unwrap_or(0);
+        
+        emit!(LoanBreakEven {
+            schema_version: EVENT_SCHEMA_VERSION,
+            user: user.owner,
+            principal: user.lent_balance,
+            max_interest_budget,
+            seconds,
+            break_even_timestamp,
+        });
+        
+        Ok(())
+    }
+
+    /// Transfer funds between users
+    pub fn transfer_funds<'info>(ctx: Context<'_, '_, 'info, 'info, FundTransfer<'info>>, amount: u64) -> Result<()> {
+        // Validate input
+        if amount == 0 {
+            return Err(ErrorCode::InvalidAmount.into());
+        }
+        
+        let from_user = &mut ctx.accounts.from_user;
+        let to_user = &mut ctx.accounts.to_user;
+        
+        // Check if transfer is paused
+        if is_paused(&ctx.accounts.bank_account, OpKind::Transfer) {
+            return Err(ErrorCode::OperationPaused.into());
+        }
+        
+        // Check if either account is frozen; funds sent to a frozen account would be stuck
+        if from_user.is_frozen || to_user.is_frozen {
+            return Err(ErrorCode::AccountFrozen.into());
+        }
+        
+        // Check sufficient balance; collateral locked by loans is not spendable
+        if spendable_balance(from_user) < amount {
+            return Err(ErrorCode::InsufficientBalance.into());
+        }
+        
+        // Prevent self-transfer
+        if from_user.key() == to_user.key() {
+            return Err(ErrorCode::InvalidAddress.into());
+        }
+        
+        // Update balances
+        from_user.balance = from_user.balance
+            .checked_sub(amount)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        to_user.balance = to_user.balance
+            .checked_add(amount)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        
+        // Keep any active loan above the minimum health factor
+        assert_health_factor(&ctx.accounts.bank_account, from_user, ctx.accounts.clock.unix_timestamp)?;
+        
+        // Incoming funds may let the recipient's loans (passed in remaining accounts) close automatically
+        update_apy_index(&mut ctx.accounts.bank_account, ctx.accounts.clock.slot)?;
+        try_auto_repay(&mut ctx.accounts.bank_account, to_user, ctx.remaining_accounts, ctx.accounts.clock.unix_timestamp)?;
+        
+        emit!(TransferEvent {
+            schema_version: EVENT_SCHEMA_VERSION,
+            from: from_user.owner,
+            to: to_user.owner,
+            amount,
+        });
+        
+        msg!("Transferred {} tokens from {} to {}", amount, from_user.owner, to_user.owner);
+        Ok(())
+    }
+
+    /// Report headline bank totals, TVL and lending utilization for dashboards
+    pub fn get_bank_stats(ctx: Context<BankView>) -> Result<()> {
+        let bank = &ctx.accounts.bank_account;
+        
+        emit!(BankStatsEvent {
+            schema_version: EVENT_SCHEMA_VERSION,
+            balance: bank.balance,
+            staked_balance: bank.staked_balance,
+            lent_balance: bank.lent_balance,
+            total_users: bank.total_users,
+            total_value_locked: bank.total_deposits,
+            utilization_bps: calculate_utilization_bps(bank)?,
+        });
+        
+        Ok(())
+    }
+
+    /// Report lifetime interest collected minus rewards paid, and the annualized margin
+    /// implied by the loans and stakes open right now
+    pub fn get_net_interest_margin(ctx: Context<BankView>) -> Result<()> {
+        let bank = &ctx.accounts.bank_account;
+        
+        let cumulative_margin = i64::try_from(
+            (bank.total_interest_collected as i128) - (bank.total_rewards_paid as i128)
+        ).map_err(|_| ErrorCode::ArithmeticOverflow)?;
+        
+        let annual_interest = bank.loan_rate_weight
+            .checked_div(BASIS_POINTS_DIVISOR as u128)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        let annual_interest = u64::try_from(annual_interest).map_err(|_| ErrorCode::ArithmeticOverflow)?;
+        let annual_rewards = calculate_annual_reward_liability(bank, bank.staked_balance)?;
+        let annualized_margin = i64::try_from(
+            (annual_interest as i128) - (annual_rewards as i128)
+        ).map_err(|_| ErrorCode::ArithmeticOverflow)?;
+        
+        emit!(NetInterestMargin {
+            schema_version: EVENT_SCHEMA_VERSION,
+            total_interest_collected: bank.total_interest_collected,
+            total_rewards_paid: bank.total_rewards_paid,
+            cumulative_margin,
+            annual_interest,
+            annual_rewards,
+            annualized_margin,
+        });
+        
+        Ok(())
+    }
+
+    /// Report the annual reward cost of current stakes and how long the bank can cover it
+    pub fn get_reward_liability(ctx: Context<BankView>) -> Result<()> {
+        let bank = &ctx.accounts.bank_account;
+        
+        let annual_liability = calculate_annual_reward_liability(bank, bank.staked_balance)?;
+        
+        // Slots the available balance can fund at the current liability; unbounded when nothing accrues
+        let runway_slots = if annual_liability == 0 {
+            u64::MAX
+        } else {
+            let runway = (bank.balance as u128)
+                .checked_mul(SLOTS_PER_YEAR as u128)
+                .ok_or(ErrorCode::ArithmeticOverflow)?
+                .checked_div(annual_liability as u128)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+            u64::try_from(runway).unwrap_or(u64::MAX)
+        };
+        
+        emit!(RewardLiability {
+            schema_version: EVENT_SCHEMA_VERSION,
+            staked_balance: bank.staked_balance,
+            annual_liability,
+            available_balance: bank.balance,
+            runway_slots,
+        });
+        
+        Ok(())
+    }
+
+    /// Report how many seconds the bank balance can sustain rewards at the current stake and APY
+    pub fn get_reward_runway(ctx: Context<BankView>) -> Result<()> {
+        let bank = &ctx.accounts.bank_account;
+        
+        let annual_liability = calculate_annual_reward_liability(bank, bank.staked_balance)?;
+        let is_infinite = annual_liability == 0;
+        
+        let runway_seconds = if is_infinite {
+            u64::MAX
+        } else {
+            let runway = (bank.balance as u128)
+                .checked_mul(SECONDS_PER_YEAR as u128)
+                .ok_or(ErrorCode::ArithmeticOverflow)?
+                .checked_div(annual_liability as u128)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+            u64::try_from(runway).unwrap_or(u64::MAX)
+        };
+        
+        emit!(RewardRunway {
+            schema_version: EVENT_SCHEMA_VERSION,
+            staked_balance: bank.staked_balance,
+            available_balance: bank.balance,
+            annual_liability,
+            runway_seconds,
+            is_infinite,
+        });
+        
+        Ok(())
+    }
+
+    /// Admin function to set the fixed per-slot reward emission (0 keeps the APY model)
+    pub fn set_reward_per_slot(ctx: Context<AdminClockOperation>, reward_per_slot: u64) -> Result<()> {
+        let bank = &mut ctx.accounts.bank_account;
+        
+        // Settle emissions at the old rate before switching
+        update_reward_accumulator(bank, ctx.accounts.clock.slot)?;
+        bank.reward_per_slot = reward_per_slot;
+        
+        emit!(RewardEmissionChanged {
+            schema_version: EVENT_SCHEMA_VERSION,
+            admin: ctx.accounts.admin.key(),
+            reward_per_slot,
+        });
+        
+        msg!("Reward emission set to {} per slot", reward_per_slot);
+        Ok(())
+    }
+
+    /// Admin function to cap total value locked (deposits plus stakes); 0 removes the cap
+    pub fn set_max_tvl(ctx: Context<AdminOperation>, max_tvl: u64) -> Result<()> {
+        let bank = &mut ctx.accounts.bank_account;
+        bank.max_tvl = max_tvl;
+        
+        msg!("Max TVL set to {}", max_tvl);
+        Ok(())
+    }
+
+    /// Admin function to configure the interest rebate for loans repaid early
+    pub fn set_early_repay_rebate(ctx: Context<AdminOperation>, rebate_bps: u64, window_seconds: i64) -> Result<()> {
+        if rebate_bps > BASIS_POINTS_DIVISOR || window_seconds < 0 {
+            return Err(ErrorCode::InvalidAmount.into());
+        }
+        
+        let bank = &mut ctx.accounts.bank_account;
+        bank.early_repay_rebate_bps = rebate_bps;
+        bank.early_repay_window_seconds = window_seconds;
+        
+        msg!("Early repay rebate set to {} bps within {} seconds", rebate_bps, window_seconds);
+        Ok(())
+    }
+
+    /// Admin function to reject, rather than just warn about, stakes too small to earn rewards
+    pub fn set_reject_unrewarded_stakes(ctx: Context<AdminOperation>, reject: bool) -> Result<()> {
+        let bank = &mut ctx.accounts.bank_account;
+        bank.reject_unrewarded_stakes = reject;
+        
+        msg!("Reject unrewarded stakes set to {}", reject);
+        Ok(())
+    }
+
+    /// Admin function to emit a full snapshot of the bank account for audits
+    pub fn export_bank_state(ctx: Context<AdminView>) -> Result<()> {
+        emit!(BankStateExport {
+            schema_version: EVENT_SCHEMA_VERSION,
+            slot: ctx.accounts.clock.slot,
+            bank: ctx.accounts.bank_account.clone().into_inner(),
+        });
+        
+        Ok(())
+    }
+
+    /// Admin function to freeze or unfreeze every `User` account passed in remaining accounts
+    pub fn batch_set_frozen<'info>(
+        ctx: Context<'_, '_, 'info, 'info, AdminOperation<'info>>,
+        frozen: bool,
+    ) -> Result<()> {
+        // Reject oversized batches before mutating anything
+        if ctx.remaining_accounts.len() > MAX_BATCH_SIZE {
+            return Err(ErrorCode::BatchTooLarge.into());
+        }
+        
+        for account_info in ctx.remaining_accounts.iter() {
+            let mut user: Account<'info, User> = Account::try_from(account_info)?;
+            user.is_frozen = frozen;
+            user.exit(&crate::ID)?;
+        }
+        
+        emit!(AccountsFrozenChanged {
+            schema_version: EVENT_SCHEMA_VERSION,
+            admin: ctx.accounts.admin.key(),
+            is_frozen: frozen,
+            accounts_affected: ctx.remaining_accounts.len() as u64,
+        });
+        
+        msg!("Set frozen = {} on {} accounts", frozen, ctx.remaining_accounts.len());
+        Ok(())
+    }
+
+    /// Admin function to report aggregate collateralization across the loans passed in remaining accounts
+    pub fn get_portfolio_health<'info>(ctx: Context<'_, '_, 'info, 'info, AdminView<'info>>) -> Result<()> {
+        if ctx.remaining_accounts.len() > MAX_BATCH_SIZE {
+            return Err(ErrorCode::BatchTooLarge.into());
+        }
+        
+        let now = ctx.accounts.clock.unix_timestamp;
+        let mut borrowers: u64 = 0;
+        let mut total_debt: u64 = 0;
+        let mut portfolio_collateral: u128 = 0;
+        
+        for account_info in ctx.remaining_accounts.iter() {
+            let user: Account<'info, User> = Account::try_from(account_info)?;
+            if user.lent_balance == 0 {
+                continue;
+            }
+            
+            borrowers = borrowers
+                .checked_add(1)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+            total_debt = total_debt
+                .checked_add(calculate_loan_debt(&user, now)?)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+            // Staked collateral backs loans too, as in the health factor
+            portfolio_collateral = portfolio_collateral
+                .checked_add(total_collateral(&user))
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+        }
+        let total_collateral = u64::try_from(portfolio_collateral).unwrap_or(u64::MAX);
+        
+        // Without outstanding debt the portfolio is unboundedly collateralized
+        let collateralization_bps = if total_debt == 0 {
+            u64::MAX
+        } else {
+            let ratio = (total_collateral as u128)
+                .checked_mul(BASIS_POINTS_DIVISOR as u128)
+                .ok_or(ErrorCode::ArithmeticOverflow)?
+                .checked_div(total_debt as u128)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+            u64::try_from(ratio).unwrap_or(u64::MAX)
+        };
+        
+        emit!(PortfolioHealth {
+            schema_version: EVENT_SCHEMA_VERSION,
+            borrowers,
+            total_debt,
+            total_collateral,
+            collateralization_bps,
+        });
+        
+        Ok(())
+    }
+
+    /// Admin function to grant a user an additive APY boost until `boost_until_slot`
+    pub fn grant_apy_boost(ctx: Context<GrantApyBoost>, boost_bps: u64, boost_until_slot: u64) -> Result<()> {
+        let current_slot = ctx.accounts.clock.slot;
+        
+        if boost_until_slot <= current_slot {
+            return Err(ErrorCode::InvalidAmount.into());
+        }
+        
+        let user = &mut ctx.accounts.user_account;
+        
+        // Fold the slots earned under any previous boost before it is replaced
+        accrue_token_slots(&ctx.accounts.bank_account, user, current_slot)?;
+        
+        user.boost_bps = boost_bps;
+        user.boost_start_slot = current_slot;
+        user.boost_until_slot = boost_until_slot;
+        
+        emit!(ApyBoostGranted {
+            schema_version: EVENT_SCHEMA_VERSION,
+            user: user.owner,
+            boost_bps,
+            boost_until_slot,
+        });
+        
+        msg!("Granted {} bps boost until slot {} for user: {}", boost_bps, boost_until_slot, user.owner);
+        Ok(())
+    }
+
+    /// Admin function to cap a user's total outstanding principal; 0 removes the cap
+    pub fn set_max_loan_override(ctx: Context<AdminUserOperation>, max_loan: u64) -> Result<()> {
+        let user = &mut ctx.accounts.user_account;
+        user.max_loan_override = max_loan;
+        
+        msg!("Max loan set to {} for user: {}", max_loan, user.owner);
+        Ok(())
+    }
+
+    /// Admin function to bound a user's unclaimed reward; 0 removes the cap
+    pub fn set_reward_cap(ctx: Context<AdminUserOperation>, reward_cap: u64) -> Result<()> {
+        let user = &mut ctx.accounts.user_account;
+        user.reward_cap = reward_cap;
+        
+        msg!("Reward cap set to {} for user: {}", reward_cap, user.owner);
+        Ok(())
+    }
+
+    /// Admin function to set the health factor every loan must stay above; 0 disables the floor
+    pub fn set_min_health_factor(ctx: Context<AdminOperation>, min_health_factor_bps: u64) -> Result<()> {
+        let bank = &mut ctx.accounts.bank_account;
+        bank.min_health_factor_bps = min_health_factor_bps;
+        
+        msg!("Minimum health factor set to {} bps", min_health_factor_bps);
+        Ok(())
+    }
+
+    /// Admin function to stake bank funds on a user's behalf, locked until `unlock_slot`
+    pub fn grant_locked_stake(ctx: Context<GrantLockedStake>, amount: u64, unlock_slot: u64) -> Result<()> {
+        // Validate input
+        if amount == 0 {
+            return Err(ErrorCode::InvalidAmount.into());
+        }
+        
+        let current_slot = ctx.accounts.clock.slot;
+        if unlock_slot <= current_slot {
+            return Err(ErrorCode::InvalidAmount.into());
+        }
+        
+        let user = &mut ctx.accounts.user_account;
+        let bank = &mut ctx.accounts.bank_account;
+        let mut outflow = 0;
+        
+        // Bring the emission accumulator up to date before the staked total changes
+        update_reward_accumulator(bank, current_slot)?;
+        
+        // Settle rewards on the existing position before the grant resets stake_slot
+        let reward = calculate_pending_reward(bank, user, current_slot)?;
+        let total_cost = amount
+            .checked_add(reward)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        
+        // Check if bank can fund the grant and pending reward
+        if available_bank_balance(bank) < total_cost {
+            return Err(ErrorCode::BankInsufficientFunds.into());
+        }
+        
+        credit_reward(user, &mut ctx.accounts.reward_recipient, reward)?;
+        bank.balance = bank.balance
+            .checked_sub(total_cost)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        record_outflow(bank, &mut outflow, reward)?;
+        bank.report_rewards_paid = bank.report_rewards_paid
+            .checked_add(reward)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        bank.total_rewards_paid = bank.total_rewards_paid
+            .checked_add(reward)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        bank.staked_balance = bank.staked_balance
+            .checked_add(amount)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        // The grant hands protocol equity to the user, like spending from a bucket
+        bank.protocol_owned_balance = bank.protocol_owned_balance.saturating_sub(amount);
+        
+        if user.staked_balance == 0 {
+            user.stake_start_slot = current_slot;
+        }
+        user.stake_slot = current_slot;
+        reset_token_slots(bank, user, current_slot)?;
+        user.staked_balance = user.staked_balance
+            .checked_add(amount)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        user.granted_stake = user.granted_stake
+            .checked_add(amount)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        user.grant_unlock_slot = user.grant_unlock_slot.max(unlock_slot);
+        user.reward_debt = calculate_reward_debt(user.staked_balance, bank.acc_reward_per_share)?;
+        
+        emit!(LockedStakeGranted {
+            schema_version: EVENT_SCHEMA_VERSION,
+            user: user.owner,
+            amount,
+            unlock_slot: user.grant_unlock_slot,
+        });
+        
+        msg!("Granted {} locked stake until slot {} for user: {}", amount, user.grant_unlock_slot, user.owner);
+        Ok(())
+    }
+
+    /// Admin function to set the interest rate applied to a user's first loan
+    pub fn set_intro_rate(ctx: Context<AdminOperation>, intro_rate_bps: u64) -> Result<()> {
+        let bank = &mut ctx.accounts.bank_account;
+        bank.intro_rate_bps = intro_rate_bps;
+        
+        msg!("Introductory loan rate set to {} bps", intro_rate_bps);
+        Ok(())
+    }
+
+    /// Admin function to cap the number of concurrently open loans; 0 removes the cap
+    pub fn set_max_open_loans(ctx: Context<AdminOperation>, max_open_loans: u64) -> Result<()> {
+        let bank = &mut ctx.accounts.bank_account;
+        bank.max_open_loans = max_open_loans;
+        
+        msg!("Max open loans set to {}", max_open_loans);
+        Ok(())
+    }
+
+    /// Admin function to mark a rate-changing proposal as pending, blocking new stakes and loans
+    pub fn set_rate_proposal_active(ctx: Context<AdminOperation>, active: bool) -> Result<()> {
+        let bank = &mut ctx.accounts.bank_account;
+        bank.rate_proposal_active = active;
+        
+        msg!("Rate proposal active set to {}", active);
+        Ok(())
+    }
+
+    /// Admin function to change the collateral ratio, at most once per cooldown window
+    pub fn set_collateral_ratio(ctx: Context<AdminClockOperation>, new_ratio: u64) -> Result<()> {
+        if new_ratio == 0 || new_ratio > PERCENTAGE_DIVISOR {
+            return Err(ErrorCode::InvalidCollateralRatio.into());
+        }
+        
+        let bank = &mut ctx.accounts.bank_account;
+        let current_slot = ctx.accounts.clock.slot;
+        
+        if bank.last_ratio_change_slot > 0 {
+            let cooldown_end = bank.last_ratio_change_slot
+                .checked_add(bank.ratio_change_cooldown_slots)
+                .ok_or(ErrorCode::ArithmeticOverflow)?;
+            if current_slot < cooldown_end {
+                return Err(ErrorCode::RatioChangeCooldown.into());
+            }
+        }
+        
+        let old_ratio = bank.collateral_ratio;
+        bank.collateral_ratio = new_ratio;
+        bank.last_ratio_change_slot = current_slot;
+        
+        emit!(CollateralRatioChanged {
+            schema_version: EVENT_SCHEMA_VERSION,
+            admin: ctx.accounts.admin.key(),
+            old_ratio,
+            new_ratio,
+        });
+        
+        msg!("Collateral ratio changed from {} to {}", old_ratio, new_ratio);
+        Ok(())
+    }
+
+    /// Admin function to set the minimum slots between collateral ratio changes
+    pub fn set_ratio_change_cooldown(ctx: Context<AdminOperation>, cooldown_slots: u64) -> Result<()> {
+        let bank = &mut ctx.accounts.bank_account;
+        bank.ratio_change_cooldown_slots = cooldown_slots;
+        
+        msg!("Collateral ratio cooldown set to {} slots", cooldown_slots);
+        Ok(())
+    }
+
+    /// Admin function to emit activity since the previous report and reset the counters
+    pub fn emit_daily_report(ctx: Context<AdminClockOperation>) -> Result<()> {
+        let bank = &mut ctx.accounts.bank_account;
+        
+        emit!(DailyReport {
+            schema_version: EVENT_SCHEMA_VERSION,
+            from_slot: bank.last_report_slot,
+            to_slot: ctx.accounts.clock.slot,
+            deposits: bank.report_deposits,
+            withdrawals: bank.report_withdrawals,
+            interest_collected: bank.report_interest_collected,
+            rewards_paid: bank.report_rewards_paid,
+            new_users: bank.report_new_users,
+            closed_users: bank.report_closed_users,
+        });
+        
+        bank.report_deposits = 0;
+        bank.report_withdrawals = 0;
+        bank.report_interest_collected = 0;
+        bank.report_rewards_paid = 0;
+        bank.report_new_users = 0;
+        bank.report_closed_users = 0;
+        bank.last_report_slot = ctx.accounts.clock.slot;
+        
+        msg!("Daily report emitted at slot: {}", bank.last_report_slot);
+        Ok(())
+    }
+
+    /// Admin function to set the balance borrowers must keep un-borrowed as an interest buffer
+    pub fn set_min_residual_balance(ctx: Context<AdminOperation>, min_residual_balance: u64) -> Result<()> {
+        let bank = &mut ctx.accounts.bank_account;
+        bank.min_residual_balance = min_residual_balance;
+        
+        msg!("Minimum residual balance set to: {}", min_residual_balance);
+        Ok(())
+    }
+
+    /// Admin function to make loans with principal below `threshold` interest-free; 0 disables
+    pub fn set_interest_free_loan_threshold(ctx: Context<AdminOperation>, threshold: u64) -> Result<()> {
+        let bank = &mut ctx.accounts.bank_account;
+        bank.interest_free_loan_threshold = threshold;
+        
+        msg!("Interest-free loan threshold set to: {}", threshold);
+        Ok(())
+    }
+
+    /// Admin function to create a named bucket for earmarking part of the bank balance
+    pub fn create_bucket(ctx: Context<CreateBucket>, name: String) -> Result<()> {
+        if name.is_empty() || name.len() > MAX_BUCKET_NAME_LEN {
+            return Err(ErrorCode::InvalidBucketName.into());
+        }
+        
+        let bucket = &mut ctx.accounts.bucket_account;
+        bucket.name = name;
+        bucket.balance = 0;
+        
+        msg!("Bucket created: {}", bucket.name);
+        Ok(())
+    }
+
+    /// Admin function to earmark `amount` of the unallocated bank balance into a bucket
+    pub fn fund_bucket(ctx: Context<BucketOperation>, amount: u64) -> Result<()> {
+        if amount == 0 {
+            return Err(ErrorCode::InvalidAmount.into());
+        }
+        
+        let bank = &mut ctx.accounts.bank_account;
+        let bucket = &mut ctx.accounts.bucket_account;
+        
+        // Buckets together can never earmark more than the bank holds
+        let bucketed_balance = bank.bucketed_balance
+            .checked_add(amount)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        if bucketed_balance > bank.balance {
+            return Err(ErrorCode::BankInsufficientFunds.into());
+        }
+        
+        bank.bucketed_balance = bucketed_balance;
+        bucket.balance = bucket.balance
+            .checked_add(amount)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        
+        msg!("Funded bucket {} with {} tokens", bucket.name, amount);
+        Ok(())
+    }
+
+    /// Admin function to spend `amount` out of a bucket, transferring it from the vault to `destination_token_account`
+    pub fn spend_from_bucket(ctx: Context<SpendFromBucket>, amount: u64) -> Result<()> {
+        if amount == 0 {
+            return Err(ErrorCode::InvalidAmount.into());
+        }
+        
+        let vault = VaultAccounts::new(
+            &ctx.accounts.token_program,
+            &ctx.accounts.vault_token_account,
+            &ctx.accounts.bank_account
+        );
+        let bank = &mut ctx.accounts.bank_account;
+        let bucket = &mut ctx.accounts.bucket_account;
+        
+        if bucket.balance < amount {
+            return Err(ErrorCode::InsufficientBalance.into());
+        }
+        
+        // Other outflows may have drawn the bank below its earmarks
+        if bank.balance < amount {
+            return Err(ErrorCode::BankInsufficientFunds.into());
+        }
+        
+        transfer_from_vault(&vault, ctx.bumps.bank_account, ctx.accounts.destination_token_account.to_account_info(), amount)?;
+        
+        bucket.balance = bucket.balance
+            .checked_sub(amount)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        bank.bucketed_balance = bank.bucketed_balance
+            .checked_sub(amount)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        bank.balance = bank.balance
+            .checked_sub(amount)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        bank.protocol_owned_balance = bank.protocol_owned_balance.saturating_sub(amount);
+        
+        msg!("Spent {} tokens from bucket {}", amount, bucket.name);
+        Ok(())
+    }
+
+    /// Admin function to change the staking APY; rewards up to now keep accruing at the old rate
+    pub fn set_staking_apy(ctx: Context<AdminClockOperation>, new_bps: u64) -> Result<()> {
+        let bank = &mut ctx.accounts.bank_account;
+        let old_bps = bank.staking_apy_bps;
+        
+        // Close out the old rate before the new one takes effect
+        update_apy_index(bank, ctx.accounts.clock.slot)?;
+        bank.staking_apy_bps = new_bps;
+        
+        emit!(ApyChanged {
+            schema_version: EVENT_SCHEMA_VERSION,
+            caller: ctx.accounts.admin.key(),
+            old_bps,
+            new_bps,
+        });
+        
+        msg!("Staking APY changed from {} to {} bps", old_bps, new_bps);
+        Ok(())
+    }
+
+    /// Admin function to configure utilization-targeted APY; a zero target disables it
+    pub fn set_dynamic_apy_params(
+        ctx: Context<AdminClockOperation>,
+        target_utilization_bps: u64,
+        min_apy_bps: u64,
+        max_apy_bps: u64,
+    ) -> Result<()> {
+        // Validate bounds
+        if target_utilization_bps > BASIS_POINTS_DIVISOR || min_apy_bps > max_apy_bps {
+            return Err(ErrorCode::InvalidAmount.into());
+        }
+        
+        let bank = &mut ctx.accounts.bank_account;
+        
+        // Close out the old rate before the new one takes effect
+        update_apy_index(bank, ctx.accounts.clock.slot)?;
+        bank.target_utilization_bps = target_utilization_bps;
+        bank.min_staking_apy_bps = min_apy_bps;
+        bank.max_staking_apy_bps = max_apy_bps;
+        
+        msg!(
+            "Dynamic APY set to {}-{} bps targeting {} bps utilization",
+            min_apy_bps,
+            max_apy_bps,
+            target_utilization_bps
+        );
+        Ok(())
+    }
+
+    /// Admin function to cap a single deposit as a fraction of the pool; 0 disables the cap
+    pub fn set_max_deposit_fraction(ctx: Context<AdminOperation>, max_deposit_fraction_bps: u64) -> Result<()> {
+        if max_deposit_fraction_bps > BASIS_POINTS_DIVISOR {
+            return Err(ErrorCode::InvalidAmount.into());
+        }
+        
+        let bank = &mut ctx.accounts.bank_account;
+        bank.max_deposit_fraction_bps = max_deposit_fraction_bps;
+        
+        msg!("Max deposit fraction set to: {} bps", max_deposit_fraction_bps);
+        Ok(())
+    }
+
+    /// Admin function to cap a single deposit at `supply_bps` of the mint's current supply; call
+    /// again to re-derive the cap as the supply changes, or with 0 to remove it
+    pub fn set_deposit_cap_from_supply(ctx: Context<DepositCapFromSupply>, supply_bps: u64) -> Result<()> {
+        if supply_bps > BASIS_POINTS_DIVISOR {
+            return Err(ErrorCode::InvalidAmount.into());
+        }
+        
+        let max_deposit = (ctx.accounts.mint.supply as u128)
+            .checked_mul(supply_bps as u128)
+            .ok_or(ErrorCode::ArithmeticOverflow)?
+            .checked_div(BASIS_POINTS_DIVISOR as u128)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        
+        let bank = &mut ctx.accounts.bank_account;
+        bank.deposit_cap_supply_bps = supply_bps;
+        bank.max_deposit = u64::try_from(max_deposit).map_err(|_| ErrorCode::ArithmeticOverflow)?;
+        
+        msg!("Max deposit set to {} ({} bps of supply)", bank.max_deposit, supply_bps);
+        Ok(())
+    }
+
+    /// Admin function to cap how much a single instruction may move out of the bank; 0 disables the cap
+    pub fn set_max_tx_outflow(ctx: Context<AdminOperation>, max_tx_outflow: u64) -> Result<()> {
+        let bank = &mut ctx.accounts.bank_account;
+        bank.max_tx_outflow = max_tx_outflow;
+        
+        msg!("Max transaction outflow set to: {}", max_tx_outflow);
+        Ok(())
+    }
+
+    /// Admin function to set the bonus paid on a user's first stake; 0 disables it
+    pub fn set_welcome_bonus(ctx: Context<AdminOperation>, welcome_bonus: u64) -> Result<()> {
+        let bank = &mut ctx.accounts.bank_account;
+        bank.welcome_bonus = welcome_bonus;
+        
+        msg!("Welcome bonus set to: {}", welcome_bonus);
+        Ok(())
+    }
+
+    /// Admin function to switch loan interest between simple and daily-compounded.
+    /// Only loans opened afterwards are affected; open loans keep their model.
+    pub fn set_compound_interest(ctx: Context<AdminOperation>, compound: bool) -> Result<()> {
+        let bank = &mut ctx.accounts.bank_account;
+        bank.compound_interest = compound;
+        
+        msg!("Compound interest set to: {}", compound);
+        Ok(())
+    }
+
+    /// Admin function to change the account limit; 0 allows unlimited accounts
+    pub fn set_max_users(ctx: Context<AdminOperation>, max_users: u64) -> Result<()> {
+        let bank = &mut ctx.accounts.bank_account;
+        bank.max_users = max_users;
+        
+        msg!("Max users set to: {}", max_users);
+        Ok(())
+    }
+
+    /// Admin function to cap the share of a loan's debt one liquidation repays; 0 liquidates in full
+    pub fn set_close_factor(ctx: Context<AdminOperation>, close_factor_bps: u64) -> Result<()> {
+        if close_factor_bps > BASIS_POINTS_DIVISOR {
+            return Err(ErrorCode::InvalidAmount.into());
+        }
+        
+        let bank = &mut ctx.accounts.bank_account;
+        bank.close_factor_bps = close_factor_bps;
+        
+        msg!("Close factor set to: {} bps", close_factor_bps);
+        Ok(())
+    }
+
+    /// Admin function to cap the bonus one liquidation pays the liquidator; 0 disables the cap
+    pub fn set_max_liquidator_bonus(ctx: Context<AdminOperation>, max_liquidator_bonus: u64) -> Result<()> {
+        let bank = &mut ctx.accounts.bank_account;
+        bank.max_liquidator_bonus = max_liquidator_bonus;
+        
+        msg!("Max liquidator bonus set to: {}", max_liquidator_bonus);
+        Ok(())
+    }
+
+    /// Admin function to set the APY reward bonus for stakers who have never been liquidated
+    pub fn set_clean_record_bonus(ctx: Context<AdminOperation>, clean_record_bonus_bps: u64) -> Result<()> {
+        if clean_record_bonus_bps > BASIS_POINTS_DIVISOR {
+            return Err(ErrorCode::InvalidAmount.into());
+        }
+        
+        let bank = &mut ctx.accounts.bank_account;
+        bank.clean_record_bonus_bps = clean_record_bonus_bps;
+        
+        msg!("Clean record bonus set to: {} bps", clean_record_bonus_bps);
+        Ok(())
+    }
+
+    /// Admin function to set the smallest reward `claim_rewards` pays out; 0 disables the minimum
+    pub fn set_min_claimable_reward(ctx: Context<AdminOperation>, min_claimable_reward: u64) -> Result<()> {
+        let bank = &mut ctx.accounts.bank_account;
+        bank.min_claimable_reward = min_claimable_reward;
+        
+        msg!("Minimum claimable reward set to: {}", min_claimable_reward);
+        Ok(())
+    }
+
+    /// Admin function to point `pull_reward_funding` at a token account, drawing at most `limit` per period
+    pub fn set_reward_funding_source(ctx: Context<AdminOperation>, source: Pubkey, limit: u64) -> Result<()> {
+        let bank = &mut ctx.accounts.bank_account;
+        bank.reward_funding_source = source;
+        bank.reward_funding_limit = limit;
+        
+        msg!("Reward funding source set to {} with limit {}", source, limit);
+        Ok(())
+    }
+
+    /// Admin function to enter or leave upgrade mode
+    pub fn set_upgrade_mode(ctx: Context<UpgradeModeOperation>, enabled: bool) -> Result<()> {
+        let bank = &mut ctx.accounts.bank_account;
+        bank.upgrade_mode = enabled;
+        
+        msg!("Upgrade mode set to: {}", enabled);
+        Ok(())
+    }
+
+    /// Admin function to propose a new admin, who must accept before taking over
+    pub fn propose_admin(ctx: Context<AdminOperation>, new_admin: Pubkey) -> Result<()> {
+        let bank = &mut ctx.accounts.bank_account;
+        bank.pending_admin = new_admin;
+        
+        emit!(AdminTransferProposed {
+            schema_version: EVENT_SCHEMA_VERSION,
+            admin: bank.admin,
+            pending_admin: new_admin,
+        });
+        
+        msg!("Admin transfer proposed to: {}", new_admin);
+        Ok(())
+    }
+
+    /// Accept a pending admin transfer; the signer must be the proposed admin
+    pub fn accept_admin(ctx: Context<AcceptAdmin>) -> Result<()> {
+        let bank = &mut ctx.accounts.bank_account;
+        let previous_admin = bank.admin;
+        
+        bank.admin = ctx.accounts.new_admin.key();
+        bank.pending_admin = Pubkey::default();
+        
+        emit!(AdminTransferAccepted {
+            schema_version: EVENT_SCHEMA_VERSION,
+            previous_admin,
+            new_admin: bank.admin,
+        });
+        
+        msg!("Admin transferred from {} to {}", previous_admin, bank.admin);
+        Ok(())
+    }
+
+    /// Admin function to choose whether `delete_user` pays out or rejects on unclaimed rewards
+    pub fn set_pay_rewards_on_delete(ctx: Context<AdminOperation>, pay: bool) -> Result<()> {
+        let bank = &mut ctx.accounts.bank_account;
+        bank.pay_rewards_on_delete = pay;
+        
+        msg!("Pay rewards on delete set to: {}", pay);
+        Ok(())
+    }
+
+    /// Admin function to pause or resume a single operation
+    pub fn set_pause(ctx: Context<AdminOperation>, operation: OpKind, paused: bool) -> Result<()> {
+        let bank = &mut ctx.accounts.bank_account;
+        
+        if paused {
+            bank.paused_operations |= operation.mask();
+        } else {
+            bank.paused_operations &= !operation.mask();
+        }
+        
+        msg!("{:?} paused set to: {}", operation, paused);
+        Ok(())
+    }
+
+    /// Admin function to toggle bank operational status: pauses every operation
+    /// if any is still running, otherwise resumes them all
+    pub fn toggle_bank_status(ctx: Context<AdminOperation>) -> Result<()> {
+        let bank = &mut ctx.accounts.bank_account;
+        
+        // Check if caller is admin
+        if ctx.accounts.admin.key() != bank.admin {
+            return Err(ErrorCode::Unauthorized.into());
+        }
+        
+        bank.paused_operations = if bank.paused_operations == OpKind::ALL {
+            0
+        } else {
+            OpKind::ALL
+        };
+        let is_operational = bank.paused_operations == 0;
+        
+        emit!(BankStatusChanged {
+            schema_version: EVENT_SCHEMA_VERSION,
+            admin: ctx.accounts.admin.key(),
+            is_operational,
+        });
+        
+        msg!("Bank operational status changed to: {}", is_operational);
+        Ok(())
+    }
+
+    /// Admin function to add funds to bank
+    pub fn add_bank_funds(ctx: Context<AddBankFunds>, amount: u64) -> Result<()> {
+        // Check if caller is admin
+        if ctx.accounts.admin.key() != ctx.accounts.bank_account.admin {
+            return Err(ErrorCode::Unauthorized.into());
+        }
+        
+        if amount == 0 {
+            return Err(ErrorCode::InvalidAmount.into());
+        }
+        
+        // Move real tokens into the vault and credit only what actually arrived
+        let vault_before = ctx.accounts.vault_token_account.amount;
+        let transfer_ctx = CpiContext::new(
+            ctx.accounts.token_program.to_account_info(),
+            Transfer {
+                from: ctx.accounts.admin_token_account.to_account_info(),
+                to: ctx.accounts.vault_token_account.to_account_info(),
+                authority: ctx.accounts.admin.to_account_info(),
+            },
+        );
+        token::transfer(transfer_ctx, amount)?;
+        ctx.accounts.vault_token_account.reload()?;
+        let received = ctx.accounts.vault_token_account.amount
+            .checked_sub(vault_before)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        
+        let bank = &mut ctx.accounts.bank_account;
+        bank.balance = bank.balance
+            .checked_add(received)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        bank.protocol_owned_balance = bank.protocol_owned_balance
+            .checked_add(received)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        
+        emit!(BankFundsAdded {
+            schema_version: EVENT_SCHEMA_VERSION,
+            admin: ctx.accounts.admin.key(),
+            amount: received,
+            new_balance: bank.balance,
+        });
+        
+        msg!("Added {} tokens to bank balance", received);
+        Ok(())
+    }
+
+    /// Top up the reward pool from the reward funding source, within the per-period limit; anyone may call
+    pub fn pull_reward_funding(ctx: Context<PullRewardFunding>, amount: u64) -> Result<()> {
+        if amount == 0 {
+            return Err(ErrorCode::InvalidAmount.into());
+        }
+        
+        let now = ctx.accounts.clock.unix_timestamp;
+        let bank = &mut ctx.accounts.bank_account;
+        
+        // Start a new window once the current one has elapsed
+        if now >= bank.reward_funding_window_start.saturating_add(REWARD_FUNDING_PERIOD_SECONDS) {
+            bank.reward_funding_window_start = now;
+            bank.reward_funded_in_window = 0;
+        }
+        
+        let funded_in_window = bank.reward_funded_in_window
+            .checked_add(amount)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        if funded_in_window > bank.reward_funding_limit {
+            return Err(ErrorCode::RewardFundingLimitExceeded.into());
+        }
+        bank.reward_funded_in_window = funded_in_window;
+        
+        // The bank PDA moves the tokens as the source's approved delegate
+        let bank_bump = [ctx.bumps.bank_account];
+        let bank_seeds: &[&[u8]] = &[b"bank", &bank_bump];
+        let signer_seeds = &[bank_seeds];
+        let vault_before = ctx.accounts.vault_token_account.amount;
+        let transfer_ctx = CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            Transfer {
+                from: ctx.accounts.funding_source.to_account_info(),
+                to: ctx.accounts.vault_token_account.to_account_info(),
+                authority: ctx.accounts.bank_account.to_account_info(),
+            },
+            signer_seeds,
+        );
+        token::transfer(transfer_ctx, amount)?;
+        ctx.accounts.vault_token_account.reload()?;
+        let received = ctx.accounts.vault_token_account.amount
+            .checked_sub(vault_before)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        
+        let bank = &mut ctx.accounts.bank_account;
+        bank.balance = bank.balance
+            .checked_add(received)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        bank.protocol_owned_balance = bank.protocol_owned_balance
+            .checked_add(received)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        
+        emit!(RewardFundingPulled {
+            schema_version: EVENT_SCHEMA_VERSION,
+            caller: ctx.accounts.caller.key(),
+            source: ctx.accounts.funding_source.key(),
+            amount: received,
+            new_balance: bank.balance,
+        });
+        
+        msg!("Pulled {} tokens of reward funding", received);
+        Ok(())
+    }
+}
+
+/// The accounts a transfer out of the vault is signed with, captured before a handler
+/// borrows the bank mutably
+struct VaultAccounts<'info> {
+    token_program: AccountInfo<'info>,
+    vault: AccountInfo<'info>,
+    bank: AccountInfo<'info>,
+}
+
+impl<'info> VaultAccounts<'info> {
+    fn new(
+        token_program: &Program<'info, Token>,
+        vault: &Account<'info, TokenAccount>,
+        bank: &Account<'info, Bank>,
+    ) -> Self {
+        Self {
+            token_program: token_program.to_account_info(),
+            vault: vault.to_account_info(),
+            bank: bank.to_account_info(),
+        }
+    }
+}
+
+/// Move `amount` out of the vault to `to`, signed by the bank PDA that owns the vault
+fn transfer_from_vault<'info>(
+    accounts: &VaultAccounts<'info>,
+    bank_bump: u8,
+    to: AccountInfo<'info>,
+    amount: u64,
+) -> Result<()> {
+    let bank_bump = [bank_bump];
+    let bank_seeds: &[&[u8]] = &[b"bank", &bank_bump];
+    let signer_seeds = &[bank_seeds];
+    let transfer_ctx = CpiContext::new_with_signer(
+        accounts.token_program.clone(),
+        Transfer {
+            from: accounts.vault.clone(),
+            to,
+            authority: accounts.bank.clone(),
+        },
+        signer_seeds,
+    );
+    
+    token::transfer(transfer_ctx, amount)
+}
+
+/// Everything a stake will change, worked out before any state is written
+struct StakePlan {
+    reward: u64,
+    bonus: u64,
+    lock_tier: u8,
+    lock_multiplier_bps: u64,
+    lock_end_slot: u64,
+}
+
+// Helper functions
+/// Check that `user` may stake `amount` more and compute the resulting position. With
+/// `pay_now` the settled reward and welcome bonus are paid out, otherwise they are left
+/// in `pending_reward`. Reads state only, so a failure leaves nothing to roll back.
+fn plan_stake(
+    bank: &Bank,
+    user: &User,
+    amount: u64,
+    lock_slots: u64,
+    current_slot: u64,
+    now: i64,
+    pay_now: bool,
+) -> Result<StakePlan> {
+    // Check if account is frozen
+    if user.is_frozen {
+        return Err(ErrorCode::AccountFrozen.into());
+    }
+    
+    // Check sufficient balance; collateral locked by loans is not spendable
+    if spendable_balance(user) < amount {
+        return Err(ErrorCode::InsufficientBalance.into());
+    }
+    
+    // Under the APY model a dust stake can truncate to zero reward for a whole year
+    if bank.reward_per_slot == 0 && calculate_annual_reward_liability(bank, amount)? == 0 {
+        if bank.reject_unrewarded_stakes {
+            return Err(ErrorCode::StakeTooSmallForRewards.into());
+        }
+        msg!("Warning: stake of {} is too small to earn rewards", amount);
+    }
+    
+    // Settle the existing position's reward before the stake changes
+    let reward = if user.staked_balance > 0 {
+        calculate_pending_reward(bank, user, current_slot)?
+    } else {
+        0
+    };
+    
+    // Check if bank can pay reward
+    if pay_now && available_bank_balance(bank) < reward {
+        return Err(ErrorCode::BankInsufficientFunds.into());
+    }
+    
+    // One-time welcome bonus; accounts that staked before the flag existed have a stake_start_slot
+    let mut bonus = 0;
+    if !user.has_staked_before && user.stake_start_slot == 0 && bank.welcome_bonus > 0 {
+        if available_bank_balance(bank).saturating_sub(reward) < bank.welcome_bonus {
+            msg!("Warning: reward pool cannot cover the {} welcome bonus", bank.welcome_bonus);
+        } else {
+            bonus = bank.welcome_bonus;
+        }
+    }
+    
+    // The lock tier applies to the whole position once its reward is settled
+    let remaining_lock = user.lock_end_slot.saturating_sub(current_slot);
+    let effective_lock = lock_slots.max(remaining_lock);
+    let (lock_tier, lock_multiplier_bps) = find_lock_tier(effective_lock);
+    let lock_end_slot = current_slot
+        .checked_add(effective_lock)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    
+    // Keep any active loan above the minimum health factor once the balance is staked
+    let mut projected = user.clone();
+    projected.balance = projected.balance
+        .checked_sub(amount)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    projected.staked_balance = projected.staked_balance
+        .checked_add(amount)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    assert_health_factor(bank, &projected, now)?;
+    
+    Ok(StakePlan {
+        reward,
+        bonus,
+        lock_tier,
+        lock_multiplier_bps,
+        lock_end_slot,
+    })
+}
+
+/// Apply a `plan_stake` result, returning the amount the caller must credit to the reward recipient
+fn commit_stake(
+    bank: &mut Bank,
+    user: &mut User,
+    plan: &StakePlan,
+    amount: u64,
+    current_slot: u64,
+    pay_now: bool,
+    outflow: &mut u64,
+) -> Result<u64> {
+    let payout = plan.reward
+        .checked_add(plan.bonus)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    
+    // Accumulator first so the reward debt below uses the settled share
+    update_reward_accumulator(bank, current_slot)?;
+    if pay_now {
+        record_outflow(bank, outflow, payout)?;
+        bank.balance = bank.balance
+            .checked_sub(payout)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        bank.report_rewards_paid = bank.report_rewards_paid
+            .checked_add(payout)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        bank.total_rewards_paid = bank.total_rewards_paid
+            .checked_add(payout)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+    }
+    bank.staked_balance = bank.staked_balance
+        .checked_add(amount)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    
+    // The lock period runs from the first stake of a position
+    if user.staked_balance == 0 {
+        user.stake_start_slot = current_slot;
+    }
+    user.has_staked_before = true;
+    user.stake_slot = current_slot;
+    reset_token_slots(bank, user, current_slot)?;
+    if !pay_now {
+        user.pending_reward = payout;
+    }
+    user.lock_end_slot = plan.lock_end_slot;
+    user.lock_multiplier_bps = plan.lock_multiplier_bps;
+    user.balance = user.balance
+        .checked_sub(amount)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    user.staked_balance = user.staked_balance
+        .checked_add(amount)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    user.reward_debt = calculate_reward_debt(user.staked_balance, bank.acc_reward_per_share)?;
+    
+    if plan.bonus > 0 {
+        emit!(WelcomeBonusPaid {
+            schema_version: EVENT_SCHEMA_VERSION,
+            user: user.owner,
+            amount: plan.bonus,
+        });
+    }
+    
+    Ok(if pay_now { payout } else { 0 })
+}
+
+/// Move `amount` from a user's stake back to their balance and pay the accrued reward
+fn process_unstake<'info>(
+    bank: &mut Account<'info, Bank>,
+    user: &mut Account<'info, User>,
+    recipient: &mut Option<Account<'info, User>>,
+    amount: u64,
+    current_slot: u64,
+    outflow: &mut u64,
+) -> Result<u64> {
+    check_unstake_allowed(user, amount, current_slot, false)?;
+    
+    // An expired grant no longer restricts the position
+    if current_slot >= user.grant_unlock_slot {
+        user.granted_stake = 0;
+    }
+    
+    // Bring the emission accumulator up to date before the staked total changes
+    update_reward_accumulator(bank, current_slot)?;
+    
+    // Settle the whole position's reward so the remainder restarts accrual from now
+    let reward = calculate_pending_reward(bank, user, current_slot)?;
+    
+    // Check if bank can pay reward
+    if available_bank_balance(bank) < reward {
+        return Err(ErrorCode::BankInsufficientFunds.into());
+    }
+    
+    // Update balances
+    user.staked_balance = user.staked_balance
+        .checked_sub(amount)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    user.balance = user.balance
+        .checked_add(amount)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    credit_reward(user, recipient, reward)?;
+    bank.staked_balance = bank.staked_balance
+        .checked_sub(amount)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    bank.balance = bank.balance
+        .checked_sub(reward)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    record_outflow(bank, outflow, reward)?;
+    bank.report_rewards_paid = bank.report_rewards_paid
+        .checked_add(reward)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    bank.total_rewards_paid = bank.total_rewards_paid
+        .checked_add(reward)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    user.reward_debt = calculate_reward_debt(user.staked_balance, bank.acc_reward_per_share)?;
+    user.stake_slot = current_slot;
+    reset_token_slots(bank, user, current_slot)?;
+    
+    // Staked collateral leaving the position must still leave open loans covered
+    assert_collateral_covered(user)?;
+    
+    Ok(reward)
+}
+
+/// Preconditions shared by `unstake`, `force_unstake` and `quote_net_reward`
+fn check_unstake_allowed(user: &User, amount: u64, current_slot: u64, early_exit: bool) -> Result<()> {
+    // Check if account is frozen
+    if user.is_frozen {
+        return Err(ErrorCode::AccountFrozen.into());
+    }
+    
+    // Check if user has sufficient staked balance
+    if user.staked_balance < amount {
+        return Err(ErrorCode::InsufficientBalance.into());
+    }
+    
+    // Positions must be held for the minimum period, measured from the first stake
+    let slots_held = current_slot.saturating_sub(user.stake_start_slot);
+    if slots_held < MIN_STAKING_SLOTS {
+        return Err(ErrorCode::MinimumStakingPeriodNotMet.into());
+    }
+    
+    // Granted stake stays locked until its unlock slot
+    if current_slot < user.grant_unlock_slot {
+        let unlocked = user.staked_balance.saturating_sub(user.granted_stake);
+        if amount > unlocked {
+            return Err(ErrorCode::MinimumStakingPeriodNotMet.into());
+        }
+    }
+    
+    // A tiered lock covers the whole position unless the early-exit penalty is paid
+    if !early_exit && current_slot < user.lock_end_slot {
+        return Err(ErrorCode::MinimumStakingPeriodNotMet.into());
+    }
+    
+    // Stake pledged to open loans stays put until they are repaid
+    if amount > user.staked_balance.saturating_sub(user.locked_staked_balance) {
+        return Err(ErrorCode::InvalidCollateralRatio.into());
+    }
+    
+    Ok(())
+}
+
+fn credit_reward<'info>(
+    user: &mut Account<'info, User>,
+    recipient: &mut Option<Account<'info, User>>,
+    reward: u64,
+) -> Result<()> {
+    if reward == 0 {
+        return Ok(());
+    }
+    
+    // Rewards stay with the staker unless another recipient was designated
+    if user.reward_recipient == user.key() {
+        user.balance = user.balance
+            .checked_add(reward)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        return Ok(());
+    }
+    
+    let recipient = recipient.as_mut().ok_or(ErrorCode::InvalidAddress)?;
+    if recipient.key() != user.reward_recipient {
+        return Err(ErrorCode::InvalidAddress.into());
+    }
+    
+    recipient.balance = recipient.balance
+        .checked_add(reward)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    
+    Ok(())
+}
+
+/// APY reward on the time-weighted staked balance since the last settlement
+fn calculate_staking_reward(bank: &Bank, current_slot: u64, user: &User) -> Result<u64> {
+    // An unset stake_slot on a live position would accrue from genesis
+    if user.staked_balance > 0 && user.stake_slot == 0 {
+        return Err(ErrorCode::InvalidAmount.into());
+    }
+    
+    let (apy_weight, boost_weight) = calculate_open_token_slots(bank, user, current_slot)?;
+    let apy_weight = user.accumulated_apy_weight
+        .checked_add(apy_weight)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    let boost_weight = user.accumulated_boost_weight
+        .checked_add(boost_weight)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    
+    // Calculate reward based on APY, plus any boost earned inside its window
+    let reward = apy_weight
+        .checked_add(boost_weight)
+        .ok_or(ErrorCode::ArithmeticOverflow)?
+        .checked_div(BASIS_POINTS_DIVISOR as u128)
+        .ok_or(ErrorCode::ArithmeticOverflow)?
+        .checked_div(SLOTS_PER_YEAR as u128)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    
+    // Stakers who have never been liquidated earn the clean-record bonus on top
+    let reward = if user.liquidation_count == 0 {
+        reward
+            .checked_mul((BASIS_POINTS_DIVISOR + bank.clean_record_bonus_bps) as u128)
+            .ok_or(ErrorCode::ArithmeticOverflow)?
+            .checked_div(BASIS_POINTS_DIVISOR as u128)
+            .ok_or(ErrorCode::ArithmeticOverflow)?
+    } else {
+        reward
+    };
+    
+    u64::try_from(reward).map_err(|_| ErrorCode::ArithmeticOverflow.into())
+}
+
+/// APY-weighted and boost-weighted token-slots held since `last_update_slot`
+fn calculate_open_token_slots(bank: &Bank, user: &User, current_slot: u64) -> Result<(u128, u128)> {
+    // The APY index already weights each slot by the rate in force at the time
+    let index_delta = projected_apy_index(bank, current_slot)?
+        .checked_sub(user.apy_index_checkpoint)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    
+    // A granted boost only adds to the slots that fall inside its window
+    let boost_start = user.last_update_slot.max(user.boost_start_slot);
+    let boosted_slots = current_slot
+        .min(user.boost_until_slot)
+        .saturating_sub(boost_start);
+    
+    let apy_weight = (user.staked_balance as u128)
+        .checked_mul(index_delta)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    
+    // The lock tier scales only the slots before lock_end_slot; the index delta is
+    // split in proportion to slots, as the index does not record when the APY changed
+    let multiplier_bps = if user.lock_multiplier_bps == 0 {
+        BASIS_POINTS_DIVISOR
+    } else {
+        user.lock_multiplier_bps
+    };
+    let open_slots = current_slot.saturating_sub(user.last_update_slot);
+    let locked_slots = current_slot
+        .min(user.lock_end_slot)
+        .saturating_sub(user.last_update_slot);
+    let apy_weight = if multiplier_bps == BASIS_POINTS_DIVISOR || locked_slots == 0 {
+        apy_weight
+    } else {
+        let locked_weight = apy_weight
+            .checked_mul(locked_slots as u128)
+            .ok_or(ErrorCode::ArithmeticOverflow)?
+            .checked_div(open_slots as u128)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        let boosted_locked_weight = locked_weight
+            .checked_mul(multiplier_bps as u128)
+            .ok_or(ErrorCode::ArithmeticOverflow)?
+            .checked_div(BASIS_POINTS_DIVISOR as u128)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        apy_weight
+            .checked_sub(locked_weight)
+            .ok_or(ErrorCode::ArithmeticOverflow)?
+            .checked_add(boosted_locked_weight)
+            .ok_or(ErrorCode::ArithmeticOverflow)?
+    };
+    let boost_weight = (user.staked_balance as u128)
+        .checked_mul(boosted_slots as u128)
+        .ok_or(ErrorCode::ArithmeticOverflow)?
+        .checked_mul(user.boost_bps as u128)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    
+    Ok((apy_weight, boost_weight))
+}
+
+/// Fold the interval since `last_update_slot` into the integrals; call before `staked_balance` changes
+fn accrue_token_slots(bank: &Bank, user: &mut User, current_slot: u64) -> Result<()> {
+    let (apy_weight, boost_weight) = calculate_open_token_slots(bank, user, current_slot)?;
+    user.accumulated_apy_weight = user.accumulated_apy_weight
+        .checked_add(apy_weight)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    user.accumulated_boost_weight = user.accumulated_boost_weight
+        .checked_add(boost_weight)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    user.last_update_slot = current_slot;
+    user.apy_index_checkpoint = projected_apy_index(bank, current_slot)?;
+    
+    Ok(())
+}
+
+/// Start a fresh accrual interval once the pending reward has been paid
+fn reset_token_slots(bank: &Bank, user: &mut User, current_slot: u64) -> Result<()> {
+    user.pending_reward = 0;
+    user.accumulated_apy_weight = 0;
+    user.accumulated_boost_weight = 0;
+    user.last_update_slot = current_slot;
+    user.apy_index_checkpoint = projected_apy_index(bank, current_slot)?;
+    
+    // Settled weight already stops the tier at lock_end_slot; drop an expired tier
+    if current_slot >= user.lock_end_slot {
+        user.lock_multiplier_bps = BASIS_POINTS_DIVISOR;
+    }
+    
+    Ok(())
+}
+
+/// Cumulative APY bps-slots up to `current_slot`. Under a utilization target the slots since
+/// the last checkpoint accrue at the rate current utilization implies; handlers that move
+/// utilization checkpoint first, so earlier slots keep the rate they accrued at.
+fn projected_apy_index(bank: &Bank, current_slot: u64) -> Result<u128> {
+    let slots = current_slot.saturating_sub(bank.apy_index_slot);
+    let index = (effective_staking_apy_bps(bank)? as u128)
+        .checked_mul(slots as u128)
+        .ok_or(ErrorCode::ArithmeticOverflow)?
+        .checked_add(bank.apy_index)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    
+    Ok(index)
+}
+
+/// Share of bank liquidity currently lent out: `lent_balance / (balance + lent_balance)`, 0 when empty
+fn calculate_utilization_bps(bank: &Bank) -> Result<u64> {
+    let total = (bank.balance as u128)
+        .checked_add(bank.lent_balance as u128)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    if total == 0 {
+        return Ok(0);
+    }
+    
+    let utilization = (bank.lent_balance as u128)
+        .checked_mul(BASIS_POINTS_DIVISOR as u128)
+        .ok_or(ErrorCode::ArithmeticOverflow)?
+        .checked_div(total)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    
+    Ok(utilization as u64)
+}
+
+/// APY falling linearly from the max at zero utilization to the min at the target,
+/// so idle liquidity pays stakers more until borrowers pick it up
+fn calculate_dynamic_apy_bps(bank: &Bank) -> Result<u64> {
+    let utilization = calculate_utilization_bps(bank)?;
+    if utilization >= bank.target_utilization_bps {
+        return Ok(bank.min_staking_apy_bps);
+    }
+    
+    let spread = bank.max_staking_apy_bps
+        .checked_sub(bank.min_staking_apy_bps)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    let reduction = (spread as u128)
+        .checked_mul(utilization as u128)
+        .ok_or(ErrorCode::ArithmeticOverflow)?
+        .checked_div(bank.target_utilization_bps as u128)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    
+    Ok(bank.max_staking_apy_bps - reduction as u64)
+}
+
+/// The dynamic APY while a utilization target is set, otherwise the admin-set rate
+fn effective_staking_apy_bps(bank: &Bank) -> Result<u64> {
+    if bank.target_utilization_bps == 0 {
+        return Ok(bank.staking_apy_bps);
+    }
+    
+    calculate_dynamic_apy_bps(bank)
+}
+
+/// Checkpoint the APY index so past slots keep the rate they accrued at
+fn update_apy_index(bank: &mut Bank, current_slot: u64) -> Result<()> {
+    bank.apy_index = projected_apy_index(bank, current_slot)?;
+    bank.apy_index_slot = current_slot;
+    
+    Ok(())
+}
+
+/// Principal plus carried and accrued interest across all of a user's open loans.
+/// Compounded loans are folded into one loan at their weighted rate and start, which
+/// is exact for a single loan and otherwise slightly below the per-loan sum.
+fn calculate_loan_debt(user: &User, now: i64) -> Result<u64> {
+    // Simple-interest loans: sum of base * rate * (now - start), from the user's running aggregates
+    let now_secs = u64::try_from(now).map_err(|_| ErrorCode::ArithmeticOverflow)?;
+    let simple_rate_weight = user.loan_rate_weight.saturating_sub(user.compound_rate_weight);
+    let simple_rate_time_weight = user.loan_rate_time_weight.saturating_sub(user.compound_rate_time_weight);
+    let simple_interest = simple_rate_weight
+        .checked_mul(now_secs as u128)
+        .ok_or(ErrorCode::ArithmeticOverflow)?
+        .saturating_sub(simple_rate_time_weight)
+        .checked_div(BASIS_POINTS_DIVISOR as u128)
+        .ok_or(ErrorCode::ArithmeticOverflow)?
+        .checked_div(SECONDS_PER_YEAR as u128)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    
+    // Compounded loans: round the rate up and the start down so the estimate errs high
+    let compound_interest = if user.compound_rate_weight == 0 || user.compound_loan_principal == 0 {
+        0
+    } else {
+        let rate_bps = u64::try_from(user.compound_rate_weight.div_ceil(user.compound_loan_principal as u128))
+            .map_err(|_| ErrorCode::ArithmeticOverflow)?;
+        let start = i64::try_from(user.compound_rate_time_weight / user.compound_rate_weight)
+            .map_err(|_| ErrorCode::ArithmeticOverflow)?;
+        let time_elapsed = now
+            .checked_sub(start)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        calculate_accrued_interest(true, user.compound_loan_principal, time_elapsed, rate_bps)?
+    };
+    
+    let debt = (user.lent_balance as u128)
+        .checked_add(user.loan_carried_interest as u128)
+        .ok_or(ErrorCode::ArithmeticOverflow)?
+        .checked_add(simple_interest)
+        .ok_or(ErrorCode::ArithmeticOverflow)?
+        .checked_add(compound_interest as u128)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    
+    u64::try_from(debt).map_err(|_| ErrorCode::ArithmeticOverflow.into())
+}
+
+/// Amount interest accrues on: the principal, plus carried interest when compounding
+fn loan_accrual_base(loan: &Loan) -> Result<u64> {
+    if loan.compound_interest {
+        loan.principal
+            .checked_add(loan.carried_interest)
+            .ok_or(ErrorCode::ArithmeticOverflow.into())
+    } else {
+        Ok(loan.principal)
+    }
+}
+
+/// Interest owed on one loan at `now`, before any early-repayment rebate
+fn calculate_loan_owed_interest(loan: &Loan, now: i64) -> Result<u64> {
+    let time_elapsed = now
+        .checked_sub(loan.start_timestamp)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    let accrued = calculate_accrued_interest(
+        loan.compound_interest,
+        loan_accrual_base(loan)?,
+        time_elapsed,
+        loan.interest_rate_snapshot,
+    )?;
+    
+    loan.carried_interest
+        .checked_add(accrued)
+        .ok_or(ErrorCode::ArithmeticOverflow.into())
+}
+
+/// A loan's (base * rate, base * rate * start) terms in the user's debt aggregates
+fn calculate_loan_weights(loan: &Loan) -> Result<(u128, u128)> {
+    let rate_weight = (loan_accrual_base(loan)? as u128)
+        .checked_mul(loan.interest_rate_snapshot as u128)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    let start = u64::try_from(loan.start_timestamp).map_err(|_| ErrorCode::ArithmeticOverflow)?;
+    let rate_time_weight = rate_weight
+        .checked_mul(start as u128)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    
+    Ok((rate_weight, rate_time_weight))
+}
+
+fn add_loan_weights(bank: &mut Bank, user: &mut User, loan: &Loan) -> Result<()> {
+    let (rate_weight, rate_time_weight) = calculate_loan_weights(loan)?;
+    bank.loan_rate_weight = bank.loan_rate_weight
+        .checked_add(rate_weight)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    user.loan_rate_weight = user.loan_rate_weight
+        .checked_add(rate_weight)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    user.loan_rate_time_weight = user.loan_rate_time_weight
+        .checked_add(rate_time_weight)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    user.loan_carried_interest = user.loan_carried_interest
+        .checked_add(loan.carried_interest)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    if loan.compound_interest {
+        user.compound_loan_principal = user.compound_loan_principal
+            .checked_add(loan_accrual_base(loan)?)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        user.compound_rate_weight = user.compound_rate_weight
+            .checked_add(rate_weight)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        user.compound_rate_time_weight = user.compound_rate_time_weight
+            .checked_add(rate_time_weight)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+    }
+    
+    Ok(())
+}
+
+fn remove_loan_weights(bank: &mut Bank, user: &mut User, loan: &Loan) -> Result<()> {
+    let (rate_weight, rate_time_weight) = calculate_loan_weights(loan)?;
+    // Loans opened before the bank aggregate existed were never added to it
+    bank.loan_rate_weight = bank.loan_rate_weight.saturating_sub(rate_weight);
+    user.loan_rate_weight = user.loan_rate_weight
+        .checked_sub(rate_weight)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    user.loan_rate_time_weight = user.loan_rate_time_weight
+        .checked_sub(rate_time_weight)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    user.loan_carried_interest = user.loan_carried_interest
+        .checked_sub(loan.carried_interest)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    if loan.compound_interest {
+        user.compound_loan_principal = user.compound_loan_principal
+            .checked_sub(loan_accrual_base(loan)?)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        user.compound_rate_weight = user.compound_rate_weight
+            .checked_sub(rate_weight)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        user.compound_rate_time_weight = user.compound_rate_time_weight
+            .checked_sub(rate_time_weight)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+    }
+    
+    Ok(())
+}
+
+/// Interest due if the loan were repaid at `now`, after any early-repayment rebate
+fn calculate_repayment_interest(bank: &Bank, loan: &Loan, now: i64) -> Result<u64> {
+    // Calculate interest based on time elapsed
+    let time_elapsed = now
+        .checked_sub(loan.start_timestamp)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    
+    let mut interest = calculate_loan_owed_interest(loan, now)?;
+    
+    // Rebate part of the interest when the loan is repaid within the early window
+    if bank.early_repay_rebate_bps > 0 && time_elapsed < bank.early_repay_window_seconds {
+        let rebate = interest
+            .checked_mul(bank.early_repay_rebate_bps)
+            .ok_or(ErrorCode::ArithmeticOverflow)?
+            .checked_div(BASIS_POINTS_DIVISOR)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        interest = interest
+            .checked_sub(rebate)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+    }
+    
+    Ok(interest)
+}
+
+/// Repay one loan in full from the user's balance, returning (principal, interest, total)
+fn settle_loan(bank: &mut Bank, user: &mut User, loan: &Loan, now: i64) -> Result<(u64, u64, u64)> {
+    let interest = calculate_repayment_interest(bank, loan, now)?;
+    let total_repayment = loan.principal
+        .checked_add(interest)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    
+    // Check if user has sufficient balance to repay
+    if user.balance < total_repayment {
+        return Err(ErrorCode::InsufficientBalance.into());
+    }
+    
+    // Update balances
+    let principal = loan.principal;
+    bank.lent_balance = bank.lent_balance
+        .checked_sub(principal)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    bank.balance = bank.balance
+        .checked_add(total_repayment)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    user.balance = user.balance
+        .checked_sub(total_repayment)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    user.lent_balance = user.lent_balance
+        .checked_sub(principal)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    user.locked_balance = user.locked_balance.saturating_sub(loan.locked_collateral);
+    user.locked_staked_balance = user.locked_staked_balance.saturating_sub(loan.locked_stake);
+    remove_loan_weights(bank, user, loan)?;
+    if user.lent_balance == 0 {
+        user.loan_timestamp = 0;
+    }
+    user.lifetime_interest_paid = user.lifetime_interest_paid
+        .checked_add(interest)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    bank.open_loans = bank.open_loans.saturating_sub(1);
+    bank.report_interest_collected = bank.report_interest_collected
+        .checked_add(interest)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    bank.total_interest_collected = bank.total_interest_collected
+        .checked_add(interest)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    
+    Ok((principal, interest, total_repayment))
+}
+
+/// Repay, in order, each passed loan of an `auto_repay` user that their balance covers.
+/// Closed loans return their rent to the user account, recoverable on `delete_user`.
+fn try_auto_repay<'info>(
+    bank: &mut Bank,
+    user: &mut Account<'info, User>,
+    loan_accounts: &'info [AccountInfo<'info>],
+    now: i64,
+) -> Result<()> {
+    // A paused repay skips auto-repayment rather than failing the host instruction
+    if !user.auto_repay || user.lent_balance == 0 || is_paused(bank, OpKind::Repay) {
+        return Ok(());
+    }
+    
+    if loan_accounts.len() > MAX_BATCH_SIZE {
+        return Err(ErrorCode::BatchTooLarge.into());
+    }
+    
+    for account_info in loan_accounts.iter() {
+        let loan: Account<'info, Loan> = Account::try_from(account_info)?;
+        if loan.owner != user.owner {
+            return Err(ErrorCode::Unauthorized.into());
+        }
+        
+        let interest = calculate_repayment_interest(bank, &loan, now)?;
+        let total_owed = loan.principal
+            .checked_add(interest)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        if user.balance < total_owed {
+            continue;
+        }
+        
+        let (principal, interest, total_repayment) = settle_loan(bank, user, &loan, now)?;
+        
+        emit!(RepayEvent {
+            schema_version: EVENT_SCHEMA_VERSION,
+            user: user.owner,
+            loan_index: loan.loan_index,
+            principal,
+            interest,
+            total_repayment,
+        });
+        
+        msg!("Auto-repaid loan {}: {} principal + {} interest for user: {}", loan.loan_index, principal, interest, user.owner);
+        loan.close(user.to_account_info())?;
+    }
+    
+    Ok(())
+}
+
+fn is_paused(bank: &Bank, operation: OpKind) -> bool {
+    bank.paused_operations & operation.mask() != 0
+}
+
+/// Highest `LOCK_TIERS` entry reached by `lock_slots`, as (tier index, multiplier bps)
+fn find_lock_tier(lock_slots: u64) -> (u8, u64) {
+    let mut tier = (0, LOCK_TIERS[0].1);
+    for (index, (min_slots, multiplier_bps)) in LOCK_TIERS.iter().enumerate() {
+        if lock_slots >= *min_slots {
+            tier = (index as u8, *multiplier_bps);
+        }
+    }
+    tier
+}
+
+/// Collateral credit for the staked position, discounted by `STAKE_COLLATERAL_FACTOR`
+fn staked_collateral(user: &User) -> u64 {
+    ((user.staked_balance as u128) * (STAKE_COLLATERAL_FACTOR as u128) / (PERCENTAGE_DIVISOR as u128)) as u64
+}
+
+/// Staked tokens to pledge behind `locked_collateral` of a new loan. Locked collateral is
+/// backed by the staked position first, so as much of it as the unpledged stake can cover,
+/// at `STAKE_COLLATERAL_FACTOR`, is locked there.
+fn calculate_stake_to_lock(user: &User, locked_collateral: u64) -> Result<u64> {
+    let unpledged = user.staked_balance.saturating_sub(user.locked_staked_balance);
+    let needed = (locked_collateral as u128)
+        .checked_mul(PERCENTAGE_DIVISOR as u128)
+        .ok_or(ErrorCode::ArithmeticOverflow)?
+        .div_ceil(STAKE_COLLATERAL_FACTOR as u128);
+    
+    Ok(u64::try_from(needed).unwrap_or(u64::MAX).min(unpledged))
+}
+
+/// Liquid balance plus the weighted staked position
+fn total_collateral(user: &User) -> u128 {
+    (user.balance as u128) + (staked_collateral(user) as u128)
+}
+
+/// Balance not locked as collateral for open loans. Locked collateral is backed by the
+/// staked position first, so only the remainder is held back from the liquid balance.
+fn spendable_balance(user: &User) -> u64 {
+    let liquid_locked = user.locked_balance.saturating_sub(staked_collateral(user));
+    user.balance.saturating_sub(liquid_locked)
+}
+
+/// Reject a change that leaves open loans' locked collateral uncovered
+fn assert_collateral_covered(user: &User) -> Result<()> {
+    if user.locked_balance as u128 > total_collateral(user) {
+        return Err(ErrorCode::InvalidCollateralRatio.into());
+    }
+    
+    Ok(())
+}
+
+/// Bank balance not earmarked by a bucket, available to lend or pay rewards from
+fn available_bank_balance(bank: &Bank) -> u64 {
+    bank.balance.saturating_sub(bank.bucketed_balance)
+}
+
+/// Collateral-backed borrow limit; `min_residual_balance` stays un-borrowable to cover interest
+fn calculate_max_borrow(bank: &Bank, user: &User) -> Result<u64> {
+    // Loan proceeds sit in `balance` too; counting them as collateral would allow recursive leverage
+    let net_collateral = total_collateral(user)
+        .saturating_sub(user.locked_balance as u128)
+        .saturating_sub(user.lent_balance as u128);
+    let borrowable_balance = net_collateral.saturating_sub(bank.min_residual_balance as u128);
+    let max_borrow = borrowable_balance
+        .checked_mul(bank.collateral_ratio as u128)
+        .ok_or(ErrorCode::ArithmeticOverflow)?
+        .checked_div(PERCENTAGE_DIVISOR as u128)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    
+    Ok(u64::try_from(max_borrow).unwrap_or(u64::MAX))
+}
+
+/// Collateral value over outstanding debt (principal plus accrued interest), in basis points
+fn calculate_health_factor(bank: &Bank, user: &User, now: i64) -> Result<u64> {
+    if user.lent_balance == 0 {
+        return Ok(u64::MAX);
+    }
+    
+    let debt = calculate_loan_debt(user, now)?;
+    
+    let collateral_value = total_collateral(user)
+        .checked_mul(bank.collateral_ratio as u128)
+        .ok_or(ErrorCode::ArithmeticOverflow)?
+        .checked_div(PERCENTAGE_DIVISOR as u128)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    let health_factor = collateral_value
+        .checked_mul(BASIS_POINTS_DIVISOR as u128)
+        .ok_or(ErrorCode::ArithmeticOverflow)?
+        .checked_div(debt as u128)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    
+    Ok(u64::try_from(health_factor).unwrap_or(u64::MAX))
+}
+
+fn assert_health_factor(bank: &Bank, user: &User, now: i64) -> Result<()> {
+    if bank.min_health_factor_bps == 0 || user.lent_balance == 0 {
+        return Ok(());
+    }
+    
+    if calculate_health_factor(bank, user, now)? < bank.min_health_factor_bps {
+        return Err(ErrorCode::InvalidCollateralRatio.into());
+    }
+    
+    Ok(())
+}
+
+/// Bank balance available to pay out over total staked principal, in basis points
+fn calculate_solvency_bps(bank: &Bank) -> Result<u64> {
+    if bank.staked_balance == 0 {
+        return Ok(u64::MAX);
+    }
+    
+    let solvency = (bank.balance as u128)
+        .checked_mul(BASIS_POINTS_DIVISOR as u128)
+        .ok_or(ErrorCode::ArithmeticOverflow)?
+        .checked_div(bank.staked_balance as u128)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    
+    Ok(u64::try_from(solvency).unwrap_or(u64::MAX))
+}
+
+/// Render a raw token amount as a decimal string, e.g. 1500000000 with 9 decimals as "1.5"
+fn format_ui_amount(amount: u64, decimals: u8) -> Result<String> {
+    if decimals == 0 {
+        return Ok(amount.to_string());
+    }
+    
+    let scale = 10u64
+        .checked_pow(decimals as u32)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    let whole = amount / scale;
+    let fraction = amount % scale;
+    
+    if fraction == 0 {
+        return Ok(whole.to_string());
+    }
+    
+    let fraction = format!("{:0width$}", fraction, width = decimals as usize);
+    Ok(format!("{}.{}", whole, fraction.trim_end_matches('0')))
+}
+
+fn validate_deposit_amount(amount: u64) -> Result<()> {
+    if amount == 0 {
+        return Err(ErrorCode::InvalidAmount.into());
+    }
+    
+    // Reject dust deposits that carry no economic weight
+    if amount < MIN_DEPOSIT_AMOUNT {
+        return Err(ErrorCode::DepositTooSmall.into());
+    }
+    
+    if amount > MAX_DEPOSIT_AMOUNT {
+        return Err(ErrorCode::AmountTooLarge.into());
+    }
+    
+    Ok(())
+}
+
+/// TVL is net deposits; staking moves funds that were already deposited, so it is not counted again
+fn check_tvl_cap(bank: &Bank, additional: u64) -> Result<()> {
+    if bank.max_tvl == 0 {
+        return Ok(());
+    }
+    
+    let new_tvl = bank.total_deposits
+        .checked_add(additional)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    
+    if new_tvl > bank.max_tvl {
+        return Err(ErrorCode::TvlCapExceeded.into());
+    }
+    
+    Ok(())
+}
+
+fn check_deposit_fraction(bank: &Bank, amount: u64) -> Result<()> {
+    if bank.max_deposit_fraction_bps == 0 {
+        return Ok(());
+    }
+    
+    // An empty pool has nothing to skew yet
+    let pool = (bank.balance as u128)
+        .checked_add(bank.total_deposits as u128)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    if pool == 0 {
+        return Ok(());
+    }
+    
+    let max_deposit = pool
+        .checked_mul(bank.max_deposit_fraction_bps as u128)
+        .ok_or(ErrorCode::ArithmeticOverflow)?
+        .checked_div(BASIS_POINTS_DIVISOR as u128)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    
+    if amount as u128 > max_deposit {
+        return Err(ErrorCode::DepositTooLargeRelative.into());
+    }
+    
+    Ok(())
+}
+
+fn check_max_deposit(bank: &Bank, amount: u64) -> Result<()> {
+    if bank.max_deposit > 0 && amount > bank.max_deposit {
+        return Err(ErrorCode::AmountTooLarge.into());
+    }
+    
+    Ok(())
+}
+
+/// Count `amount` against `max_tx_outflow`. `outflow` is the running total of the current
+/// instruction, kept by the handler, so the cap bounds each instruction on its own and one
+/// caller's payouts never use up another's allowance.
+fn record_outflow(bank: &Bank, outflow: &mut u64, amount: u64) -> Result<()> {
+    *outflow = check_outflow(bank, *outflow, amount)?;
+    
+    Ok(())
+}
+
+/// Instruction outflow once `amount` more leaves on top of `outflow`, rejected past the limit
+fn check_outflow(bank: &Bank, outflow: u64, amount: u64) -> Result<u64> {
+    let tx_outflow = outflow
+        .checked_add(amount)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    
+    if bank.max_tx_outflow > 0 && tx_outflow > bank.max_tx_outflow {
+        return Err(ErrorCode::TxOutflowExceeded.into());
+    }
+    
+    Ok(tx_outflow)
+}
+
+/// Per-share accumulator value at `current_slot`, where each staker earns
+/// `reward_per_slot * staked_balance / total_staked` for every elapsed slot
+fn projected_acc_reward_per_share(bank: &Bank, current_slot: u64) -> Result<u128> {
+    if current_slot <= bank.last_reward_slot || bank.reward_per_slot == 0 || bank.staked_balance == 0 {
+        return Ok(bank.acc_reward_per_share);
+    }
+    
+    let slots_elapsed = current_slot
+        .checked_sub(bank.last_reward_slot)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    
+    let increment = (bank.reward_per_slot as u128)
+        .checked_mul(slots_elapsed as u128)
+        .ok_or(ErrorCode::ArithmeticOverflow)?
+        .checked_mul(REWARD_PRECISION)
+        .ok_or(ErrorCode::ArithmeticOverflow)?
+        .checked_div(bank.staked_balance as u128)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    
+    let acc = bank.acc_reward_per_share
+        .checked_add(increment)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    
+    Ok(acc)
+}
+
+fn update_reward_accumulator(bank: &mut Bank, current_slot: u64) -> Result<()> {
+    if current_slot <= bank.last_reward_slot {
+        return Ok(());
+    }
+    
+    bank.acc_reward_per_share = projected_acc_reward_per_share(bank, current_slot)?;
+    bank.last_reward_slot = current_slot;
+    Ok(())
+}
+
+/// Reward owed at `current_slot` on a user's whole staked position under the active reward
+/// model, including any amount already settled into `pending_reward`, bounded by `reward_cap`
+fn calculate_pending_reward(bank: &Bank, user: &User, current_slot: u64) -> Result<u64> {
+    if user.staked_balance == 0 {
+        return Ok(user.pending_reward);
+    }
+    
+    let accrued = if bank.reward_per_slot > 0 {
+        let acc = projected_acc_reward_per_share(bank, current_slot)?;
+        calculate_emission_reward(user.staked_balance, user.reward_debt, acc)?
+    } else {
+        calculate_staking_reward(bank, current_slot, user)?
+    };
+    
+    let pending = accrued
+        .checked_add(user.pending_reward)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    
+    // Accrual stops at the cap until a payout resets the position
+    if user.reward_cap > 0 {
+        return Ok(pending.min(user.reward_cap));
+    }
+    
+    Ok(pending)
+}
+
+fn calculate_reward_debt(staked_balance: u64, acc_reward_per_share: u128) -> Result<u128> {
+    let debt = (staked_balance as u128)
+        .checked_mul(acc_reward_per_share)
+        .ok_or(ErrorCode::ArithmeticOverflow)?
+        .checked_div(REWARD_PRECISION)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    
+    Ok(debt)
+}
+
+fn calculate_emission_reward(staked_balance: u64, reward_debt: u128, acc_reward_per_share: u128) -> Result<u64> {
+    let accumulated = calculate_reward_debt(staked_balance, acc_reward_per_share)?;
+    let reward = accumulated.saturating_sub(reward_debt);
+    
+    u64::try_from(reward).map_err(|_| ErrorCode::ArithmeticOverflow.into())
+}
+
+fn calculate_annual_reward_liability(bank: &Bank, staked_balance: u64) -> Result<u64> {
+    let liability = staked_balance
+        .checked_mul(effective_staking_apy_bps(bank)?)
+        .ok_or(ErrorCode::ArithmeticOverflow)?
+        .checked_div(BASIS_POINTS_DIVISOR)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    
+    Ok(liability)
+}
+
+/// Interest accrued on `principal` under the simple or daily-compounded model
+fn calculate_accrued_interest(compound: bool, principal: u64, time_elapsed_seconds: i64, rate_bps: u64) -> Result<u64> {
+    if compound {
+        calculate_compound_interest(principal, time_elapsed_seconds, rate_bps)
+    } else {
+        calculate_loan_interest(principal, time_elapsed_seconds, rate_bps)
+    }
+}
+
+/// Interest compounded every `COMPOUND_PERIOD_SECONDS`, iterated for at most
+/// `MAX_COMPOUND_PERIODS`; any time past that accrues simple interest on the compounded amount
+fn calculate_compound_interest(principal: u64, time_elapsed_seconds: i64, rate_bps: u64) -> Result<u64> {
+    if time_elapsed_seconds <= 0 {
+        return Ok(0);
+    }
+    
+    let time_elapsed = time_elapsed_seconds as u64;
+    let periods = (time_elapsed / COMPOUND_PERIOD_SECONDS).min(MAX_COMPOUND_PERIODS);
+    let divisor = (BASIS_POINTS_DIVISOR as u128)
+        .checked_mul(SECONDS_PER_YEAR as u128)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    let period_factor = (rate_bps as u128)
+        .checked_mul(COMPOUND_PERIOD_SECONDS as u128)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    
+    let mut amount = (principal as u128)
+        .checked_mul(COMPOUND_PRECISION)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    for _ in 0..periods {
+        let period_interest = amount
+            .checked_mul(period_factor)
+            .ok_or(ErrorCode::ArithmeticOverflow)?
+            .checked_div(divisor)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+        amount = amount
+            .checked_add(period_interest)
+            .ok_or(ErrorCode::ArithmeticOverflow)?;
+    }
+    
+    let remaining_seconds = time_elapsed - periods * COMPOUND_PERIOD_SECONDS;
+    let tail_interest = amount
+        .checked_mul(rate_bps as u128)
+        .ok_or(ErrorCode::ArithmeticOverflow)?
+        .checked_mul(remaining_seconds as u128)
+        .ok_or(ErrorCode::ArithmeticOverflow)?
+        .checked_div(divisor)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    let interest = amount
+        .checked_add(tail_interest)
+        .ok_or(ErrorCode::ArithmeticOverflow)?
+        .checked_div(COMPOUND_PRECISION)
+        .ok_or(ErrorCode::ArithmeticOverflow)?
+        .checked_sub(principal as u128)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    
+    u64::try_from(interest).map_err(|_| ErrorCode::ArithmeticOverflow.into())
+}
+
+fn calculate_loan_interest(principal: u64, time_elapsed_seconds: i64, rate_bps: u64) -> Result<u64> {
+    if time_elapsed_seconds <= 0 {
+        return Ok(0);
+    }
+    
+    let time_elapsed = time_elapsed_seconds as u64;
+    
+    // Calculate simple interest; the intermediate product outgrows u64 within hours
+    let interest = (principal as u128)
+        .checked_mul(rate_bps as u128)
+        .ok_or(ErrorCode::ArithmeticOverflow)?
+        .checked_mul(time_elapsed as u128)
+        .ok_or(ErrorCode::ArithmeticOverflow)?
+        .checked_div(BASIS_POINTS_DIVISOR as u128)
+        .ok_or(ErrorCode::ArithmeticOverflow)?
+        .checked_div(SECONDS_PER_YEAR as u128)
+        .ok_or(ErrorCode::ArithmeticOverflow)?;
+    
+    u64::try_from(interest).map_err(|_| ErrorCode::ArithmeticOverflow.into())
+}
+
+// Account structures
+#[derive(Accounts)]
+pub struct InitializeBank<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    #[account(
+        init,
+        space = 8 + Bank::INIT_SPACE,
+        payer = admin,
+        seeds = [b"bank"],
+        bump
+    )]
+    pub bank_account: Account<'info, Bank>,
+
+    pub mint: Account<'info, Mint>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeVault<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    #[account(
+        seeds = [b"bank"],
+        bump,
+        constraint = bank_account.admin == admin.key() @ ErrorCode::Unauthorized,
+        constraint = !bank_account.upgrade_mode @ ErrorCode::UpgradeInProgress
+    )]
+    pub bank_account: Account<'info, Bank>,
+
+    #[account(address = bank_account.mint @ ErrorCode::InvalidMint)]
+    pub mint: Account<'info, Mint>,
+
+    #[account(
+        init,
+        payer = admin,
+        seeds = [b"vault"],
+        bump,
+        token::mint = mint,
+        token::authority = bank_account
+    )]
+    pub vault_token_account: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeUser<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"bank"],
+        bump,
+        constraint = !bank_account.upgrade_mode @ ErrorCode::UpgradeInProgress
+    )]
+    pub bank_account: Account<'info, Bank>,
+
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + User::INIT_SPACE,
+        seeds = [b"user", payer.key().as_ref()],
+        bump,
+    )]
+    pub user_account: Account<'info, User>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct DeleteUser<'info> {
+    pub clock: Sysvar<'info, Clock>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"bank"],
+        bump,
+        constraint = !bank_account.upgrade_mode @ ErrorCode::UpgradeInProgress
+    )]
+    pub bank_account: Account<'info, Bank>,
+
+    #[account(
+        mut,
+        seeds = [b"user", payer.key().as_ref()],
+        bump,
+        close = payer,
+    )]
+    pub user_account: Account<'info, User>,
+
+    #[account(address = bank_account.mint @ ErrorCode::InvalidMint)]
+    pub mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        token::mint = mint,
+        token::authority = payer
+    )]
+    pub user_token_account: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"vault"],
+        bump,
+        token::mint = mint,
+        token::authority = bank_account
+    )]
+    pub vault_token_account: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+
+    /// Required only when rewards are routed away from `user_account`
+    #[account(
+        mut,
+        seeds = [b"user", reward_recipient.owner.as_ref()],
+        bump,
+        constraint = reward_recipient.key() != user_account.key() @ ErrorCode::InvalidAddress
+    )]
+    pub reward_recipient: Option<Account<'info, User>>,
+}
+
+#[derive(Accounts)]
+pub struct CheckBalance<'info> {
+    #[account(
+        seeds = [b"user", user_account.owner.as_ref()],
+        bump
+    )]
+    pub user_account: Account<'info, User>,
+}
+
+#[derive(Accounts)]
+pub struct AdminClockOperation<'info> {
+    pub clock: Sysvar<'info, Clock>,
+
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"bank"],
+        bump,
+        constraint = bank_account.admin == admin.key() @ ErrorCode::Unauthorized,
+        constraint = !bank_account.upgrade_mode @ ErrorCode::UpgradeInProgress
+    )]
+    pub bank_account: Account<'info, Bank>,
+}
+
+#[derive(Accounts)]
+pub struct GrantApyBoost<'info> {
+    pub clock: Sysvar<'info, Clock>,
+
+    pub admin: Signer<'info>,
+
+    #[account(
+        seeds = [b"bank"],
+        bump,
+        constraint = bank_account.admin == admin.key() @ ErrorCode::Unauthorized,
+        constraint = !bank_account.upgrade_mode @ ErrorCode::UpgradeInProgress
+    )]
+    pub bank_account: Account<'info, Bank>,
+
+    #[account(
+        mut,
+        seeds = [b"user", user_account.owner.as_ref()],
+        bump
+    )]
+    pub user_account: Account<'info, User>,
+}
+
+#[derive(Accounts)]
+pub struct AdminUserOperation<'info> {
+    pub admin: Signer<'info>,
+
+    #[account(
+        seeds = [b"bank"],
+        bump,
+        constraint = bank_account.admin == admin.key() @ ErrorCode::Unauthorized,
+        constraint = !bank_account.upgrade_mode @ ErrorCode::UpgradeInProgress
+    )]
+    pub bank_account: Account<'info, Bank>,
+
+    #[account(
+        mut,
+        seeds = [b"user", user_account.owner.as_ref()],
+        bump
+    )]
+    pub user_account: Account<'info, User>,
+}
+
+#[derive(Accounts)]
+pub struct GrantLockedStake<'info> {
+    pub clock: Sysvar<'info, Clock>,
+
+    pub admin: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"bank"],
+        bump,
+        constraint = bank_account.admin == admin.key() @ ErrorCode::Unauthorized,
+        constraint = !bank_account.upgrade_mode @ ErrorCode::UpgradeInProgress
+    )]
+    pub bank_account: Account<'info, Bank>,
+
+    #[account(
+        mut,
+        seeds = [b"user", user_account.owner.as_ref()],
+        bump
+    )]
+    pub user_account: Account<'info, User>,
+
+    /// Required only when rewards are routed away from `user_account`
+    #[account(
+        mut,
+        seeds = [b"user", reward_recipient.owner.as_ref()],
+        bump,
+        constraint = reward_recipient.key() != user_account.key() @ ErrorCode::InvalidAddress
+    )]
+    pub reward_recipient: Option<Account<'info, User>>,
+}
+
+#[derive(Accounts)]
+pub struct AdminView<'info> {
+    pub clock: Sysvar<'info, Clock>,
+
+    pub admin: Signer<'info>,
+
+    #[account(
+        seeds = [b"bank"],
+        bump,
+        constraint = bank_account.admin == admin.key() @ ErrorCode::Unauthorized
+    )]
+    pub bank_account: Account<'info, Bank>,
+}
+
+#[derive(Accounts)]
+pub struct BankView<'info> {
+    #[account(
+        seeds = [b"bank"],
+        bump
+    )]
+    pub bank_account: Account<'info, Bank>,
+}
+
+#[derive(Accounts)]
+pub struct SettleRewards<'info> {
+    pub clock: Sysvar<'info, Clock>,
+
+    #[account(
+        mut,
+        seeds = [b"bank"],
+        bump,
+        constraint = !bank_account.upgrade_mode @ ErrorCode::UpgradeInProgress
+    )]
+    pub bank_account: Account<'info, Bank>,
+}
+
+#[derive(Accounts)]
+pub struct SweepToStake<'info> {
+    pub clock: Sysvar<'info, Clock>,
+
+    #[account(
+        mut,
+        seeds = [b"bank"],
+        bump,
+        constraint = !bank_account.upgrade_mode @ ErrorCode::UpgradeInProgress
+    )]
+    pub bank_account: Account<'info, Bank>,
+}
+
+#[derive(Accounts)]
+pub struct VerifyUser<'info> {
+    pub clock: Sysvar<'info, Clock>,
+
+    #[account(
+        seeds = [b"user", user_account.owner.as_ref()],
+        bump
+    )]
+    pub user_account: Account<'info, User>,
+}
+
+#[derive(Accounts)]
+pub struct UserSettings<'info> {
+    pub payer: Signer<'info>,
+
+    #[account(
+        seeds = [b"bank"],
+        bump,
+        constraint = !bank_account.upgrade_mode @ ErrorCode::UpgradeInProgress
+    )]
+    pub bank_account: Account<'info, Bank>,
+
+    #[account(
+        mut,
+        seeds = [b"user", payer.key().as_ref()],
+        bump,
+        constraint = user_account.owner == payer.key() @ ErrorCode::Unauthorized
+    )]
+    pub user_account: Account<'info, User>,
+}
+
+#[derive(Accounts)]
+pub struct Operations<'info> {
+    pub clock: Sysvar<'info, Clock>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"bank"],
+        bump,
+        constraint = !bank_account.upgrade_mode @ ErrorCode::UpgradeInProgress
+    )]
+    pub bank_account: Account<'info, Bank>,
+
+    #[account(
+        mut,
+        seeds = [b"user", payer.key().as_ref()],
+        bump,
+        constraint = user_account.owner == payer.key() @ ErrorCode::Unauthorized
+    )]
+    pub user_account: Account<'info, User>,
+
+    #[account(address = bank_account.mint @ ErrorCode::InvalidMint)]
+    pub mint: Account<'info, Mint>,
+
+    /// Deposit source, or withdrawal destination (which may belong to someone else)
+    #[account(
+        mut,
+        token::mint = mint
+    )]
+    pub user_token_account: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"vault"],
+        bump,
+        token::mint = mint,
+        token::authority = bank_account
+    )]
+    pub vault_token_account: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct DelegatedWithdraw<'info> {
+    pub clock: Sysvar<'info, Clock>,
+
+    pub delegate: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"bank"],
+        bump,
+        constraint = !bank_account.upgrade_mode @ ErrorCode::UpgradeInProgress
+    )]
+    pub bank_account: Account<'info, Bank>,
+
+    #[account(
+        mut,
+        seeds = [b"user", user_account.owner.as_ref()],
+        bump,
+        constraint = user_account.delegate == delegate.key() @ ErrorCode::Unauthorized
+    )]
+    pub user_account: Account<'info, User>,
+
+    #[account(address = bank_account.mint @ ErrorCode::InvalidMint)]
+    pub mint: Account<'info, Mint>,
+
+    /// Withdrawal destination chosen by the delegate, subject to the owner's whitelist
+    #[account(
+        mut,
+        token::mint = mint
+    )]
+    pub destination_token_account: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"vault"],
+        bump,
+        token::mint = mint,
+        token::authority = bank_account
+    )]
+    pub vault_token_account: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct LoanOperations<'info> {
+    pub clock: Sysvar<'info, Clock>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"bank"],
+        bump,
+        constraint = !bank_account.upgrade_mode @ ErrorCode::UpgradeInProgress
+    )]
+    pub bank_account: Account<'info, Bank>,
+
+    #[account(
+        mut,
+        seeds = [b"user", payer.key().as_ref()],
+        bump,
+        constraint = user_account.owner == payer.key() @ ErrorCode::Unauthorized
+    )]
+    pub user_account: Account<'info, User>,
+
+    #[account(
+        init,
+        space = 8 + Loan::INIT_SPACE,
+        payer = payer,
+        seeds = [b"loan", payer.key().as_ref(), &user_account.loan_count.to_le_bytes()],
+        bump
+    )]
+    pub loan_account: Account<'info, Loan>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(loan_index: u64)]
+pub struct RepayLoan<'info> {
+    pub clock: Sysvar<'info, Clock>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"bank"],
+        bump,
+        constraint = !bank_account.upgrade_mode @ ErrorCode::UpgradeInProgress
+    )]
+    pub bank_account: Account<'info, Bank>,
+
+    #[account(
+        mut,
+        seeds = [b"user", payer.key().as_ref()],
+        bump,
+        constraint = user_account.owner == payer.key() @ ErrorCode::Unauthorized
+    )]
+    pub user_account: Account<'info, User>,
+
+    #[account(
+        mut,
+        close = payer,
+        seeds = [b"loan", payer.key().as_ref(), &loan_index.to_le_bytes()],
+        bump,
+        constraint = loan_account.owner == payer.key() @ ErrorCode::Unauthorized
+    )]
+    pub loan_account: Account<'info, Loan>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RepayAllLoans<'info> {
+    pub clock: Sysvar<'info, Clock>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"bank"],
+        bump,
+        constraint = !bank_account.upgrade_mode @ ErrorCode::UpgradeInProgress
+    )]
+    pub bank_account: Account<'info, Bank>,
+
+    #[account(
+        mut,
+        seeds = [b"user", payer.key().as_ref()],
+        bump,
+        constraint = user_account.owner == payer.key() @ ErrorCode::Unauthorized
+    )]
+    pub user_account: Account<'info, User>,
+}
+
+#[derive(Accounts)]
+#[instruction(loan_index: u64)]
+pub struct Liquidation<'info> {
+    pub clock: Sysvar<'info, Clock>,
+
+    pub liquidator: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"bank"],
+        bump,
+        constraint = !bank_account.upgrade_mode @ ErrorCode::UpgradeInProgress
+    )]
+    pub bank_account: Account<'info, Bank>,
+
+    #[account(
+        mut,
+        seeds = [b"user", user_account.owner.as_ref()],
+        bump
+    )]
+    pub user_account: Account<'info, User>,
+
+    /// Closed to the borrower's user account once fully liquidated, which recovers the rent on `delete_user`
+    #[account(
+        mut,
+        seeds = [b"loan", user_account.owner.as_ref(), &loan_index.to_le_bytes()],
+        bump,
+        constraint = loan_account.owner == user_account.owner @ ErrorCode::Unauthorized
+    )]
+    pub loan_account: Account<'info, Loan>,
+
+    #[account(
+        mut,
+        seeds = [b"user", liquidator.key().as_ref()],
+        bump,
+        constraint = liquidator_account.key() != user_account.key() @ ErrorCode::InvalidAddress
+    )]
+    pub liquidator_account: Account<'info, User>,
+
+    /// Required only when the bonus exceeds `max_liquidator_bonus`
+    #[account(
+        mut,
+        seeds = [b"bucket", INSURANCE_BUCKET_NAME.as_bytes()],
+        bump
+    )]
+    pub insurance_bucket: Option<Account<'info, Bucket>>,
+}
+
+#[derive(Accounts)]
+#[instruction(loan_index: u64)]
+pub struct PartialRepay<'info> {
+    pub clock: Sysvar<'info, Clock>,
+
+    pub payer: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"bank"],
+        bump,
+        constraint = !bank_account.upgrade_mode @ ErrorCode::UpgradeInProgress
+    )]
+    pub bank_account: Account<'info, Bank>,
+
+    #[account(
+        mut,
+        seeds = [b"user", payer.key().as_ref()],
+        bump,
+        constraint = user_account.owner == payer.key() @ ErrorCode::Unauthorized
+    )]
+    pub user_account: Account<'info, User>,
+
+    #[account(
+        mut,
+        seeds = [b"loan", payer.key().as_ref(), &loan_index.to_le_bytes()],
+        bump,
+        constraint = loan_account.owner == payer.key() @ ErrorCode::Unauthorized
+    )]
+    pub loan_account: Account<'info, Loan>,
+}
+
+#[derive(Accounts)]
+pub struct Staking<'info> {
+    pub clock: Sysvar<'info, Clock>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"bank"],
+        bump,
+        constraint = !bank_account.upgrade_mode @ ErrorCode::UpgradeInProgress
+    )]
+    pub bank_account: Account<'info, Bank>,
+
+    #[account(
+        mut,
+        seeds = [b"user", payer.key().as_ref()],
+        bump,
+        constraint = user_account.owner == payer.key() @ ErrorCode::Unauthorized
+    )]
+    pub user_account: Account<'info, User>,
+
+    /// Required only when rewards are routed away from `user_account`
+    #[account(
+        mut,
+        seeds = [b"user", reward_recipient.owner.as_ref()],
+        bump,
+        constraint = reward_recipient.key() != user_account.key() @ ErrorCode::InvalidAddress
+    )]
+    pub reward_recipient: Option<Account<'info, User>>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct StopLoss<'info> {
+    pub clock: Sysvar<'info, Clock>,
+
+    /// The user themselves or any keeper
+    pub caller: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"bank"],
+        bump,
+        constraint = !bank_account.upgrade_mode @ ErrorCode::UpgradeInProgress
+    )]
+    pub bank_account: Account<'info, Bank>,
+
+    #[account(
+        mut,
+        seeds = [b"user", user_account.owner.as_ref()],
+        bump
+    )]
+    pub user_account: Account<'info, User>,
+
+    /// Required only when rewards are routed away from `user_account`
+    #[account(
+        mut,
+        seeds = [b"user", reward_recipient.owner.as_ref()],
+        bump,
+        constraint = reward_recipient.key() != user_account.key() @ ErrorCode::InvalidAddress
+    )]
+    pub reward_recipient: Option<Account<'info, User>>,
+}
+
+#[derive(Accounts)]
+pub struct SetRewardRecipient<'info> {
+    pub payer: Signer<'info>,
+
+    #[account(
+        seeds = [b"bank"],
+        bump,
+        constraint = !bank_account.upgrade_mode @ ErrorCode::UpgradeInProgress
+    )]
+    pub bank_account: Account<'info, Bank>,
+
+    #[account(
+        mut,
+        seeds = [b"user", payer.key().as_ref()],
+        bump,
+        constraint = user_account.owner == payer.key() @ ErrorCode::Unauthorized
+    )]
+    pub user_account: Account<'info, User>,
+
+    #[account(
+        seeds = [b"user", recipient_account.owner.as_ref()],
+        bump,
+    )]
+    pub recipient_account: Account<'info, User>,
+}
+
+#[derive(Accounts)]
+pub struct UserView<'info> {
+    pub clock: Sysvar<'info, Clock>,
+
+    #[account(
+        seeds = [b"bank"],
+        bump
+    )]
+    pub bank_account: Account<'info, Bank>,
+
+    #[account(
+        seeds = [b"user", user_account.owner.as_ref()],
+        bump
+    )]
+    pub user_account: Account<'info, User>,
+}
+
+#[derive(Accounts)]
+#[instruction(target: Pubkey)]
+pub struct PeekRewards<'info> {
+    pub clock: Sysvar<'info, Clock>,
+
+    #[account(
+        seeds = [b"bank"],
+        bump
+    )]
+    pub bank_account: Account<'info, Bank>,
+
+    #[account(
+        seeds = [b"user", target.as_ref()],
+        bump
+    )]
+    pub user_account: Account<'info, User>,
+}
+
+#[derive(Accounts)]
+pub struct FundTransfer<'info> {
+    pub clock: Sysvar<'info, Clock>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"bank"],
+        bump,
+        constraint = !bank_account.upgrade_mode @ ErrorCode::UpgradeInProgress
+    )]
+    pub bank_account: Account<'info, Bank>,
+
+    #[account(
+        mut,
+        seeds = [b"user", payer.key().as_ref()],
+        bump,
+        constraint = from_user.owner == payer.key() @ ErrorCode::Unauthorized
+    )]
+    pub from_user: Account<'info, User>,
+
+    #[account(
+        mut,
+        seeds = [b"user", to_user.owner.as_ref()],
+        bump,
+    )]
+    pub to_user: Account<'info, User>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct AdminOperation<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"bank"],
+        bump,
+        constraint = bank_account.admin == admin.key() @ ErrorCode::Unauthorized,
+        constraint = !bank_account.upgrade_mode @ ErrorCode::UpgradeInProgress
+    )]
+    pub bank_account: Account<'info, Bank>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct AddBankFunds<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"bank"],
+        bump,
+        constraint = bank_account.admin == admin.key() @ ErrorCode::Unauthorized,
+        constraint = !bank_account.upgrade_mode @ ErrorCode::UpgradeInProgress
+    )]
+    pub bank_account: Account<'info, Bank>,
+
+    #[account(address = bank_account.mint @ ErrorCode::InvalidMint)]
+    pub mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        token::mint = mint,
+        token::authority = admin
+    )]
+    pub admin_token_account: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"vault"],
+        bump,
+        token::mint = mint,
+        token::authority = bank_account
+    )]
+    pub vault_token_account: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct DepositCapFromSupply<'info> {
+    pub admin: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"bank"],
+        bump,
+        constraint = bank_account.admin == admin.key() @ ErrorCode::Unauthorized,
+        constraint = !bank_account.upgrade_mode @ ErrorCode::UpgradeInProgress
+    )]
+    pub bank_account: Account<'info, Bank>,
+
+    #[account(address = bank_account.mint @ ErrorCode::InvalidMint)]
+    pub mint: Account<'info, Mint>,
+}
+
+#[derive(Accounts)]
+pub struct PullRewardFunding<'info> {
+    pub clock: Sysvar<'info, Clock>,
+
+    pub caller: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"bank"],
+        bump,
+        constraint = !bank_account.upgrade_mode @ ErrorCode::UpgradeInProgress
+    )]
+    pub bank_account: Account<'info, Bank>,
+
+    #[account(address = bank_account.mint @ ErrorCode::InvalidMint)]
+    pub mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        address = bank_account.reward_funding_source @ ErrorCode::InvalidAddress,
+        token::mint = mint
+    )]
+    pub funding_source: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"vault"],
+        bump,
+        token::mint = mint,
+        token::authority = bank_account
+    )]
+    pub vault_token_account: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct AcceptAdmin<'info> {
+    pub new_admin: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"bank"],
+        bump,
+        constraint = bank_account.pending_admin == new_admin.key() @ ErrorCode::Unauthorized,
+        constraint = !bank_account.upgrade_mode @ ErrorCode::UpgradeInProgress
+    )]
+    pub bank_account: Account<'info, Bank>,
+}
+
+/// The only mutating context usable during upgrade mode
+#[derive(Accounts)]
+pub struct UpgradeModeOperation<'info> {
+    pub admin: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"bank"],
+        bump,
+        constraint = bank_account.admin == admin.key() @ ErrorCode::Unauthorized
+    )]
+    pub bank_account: Account<'info, Bank>,
+}
+
+#[derive(Accounts)]
+#[instruction(name: String)]
+pub struct CreateBucket<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    #[account(
+        seeds = [b"bank"],
+        bump,
+        constraint = bank_account.admin == admin.key() @ ErrorCode::Unauthorized,
+        constraint = !bank_account.upgrade_mode @ ErrorCode::UpgradeInProgress
+    )]
+    pub bank_account: Account<'info, Bank>,
+
+    #[account(
+        init,
+        space = 8 + Bucket::INIT_SPACE,
+        payer = admin,
+        seeds = [b"bucket", name.as_bytes()],
+        bump
+    )]
+    pub bucket_account: Account<'info, Bucket>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct BucketOperation<'info> {
+    pub admin: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"bank"],
+        bump,
+        constraint = bank_account.admin == admin.key() @ ErrorCode::Unauthorized,
+        constraint = !bank_account.upgrade_mode @ ErrorCode::UpgradeInProgress
+    )]
+    pub bank_account: Account<'info, Bank>,
+
+    #[account(
+        mut,
+        seeds = [b"bucket", bucket_account.name.as_bytes()],
+        bump
+    )]
+    pub bucket_account: Account<'info, Bucket>,
+}
+
+#[derive(Accounts)]
+pub struct SpendFromBucket<'info> {
+    pub admin: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"bank"],
+        bump,
+        constraint = bank_account.admin == admin.key() @ ErrorCode::Unauthorized,
+        constraint = !bank_account.upgrade_mode @ ErrorCode::UpgradeInProgress
+    )]
+    pub bank_account: Account<'info, Bank>,
+
+    #[account(
+        mut,
+        seeds = [b"bucket", bucket_account.name.as_bytes()],
+        bump
+    )]
+    pub bucket_account: Account<'info, Bucket>,
+
+    #[account(address = bank_account.mint @ ErrorCode::InvalidMint)]
+    pub mint: Account<'info, Mint>,
+
+    #[account(
+        mut,
+        token::mint = mint
+    )]
+    pub destination_token_account: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"vault"],
+        bump,
+        token::mint = mint,
+        token::authority = bank_account
+    )]
+    pub vault_token_account: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+// Data structures
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OpKind {
+    Deposit,
+    Withdraw,
+    Stake,
+    Unstake,
+    Borrow,
+    Repay,
+    Transfer,
+    Liquidate,
+}
+
+impl OpKind {
+    pub const ALL: u8 = 0b1111_1111;
+
+    pub fn mask(self) -> u8 {
+        1 << (self as u8)
+    }
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct Bank {
+    pub admin: Pubkey,
+    pub balance: u64,
+    pub staked_balance: u64,
+    pub lent_balance: u64,
+    pub total_users: u64,
+    /// `OpKind` bitmask of paused operations. Occupies the byte of the former
+    /// `is_operational` flag, so migrated banks must reset it with `set_pause`.
+    pub paused_operations: u8,
+    pub reward_per_slot: u64,
+    pub acc_reward_per_share: u128,
+    pub last_reward_slot: u64,
+    /// Deposits less withdrawals; staked funds stay counted here
+    pub total_deposits: u64,
+    pub max_tvl: u64,
+    pub early_repay_rebate_bps: u64,
+    pub early_repay_window_seconds: i64,
+    pub reject_unrewarded_stakes: bool,
+    /// Admin-contributed equity within `balance`, only reduced by admin withdrawals
+    pub protocol_owned_balance: u64,
+    pub min_health_factor_bps: u64,
+    pub intro_rate_bps: u64,
+    pub open_loans: u64,
+    pub max_open_loans: u64,
+    pub decimals: u8,
+    pub rate_proposal_active: bool,
+    pub collateral_ratio: u64,
+    pub last_ratio_change_slot: u64,
+    pub ratio_change_cooldown_slots: u64,
+    pub report_deposits: u64,
+    pub report_withdrawals: u64,
+    pub report_interest_collected: u64,
+    pub report_rewards_paid: u64,
+    pub report_new_users: u64,
+    pub report_closed_users: u64,
+    pub last_report_slot: u64,
+    /// SPL mint this bank custodies. Banks created before this field existed must be
+    /// reallocated to the new `Bank::INIT_SPACE` and have it set before token instructions work.
+    pub mint: Pubkey,
+    pub min_residual_balance: u64,
+    pub interest_free_loan_threshold: u64,
+    /// Portion of `balance` earmarked across all buckets
+    pub bucketed_balance: u64,
+    pub staking_apy_bps: u64,
+    /// Cumulative APY bps-slots up to `apy_index_slot`
+    pub apy_index: u128,
+    pub apy_index_slot: u64,
+    /// Blocks every mutating instruction except `set_upgrade_mode` while a migration runs
+    pub upgrade_mode: bool,
+    /// Proposed admin awaiting `accept_admin`; default when no transfer is pending
+    pub pending_admin: Pubkey,
+    /// Pay unclaimed rewards out on `delete_user` instead of rejecting the deletion
+    pub pay_rewards_on_delete: bool,
+    /// Lending utilization at which the dynamic APY bottoms out; zero disables it
+    pub target_utilization_bps: u64,
+    pub min_staking_apy_bps: u64,
+    pub max_staking_apy_bps: u64,
+    /// Largest single deposit as bps of `balance + total_deposits`; zero disables the cap
+    pub max_deposit_fraction_bps: u64,
+    /// Cap on withdrawals, borrows and reward payouts summed within one instruction; zero disables it
+    pub max_tx_outflow: u64,
+    /// Paid from `balance` on a user's first stake; zero disables it
+    pub welcome_bonus: u64,
+    /// Lifetime counters; unlike the `report_*` fields these are never reset
+    pub total_interest_collected: u64,
+    pub total_rewards_paid: u64,
+    /// Sum of principal * rate_bps over all open loans
+    pub loan_rate_weight: u128,
+    /// Open new loans with interest compounded daily instead of simple interest
+    pub compound_interest: bool,
+    /// Account limit for `create_user`; zero means unlimited
+    pub max_users: u64,
+    /// Share of a loan's debt one `liquidate` call may repay; zero liquidates it in full
+    pub close_factor_bps: u64,
+    /// Largest bonus one liquidation pays the liquidator; the excess goes to the insurance bucket. Zero means uncapped
+    pub max_liquidator_bonus: u64,
+    /// Extra APY reward, in bps of the reward, for stakers who have never been liquidated
+    pub clean_record_bonus_bps: u64,
+    /// Smallest pending reward `claim_rewards` pays out; unstaking still pays any amount
+    pub min_claimable_reward: u64,
+    /// Token account `pull_reward_funding` draws from, with the bank approved as its delegate
+    pub reward_funding_source: Pubkey,
+    /// Most `pull_reward_funding` may draw per `REWARD_FUNDING_PERIOD_SECONDS`
+    pub reward_funding_limit: u64,
+    pub reward_funding_window_start: i64,
+    pub reward_funded_in_window: u64,
+    /// Largest single deposit, derived from the mint supply; zero leaves only `MAX_DEPOSIT_AMOUNT`
+    pub max_deposit: u64,
+    /// Share of the mint supply `set_deposit_cap_from_supply` last derived `max_deposit` from
+    pub deposit_cap_supply_bps: u64,
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct User {
+    pub owner: Pubkey,
+    pub balance: u64,
+    pub staked_balance: u64,
+    pub stake_slot: u64,
+    pub lent_balance: u64,
+    pub loan_timestamp: i64,
+    pub emit_balance_events: bool,
+    pub reward_debt: u128,
+    pub reward_recipient: Pubkey,
+    pub delegate: Pubkey,
+    pub delegate_allowance: u64,
+    pub delegate_period_slots: u64,
+    pub delegate_period_start_slot: u64,
+    pub delegate_spent: u64,
+    pub is_frozen: bool,
+    pub boost_bps: u64,
+    pub boost_start_slot: u64,
+    pub boost_until_slot: u64,
+    pub granted_stake: u64,
+    pub grant_unlock_slot: u64,
+    pub has_borrowed_before: bool,
+    pub loan_rate_bps: u64,
+    pub vote_credits: u64,
+    pub auto_repay: bool,
+    pub stop_loss_enabled: bool,
+    pub stop_loss_solvency_bps: u64,
+    /// Integral of staked balance times the APY in force (and the lock tier while locked),
+    /// over slots since the last reward settlement
+    pub accumulated_apy_weight: u128,
+    /// Integral of staked balance times boost bps over boosted slots since the last settlement
+    pub accumulated_boost_weight: u128,
+    pub last_update_slot: u64,
+    #[max_len(MAX_WITHDRAW_WHITELIST)]
+    pub withdraw_whitelist: Vec<Pubkey>,
+    /// Slot the current position was opened from zero; top-ups do not move it
+    pub stake_start_slot: u64,
+    pub lifetime_interest_paid: u64,
+    /// Loans ever opened; the next loan PDA uses this as its index
+    pub loan_count: u64,
+    /// Sum of accrual base * rate_bps over open loans
+    pub loan_rate_weight: u128,
+    /// Sum of accrual base * rate_bps * start_timestamp over open loans
+    pub loan_rate_time_weight: u128,
+    /// Collateral backing open loans; excluded from withdraw, stake and transfer
+    pub locked_balance: u64,
+    /// Reward settled by a keeper but not yet paid out
+    pub pending_reward: u64,
+    /// Bank APY index at `last_update_slot`
+    pub apy_index_checkpoint: u128,
+    pub auto_stake_enabled: bool,
+    /// Spendable balance kept liquid when a keeper sweeps the rest into stake
+    pub auto_stake_threshold: u64,
+    /// Unstaking is rejected before this slot
+    pub lock_end_slot: u64,
+    /// APY multiplier from the lock tier; zero on accounts created before tiers reads as 1x
+    pub lock_multiplier_bps: u64,
+    /// Set on the first stake so the welcome bonus is paid once
+    pub has_staked_before: bool,
+    /// Admin cap on total outstanding principal; zero leaves only the collateral limit
+    pub max_loan_override: u64,
+    /// Most unclaimed reward this user can accrue; zero leaves it unbounded
+    pub reward_cap: u64,
+    /// Sum of `carried_interest` over open loans
+    pub loan_carried_interest: u64,
+    /// Accrual base (principal plus carried interest) of open compounded loans
+    pub compound_loan_principal: u64,
+    /// The compounded loans' share of `loan_rate_weight` and `loan_rate_time_weight`
+    pub compound_rate_weight: u128,
+    pub compound_rate_time_weight: u128,
+    /// Liquidations suffered; any liquidation forfeits the clean-record bonus
+    pub liquidation_count: u64,
+    /// Staked tokens pledged to open loans, which cannot be unstaked until repaid
+    pub locked_staked_balance: u64,
+}
+
+/// A single loan; `User::lent_balance` holds the running sum of open principals
+#[account]
+#[derive(InitSpace)]
+pub struct Loan {
+    pub owner: Pubkey,
+    pub loan_index: u64,
+    pub principal: u64,
+    pub start_timestamp: i64,
+    pub interest_rate_snapshot: u64,
+    pub locked_collateral: u64,
+    /// Interest model fixed when the loan was opened
+    pub compound_interest: bool,
+    /// Interest left unpaid by partial repayments, owed on top of accrual since `start_timestamp`
+    pub carried_interest: u64,
+    /// Staked tokens pledged to this loan, counted in the owner's `locked_staked_balance`
+    pub locked_stake: u64,
+}
+
+/// A named earmark within the bank balance
+#[account]
+#[derive(InitSpace)]
+pub struct Bucket {
+    #[max_len(MAX_BUCKET_NAME_LEN)]
+    pub name: String,
+    pub balance: u64,
+}
+
+// Events
+#[event]
+pub struct BalanceChecked {
+    pub schema_version: u8,
+    pub user: Pubkey,
+    pub balance: u64,
+    pub staked_balance: u64,
+    pub lent_balance: u64,
+}
+
+#[event]
+pub struct DepositEvent {
+    pub schema_version: u8,
+    pub user: Pubkey,
+    pub amount: u64,
+    pub ui_amount: String,
+    pub new_balance: u64,
+}
+
+#[event]
+pub struct WithdrawEvent {
+    pub schema_version: u8,
+    pub user: Pubkey,
+    pub amount: u64,
+    pub ui_amount: String,
+    pub new_balance: u64,
+}
+
+#[event]
+pub struct StakeEvent {
+    pub schema_version: u8,
+    pub user: Pubkey,
+    pub amount: u64,
+    pub ui_amount: String,
+    pub total_staked: u64,
+    /// Index into `LOCK_TIERS`
+    pub lock_tier: u8,
+    pub lock_end_slot: u64,
+}
+
+#[event]
+pub struct UnstakeEvent {
+    pub schema_version: u8,
+    pub user: Pubkey,
+    pub amount: u64,
+    pub ui_amount: String,
+    pub reward: u64,
+    pub remaining_staked: u64,
+}
+
+#[event]
+pub struct BorrowEvent {
+    pub schema_version: u8,
+    pub user: Pubkey,
+    pub loan_index: u64,
+    pub amount: u64,
+    pub collateral_used: u64,
+    pub collateral_buffer_bps: u64,
+}
+
+#[event]
+pub struct RepayEvent {
+    pub schema_version: u8,
+    pub user: Pubkey,
+    pub loan_index: u64,
+    pub principal: u64,
+    pub interest: u64,
+    pub total_repayment: u64,
+}
+
+#[event]
+pub struct TransferEvent {
+    pub schema_version: u8,
+    pub from: Pubkey,
+    pub to: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct BankStatusChanged {
+    pub schema_version: u8,
+    pub admin: Pubkey,
+    pub is_operational: bool,
+}
+
+#[event]
+pub struct BankFundsAdded {
+    pub schema_version: u8,
+    pub admin: Pubkey,
+    pub amount: u64,
+    pub new_balance: u64,
+}
+
+#[event]
+pub struct RewardFundingPulled {
+    pub schema_version: u8,
+    pub caller: Pubkey,
+    pub source: Pubkey,
+    pub amount: u64,
+    pub new_balance: u64,
+}
+
+#[event]
+pub struct RewardLiability {
+    pub schema_version: u8,
+    pub staked_balance: u64,
+    pub annual_liability: u64,
+    pub available_balance: u64,
+    pub runway_slots: u64,
+}
+
+#[event]
+pub struct RewardEmissionChanged {
+    pub schema_version: u8,
+    pub admin: Pubkey,
+    pub reward_per_slot: u64,
+}
+
+#[event]
+pub struct UserVerification {
+    pub schema_version: u8,
+    pub user: Pubkey,
+    pub is_consistent: bool,
+    pub stake_slot_in_future: bool,
+    pub stake_slot_unset: bool,
+    pub loan_timestamp_mismatch: bool,
+    pub collateral_shortfall: bool,
+}
+
+#[event]
+pub struct BankStateExport {
+    pub schema_version: u8,
+    pub slot: u64,
+    pub bank: Bank,
+}
+
+#[event]
+pub struct DelegatedWithdrawEvent {
+    pub schema_version: u8,
+    pub user: Pubkey,
+    pub delegate: Pubkey,
+    pub amount: u64,
+    pub remaining_allowance: u64,
+}
+
+#[event]
+pub struct RealizedApy {
+    pub schema_version: u8,
+    pub user: Pubkey,
+    pub staked_balance: u64,
+    pub accrued_reward: u64,
+    pub slots_elapsed: u64,
+    pub realized_apy_bps: u64,
+}
+
+#[event]
+pub struct AccountsFrozenChanged {
+    pub schema_version: u8,
+    pub admin: Pubkey,
+    pub is_frozen: bool,
+    pub accounts_affected: u64,
+}
+
+#[event]
+pub struct PortfolioHealth {
+    pub schema_version: u8,
+    /// Users with outstanding loans, however many loans each holds
+    pub borrowers: u64,
+    pub total_debt: u64,
+    pub total_collateral: u64,
+    pub collateralization_bps: u64,
+}
+
+#[event]
+pub struct ApyBoostGranted {
+    pub schema_version: u8,
+    pub user: Pubkey,
+    pub boost_bps: u64,
+    pub boost_until_slot: u64,
+}
+
+#[event]
+pub struct LockedStakeGranted {
+    pub schema_version: u8,
+    pub user: Pubkey,
+    pub amount: u64,
+    pub unlock_slot: u64,
+}
+
+#[event]
+pub struct RewardsRedeemedForVotes {
+    pub schema_version: u8,
+    pub user: Pubkey,
+    pub reward_redeemed: u64,
+    pub reward_paid: u64,
+    pub vote_credits: u64,
+}
+
+#[event]
+pub struct Withdrawable {
+    pub schema_version: u8,
+    pub user: Pubkey,
+    pub balance: u64,
+    pub withdrawable: u64,
+}
+
+#[event]
+pub struct PendingRewardPeek {
+    pub schema_version: u8,
+    pub user: Pubkey,
+    pub staked_balance: u64,
+    pub pending_reward: u64,
+    pub slot: u64,
+}
+
+#[event]
+pub struct StopLossExecuted {
+    pub schema_version: u8,
+    pub user: Pubkey,
+    pub caller: Pubkey,
+    pub amount: u64,
+    pub reward: u64,
+    pub solvency_bps: u64,
+}
+
+#[event]
+pub struct LoanBreakEven {
+    pub schema_version: u8,
+    pub user: Pubkey,
+    pub principal: u64,
+    pub max_interest_budget: u64,
+    pub seconds: u64,
+    pub break_even_timestamp: i64,
+}
+
+#[event]
+pub struct RewardRunway {
+    pub schema_version: u8,
+    pub staked_balance: u64,
+    pub available_balance: u64,
+    pub annual_liability: u64,
+    pub runway_seconds: u64,
+    pub is_infinite: bool,
+}
+
+#[event]
+pub struct CollateralRatioChanged {
+    pub schema_version: u8,
+    pub admin: Pubkey,
+    pub old_ratio: u64,
+    pub new_ratio: u64,
+}
+
+#[event]
+pub struct MaxBorrowForHealth {
+    pub schema_version: u8,
+    pub user: Pubkey,
+    pub target_health_bps: u64,
+    pub borrow_amount: u64,
+    pub max_borrow: u64,
+}
+
+#[event]
+pub struct DailyReport {
+    pub schema_version: u8,
+    pub from_slot: u64,
+    pub to_slot: u64,
+    pub deposits: u64,
+    pub withdrawals: u64,
+    pub interest_collected: u64,
+    pub rewards_paid: u64,
+    pub new_users: u64,
+    pub closed_users: u64,
+}
+
+#[event]
+pub struct ClaimRewardEvent {
+    pub schema_version: u8,
+    pub user: Pubkey,
+    pub reward: u64,
+    pub staked_balance: u64,
+}
+
+#[event]
+pub struct InterestHistory {
+    pub schema_version: u8,
+    pub user: Pubkey,
+    pub lifetime_interest_paid: u64,
+}
+
+#[event]
+pub struct PartialRepayEvent {
+    pub schema_version: u8,
+    pub user: Pubkey,
+    pub loan_index: u64,
+    pub amount: u64,
+    pub interest_paid: u64,
+    pub remaining_principal: u64,
+}
+
+#[event]
+pub struct LiquidationEvent {
+    pub schema_version: u8,
+    pub user: Pubkey,
+    pub liquidator: Pubkey,
+    pub loan_index: u64,
+    pub seized: u64,
+    pub bonus: u64,
+    /// Bonus above `max_liquidator_bonus`, earmarked in the insurance bucket
+    pub insured: u64,
+    /// Zero once the loan is closed
+    pub remaining_principal: u64,
+}
+
+#[event]
+pub struct RewardsSettled {
+    pub schema_version: u8,
+    pub slot: u64,
+    pub accounts_processed: u64,
+    pub total_settled: u64,
+}
+
+#[event]
+pub struct ApyChanged {
+    pub schema_version: u8,
+    pub caller: Pubkey,
+    pub old_bps: u64,
+    pub new_bps: u64,
+}
+
+#[event]
+pub struct AdminTransferProposed {
+    pub schema_version: u8,
+    pub admin: Pubkey,
+    pub pending_admin: Pubkey,
+}
+
+#[event]
+pub struct AdminTransferAccepted {
+    pub schema_version: u8,
+    pub previous_admin: Pubkey,
+    pub new_admin: Pubkey,
+}
+
+#[event]
+pub struct LoanQuoteEvent {
+    pub schema_version: u8,
+    pub user: Pubkey,
+    pub principal: u64,
+    pub accrued_interest: u64,
+    pub total_owed: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct PendingRewardEvent {
+    pub schema_version: u8,
+    pub user: Pubkey,
+    pub staked_balance: u64,
+    pub pending_reward: u64,
+    pub slots_elapsed: u64,
+}
+
+#[event]
+pub struct NetRewardQuote {
+    pub schema_version: u8,
+    pub user: Pubkey,
+    pub amount: u64,
+    pub net_reward: u64,
+}
+
+#[event]
+pub struct AutoStakeSwept {
+    pub schema_version: u8,
+    pub slot: u64,
+    pub accounts_processed: u64,
+    pub users_swept: u64,
+    pub total_staked: u64,
+}
+
+#[event]
+pub struct ForceUnstakeEvent {
+    pub schema_version: u8,
+    pub user: Pubkey,
+    pub amount: u64,
+    pub penalty: u64,
+    pub forfeited_reward: u64,
+}
+
+#[event]
+pub struct WelcomeBonusPaid {
+    pub schema_version: u8,
+    pub user: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct BankStatsEvent {
+    pub schema_version: u8,
+    pub balance: u64,
+    pub staked_balance: u64,
+    pub lent_balance: u64,
+    pub total_users: u64,
+    /// Net deposits, staked or not, as counted against `max_tvl`
+    pub total_value_locked: u64,
+    pub utilization_bps: u64,
+}
+
+#[event]
+pub struct NetInterestMargin {
+    pub schema_version: u8,
+    pub total_interest_collected: u64,
+    pub total_rewards_paid: u64,
+    pub cumulative_margin: i64,
+    pub annual_interest: u64,
+    pub annual_rewards: u64,
+    pub annualized_margin: i64,
+}
+
+#[event]
+pub struct UserCapReached {
+    pub schema_version: u8,
+    pub total_users: u64,
+    pub max_users: u64,
+}
+
+#[event]
+pub struct LiquidationEligibility {
+    pub schema_version: u8,
+    pub user: Pubkey,
+    pub liquidatable: bool,
+    /// `u64::MAX` without an open loan
+    pub health_factor: u64,
+}
+
+#[event]
+pub struct BorrowingPower {
+    pub schema_version: u8,
+    pub user: Pubkey,
+    pub liquid_power: u64,
+    pub staked_power: u64,
+    /// Before outstanding loans; `available` is what can still be borrowed
+    pub total_power: u64,
+    pub available: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anchor_lang::solana_program::entrypoint::{ProgramResult, MAX_PERMITTED_DATA_INCREASE};
+    use anchor_lang::solana_program::instruction::Instruction;
+    use anchor_lang::solana_program::program_pack::Pack;
+    use anchor_lang::solana_program::program_stubs::{self, SyscallStubs};
+    use anchor_lang::solana_program::sysvar::SysvarId;
+    use anchor_lang::{system_program, Event, InstructionData};
+    use anchor_spl::token::spl_token;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+
+    /// An all-zero account of type `T`, as `init` leaves it before any field is set
+    fn zeroed<T: AccountDeserialize + Space>() -> T {
+        let data = vec![0u8; 8 + T::INIT_SPACE];
+        T::try_deserialize_unchecked(&mut &data[..]).unwrap()
+    }
+
+    /// Wrap `value` in a program-owned `Account`, leaked so it lives for the whole test
+    fn program_account<T>(value: &T) -> Account<'static, T>
+    where
+        T: AccountSerialize + AccountDeserialize + Owner + Clone + Space,
+    {
+        let mut data = Vec::with_capacity(8 + T::INIT_SPACE);
+        value.try_serialize(&mut data).unwrap();
+        data.resize(8 + T::INIT_SPACE, 0);
+        let info = AccountInfo::new(
+            Box::leak(Box::new(Pubkey::new_unique())),
+            false,
+            true,
+            Box::leak(Box::new(1_000_000_000u64)),
+            Box::leak(data.into_boxed_slice()),
+            Box::leak(Box::new(crate::ID)),
+            false,
+            0,
+        );
+        Account::try_from(Box::leak(Box::new(info))).unwrap()
+    }
+
+    fn loan(principal: u64, rate_bps: u64, compound_interest: bool) -> Loan {
+        Loan {
+            owner: Pubkey::default(),
+            loan_index: 0,
+            principal,
+            start_timestamp: 0,
+            interest_rate_snapshot: rate_bps,
+            locked_collateral: 0,
+            compound_interest,
+            carried_interest: 0,
+            locked_stake: 0,
+        }
+    }
+
+    /// Token units per whole token at the bank's 9 decimals
+    const TOKEN: u64 = 1_000_000_000;
+    /// Unix time at slot 0 of every test bank
+    const GENESIS_TIMESTAMP: i64 = 1_700_000_000;
+
+    thread_local! {
+        /// Event payloads logged by the last instruction run on this thread
+        static EVENTS: RefCell<Vec<Vec<u8>>> = const { RefCell::new(Vec::new()) };
+    }
+
+    /// Syscalls for running the program in-process: the system and token instructions it
+    /// invokes are applied straight to the passed accounts, and emitted events are captured
+    struct Runtime;
+
+    impl SyscallStubs for Runtime {
+        fn sol_log(&self, _message: &str) {}
+
+        fn sol_log_data(&self, fields: &[&[u8]]) {
+            EVENTS.with(|events| events.borrow_mut().extend(fields.iter().map(|field| field.to_vec())));
+        }
+
+        fn sol_get_rent_sysvar(&self, var_addr: *mut u8) -> u64 {
+            unsafe { (var_addr as *mut Rent).write_unaligned(Rent::default()) };
+            0
+        }
+
+        fn sol_invoke_signed(
+            &self,
+            instruction: &Instruction,
+            account_infos: &[AccountInfo],
+            signers_seeds: &[&[&[u8]]],
+        ) -> ProgramResult {
+            let account = |index: usize| {
+                let key = instruction.accounts.get(index).ok_or(ProgramError::NotEnoughAccountKeys)?.pubkey;
+                account_infos
+                    .iter()
+                    .find(|info| *info.key == key)
+                    .ok_or(ProgramError::NotEnoughAccountKeys)
+            };
+            let signed = |info: &AccountInfo| {
+                info.is_signer
+                    || signers_seeds
+                        .iter()
+                        .any(|seeds| Pubkey::create_program_address(seeds, &crate::ID) == Ok(*info.key))
+            };
+            let data = &instruction.data;
+
+            if instruction.program_id == system_program::ID {
+                let (from, to) = (account(0)?, account(1)?);
+                let lamports = u64::from_le_bytes(data[4..12].try_into().unwrap());
+                if !signed(from) {
+                    return Err(ProgramError::MissingRequiredSignature);
+                }
+                let remaining = from.lamports().checked_sub(lamports).ok_or(ProgramError::InsufficientFunds)?;
+                **from.try_borrow_mut_lamports()? = remaining;
+                let credited = to.lamports() + lamports;
+                **to.try_borrow_mut_lamports()? = credited;
+                match u32::from_le_bytes(data[..4].try_into().unwrap()) {
+                    // CreateAccount { lamports, space, owner }
+                    0 => {
+                        if !signed(to) {
+                            return Err(ProgramError::MissingRequiredSignature);
+                        }
+                        let space = u64::from_le_bytes(data[12..20].try_into().unwrap());
+                        to.realloc(space as usize, true)?;
+                        to.assign(&Pubkey::try_from(&data[20..52]).unwrap());
+                    }
+                    // Transfer { lamports }
+                    2 => {}
+                    _ => return Err(ProgramError::InvalidInstructionData),
+                }
+                return Ok(());
+            }
+
+            assert_eq!(instruction.program_id, token::ID);
+            match spl_token::instruction::TokenInstruction::unpack(data)? {
+                spl_token::instruction::TokenInstruction::InitializeAccount3 { owner } => {
+                    let state = spl_token::state::Account {
+                        mint: *account(1)?.key,
+                        owner,
+                        state: spl_token::state::AccountState::Initialized,
+                        ..Default::default()
+                    };
+                    spl_token::state::Account::pack(state, &mut account(0)?.try_borrow_mut_data()?)?;
+                }
+                spl_token::instruction::TokenInstruction::Transfer { amount } => {
+                    let (source, destination, authority) = (account(0)?, account(1)?, account(2)?);
+                    let mut from = spl_token::state::Account::unpack(&source.try_borrow_data()?)?;
+                    if !signed(authority) {
+                        return Err(ProgramError::MissingRequiredSignature);
+                    }
+                    if from.delegate == Some(*authority.key).into() {
+                        from.delegated_amount = from.delegated_amount.checked_sub(amount).ok_or(ProgramError::InsufficientFunds)?;
+                    } else if from.owner != *authority.key {
+                        return Err(ProgramError::MissingRequiredSignature);
+                    }
+                    from.amount = from.amount.checked_sub(amount).ok_or(ProgramError::InsufficientFunds)?;
+                    spl_token::state::Account::pack(from, &mut source.try_borrow_mut_data()?)?;
+                    let mut to = spl_token::state::Account::unpack(&destination.try_borrow_data()?)?;
+                    if to.mint != from.mint {
+                        return Err(ProgramError::InvalidAccountData);
+                    }
+                    to.amount += amount;
+                    spl_token::state::Account::pack(to, &mut destination.try_borrow_mut_data()?)?;
+                }
+                _ => return Err(ProgramError::InvalidInstructionData),
+            }
+            Ok(())
+        }
+    }
+
+    /// Leak an account laid out like the runtime's serialized input, with the original data
+    /// length just before the key and the current length just before the data, which is where
+    /// `AccountInfo::realloc` (behind `init` and `close`) reads and writes them
+    fn leak_account(key: Pubkey, owner: Pubkey, lamports: u64, data: &[u8], executable: bool) -> AccountInfo<'static> {
+        let key_slot: &'static mut [u32; 9] = Box::leak(Box::new([0; 9]));
+        key_slot[0] = data.len() as u32;
+        let key = unsafe {
+            let key_ptr = key_slot.as_mut_ptr().add(1) as *mut Pubkey;
+            key_ptr.write(key);
+            &*key_ptr
+        };
+        let words = 2 + (data.len() + MAX_PERMITTED_DATA_INCREASE) / 8;
+        let buffer: &'static mut [u64] = Box::leak(vec![0; words].into_boxed_slice());
+        buffer[0] = data.len() as u64;
+        let data = unsafe {
+            let data_ptr = buffer.as_mut_ptr().add(1) as *mut u8;
+            std::ptr::copy_nonoverlapping(data.as_ptr(), data_ptr, data.len());
+            std::slice::from_raw_parts_mut(data_ptr, data.len())
+        };
+        AccountInfo::new(
+            key,
+            false,
+            false,
+            Box::leak(Box::new(lamports)),
+            data,
+            Box::leak(Box::new(owner)),
+            executable,
+            0,
+        )
+    }
+
+    fn user_address(owner: &Pubkey) -> Pubkey {
+        Pubkey::find_program_address(&[b"user", owner.as_ref()], &crate::ID).0
+    }
+
+    fn loan_address(owner: &Pubkey, loan_index: u64) -> Pubkey {
+        Pubkey::find_program_address(&[b"loan", owner.as_ref(), &loan_index.to_le_bytes()], &crate::ID).0
+    }
+
+    fn bucket_address(name: &str) -> Pubkey {
+        Pubkey::find_program_address(&[b"bucket", name.as_bytes()], &crate::ID).0
+    }
+
+    /// A signer with its own token account and user account
+    struct Wallet {
+        owner: Pubkey,
+        tokens: Pubkey,
+        user: Pubkey,
+    }
+
+    /// An initialized bank and vault, driven through the program entrypoint one instruction at a time
+    struct TestBank {
+        accounts: HashMap<Pubkey, AccountInfo<'static>>,
+        slot: u64,
+        admin: Wallet,
+        mint: Pubkey,
+        bank: Pubkey,
+        vault: Pubkey,
+    }
+
+    impl TestBank {
+        fn new() -> Self {
+            Self::with_decimals(9)
+        }
+
+        fn with_decimals(decimals: u8) -> Self {
+            static STUBS: std::sync::Once = std::sync::Once::new();
+            STUBS.call_once(|| {
+                program_stubs::set_syscall_stubs(Box::new(Runtime));
+            });
+
+            let mut test_bank = TestBank {
+                accounts: HashMap::new(),
+                slot: 0,
+                admin: Wallet {
+                    owner: Pubkey::new_unique(),
+                    tokens: Pubkey::new_unique(),
+                    user: Pubkey::default(),
+                },
+                mint: Pubkey::new_unique(),
+                bank: Pubkey::find_program_address(&[b"bank"], &crate::ID).0,
+                vault: Pubkey::find_program_address(&[b"vault"], &crate::ID).0,
+            };
+            for program in [crate::ID, system_program::ID, token::ID] {
+                test_bank.add_account(leak_account(program, Pubkey::default(), 1, &[], true));
+            }
+            let clock = leak_account(Clock::id(), Pubkey::default(), 1, &[0; 40], false);
+            test_bank.add_account(clock);
+            // Slot 0 reads as unset in the stake bookkeeping
+            test_bank.warp(1);
+
+            let mut mint = [0; spl_token::state::Mint::LEN];
+            let state = spl_token::state::Mint {
+                decimals,
+                is_initialized: true,
+                ..Default::default()
+            };
+            spl_token::state::Mint::pack(state, &mut mint).unwrap();
+            test_bank.add_account(leak_account(test_bank.mint, token::ID, 1, &mint, false));
+            test_bank.add_system_account(test_bank.admin.owner);
+            test_bank.add_token_account(test_bank.admin.tokens, test_bank.admin.owner, 1_000_000 * TOKEN);
+
+            test_bank
+                .run(
+                    crate::accounts::InitializeBank {
+                        admin: test_bank.admin.owner,
+                        bank_account: test_bank.bank,
+                        mint: test_bank.mint,
+                        system_program: system_program::ID,
+                    },
+                    crate::instruction::InitializeBank { max_users: 0 },
+                )
+                .unwrap();
+            test_bank
+                .run(
+                    crate::accounts::InitializeVault {
+                        admin: test_bank.admin.owner,
+                        bank_account: test_bank.bank,
+                        mint: test_bank.mint,
+                        vault_token_account: test_bank.vault,
+                        token_program: token::ID,
+                        system_program: system_program::ID,
+                    },
+                    crate::instruction::InitializeVault {},
+                )
+                .unwrap();
+            test_bank
+        }
+
+        fn add_account(&mut self, info: AccountInfo<'static>) {
+            self.accounts.insert(*info.key, info);
+        }
+
+        fn add_system_account(&mut self, key: Pubkey) {
+            self.add_account(leak_account(key, system_program::ID, 100 * TOKEN, &[], false));
+        }
+
+        /// Mint `amount` into a new token account owned by `owner`
+        fn add_token_account(&mut self, key: Pubkey, owner: Pubkey, amount: u64) {
+            let mut data = [0; spl_token::state::Account::LEN];
+            let state = spl_token::state::Account {
+                mint: self.mint,
+                owner,
+                amount,
+                state: spl_token::state::AccountState::Initialized,
+                ..Default::default()
+            };
+            spl_token::state::Account::pack(state, &mut data).unwrap();
+            self.add_account(leak_account(key, token::ID, 1, &data, false));
+
+            let mint = &self.accounts[&self.mint];
+            let mut state = spl_token::state::Mint::unpack(&mint.data.borrow()).unwrap();
+            state.supply += amount;
+            spl_token::state::Mint::pack(state, &mut mint.data.borrow_mut()).unwrap();
+        }
+
+        /// Approve `delegate` to move up to `amount` out of a token account
+        fn approve(&mut self, key: Pubkey, delegate: Pubkey, amount: u64) {
+            let account = &self.accounts[&key];
+            let mut state = spl_token::state::Account::unpack(&account.data.borrow()).unwrap();
+            state.delegate = Some(delegate).into();
+            state.delegated_amount = amount;
+            spl_token::state::Account::pack(state, &mut account.data.borrow_mut()).unwrap();
+        }
+
+        /// A new signer holding `tokens`, with its user account already created
+        fn wallet(&mut self, tokens: u64) -> Wallet {
+            let owner = Pubkey::new_unique();
+            let wallet = Wallet {
+                owner,
+                tokens: Pubkey::new_unique(),
+                user: user_address(&owner),
+            };
+            self.add_system_account(wallet.owner);
+            self.add_token_account(wallet.tokens, wallet.owner, tokens);
+            self.run(
+                crate::accounts::InitializeUser {
+                    payer: wallet.owner,
+                    bank_account: self.bank,
+                    user_account: wallet.user,
+                    system_program: system_program::ID,
+                },
+                crate::instruction::CreateUser {},
+            )
+            .unwrap();
+            wallet
+        }
+
+        /// Advance the clock by `slots`, at `SLOTS_PER_DAY` slots a day
+        fn warp(&mut self, slots: u64) {
+            self.slot += slots;
+            let unix_timestamp = self.now();
+            let mut data = self.accounts[&Clock::id()].data.borrow_mut();
+            data[..8].copy_from_slice(&self.slot.to_le_bytes());
+            data[32..].copy_from_slice(&unix_timestamp.to_le_bytes());
+        }
+
+        fn now(&self) -> i64 {
+            GENESIS_TIMESTAMP + (self.slot * 24 * 60 * 60 / SLOTS_PER_DAY) as i64
+        }
+
+        fn run(&mut self, accounts: impl ToAccountMetas, args: impl InstructionData) -> std::result::Result<(), ProgramError> {
+            self.run_with(accounts, &[], args)
+        }
+
+        /// Run one instruction with `remaining` appended as writable remaining accounts.
+        /// Accounts not seen before start out empty and owned by the system program.
+        fn run_with(
+            &mut self,
+            accounts: impl ToAccountMetas,
+            remaining: &[Pubkey],
+            args: impl InstructionData,
+        ) -> std::result::Result<(), ProgramError> {
+            let mut metas = accounts.to_account_metas(None);
+            metas.extend(remaining.iter().map(|key| AccountMeta::new(*key, false)));
+            let infos: Vec<AccountInfo<'static>> = metas
+                .iter()
+                .map(|meta| {
+                    let mut info = self
+                        .accounts
+                        .entry(meta.pubkey)
+                        .or_insert_with(|| leak_account(meta.pubkey, system_program::ID, 0, &[], false))
+                        .clone();
+                    info.is_signer = meta.is_signer;
+                    info.is_writable = meta.is_writable;
+                    info
+                })
+                .collect();
+            let snapshot: Vec<(AccountInfo<'static>, u64, Vec<u8>, Pubkey)> = infos
+                .iter()
+                .map(|info| (info.clone(), info.lamports(), info.data.borrow().to_vec(), *info.owner))
+                .collect();
+
+            EVENTS.with(|events| events.borrow_mut().clear());
+            let result = crate::entry(&crate::ID, Box::leak(infos.into_boxed_slice()), &args.data());
+
+            // As on chain, a failed instruction leaves no trace
+            if result.is_err() {
+                for (info, lamports, data, owner) in snapshot {
+                    **info.lamports.borrow_mut() = lamports;
+                    info.realloc(data.len(), false).unwrap();
+                    info.data.borrow_mut().copy_from_slice(&data);
+                    info.assign(&owner);
+                }
+            }
+            result
+        }
+
+        /// Deserialize a program account, or `None` once it has been closed
+        fn state<T: AccountDeserialize>(&self, key: &Pubkey) -> Option<T> {
+            let info = self.accounts.get(key)?;
+            if info.data_is_empty() {
+                return None;
+            }
+            Some(T::try_deserialize(&mut &info.data.borrow()[..]).unwrap())
+        }
+
+        fn bank_state(&self) -> Bank {
+            self.state(&self.bank).unwrap()
+        }
+
+        fn user_state(&self, wallet: &Wallet) -> User {
+            self.state(&wallet.user).unwrap()
+        }
+
+        fn token_balance(&self, key: &Pubkey) -> u64 {
+            spl_token::state::Account::unpack(&self.accounts[key].data.borrow()).unwrap().amount
+        }
+
+        /// Events of type `E` emitted by the last instruction
+        fn events<E: Event + AnchorDeserialize>(&self) -> Vec<E> {
+            EVENTS.with(|events| {
+                events
+                    .borrow()
+                    .iter()
+                    .filter(|data| data.starts_with(E::DISCRIMINATOR))
+                    .map(|data| E::try_from_slice(&data[E::DISCRIMINATOR.len()..]).unwrap())
+                    .collect()
+            })
+        }
+
+        fn admin_operation(&self) -> crate::accounts::AdminOperation {
+            crate::accounts::AdminOperation {
+                admin: self.admin.owner,
+                bank_account: self.bank,
+                system_program: system_program::ID,
+            }
+        }
+
+        /// Move `amount` of the admin's tokens into the bank's reward and lending pool
+        fn fund(&mut self, amount: u64) {
+            self.run(
+                crate::accounts::AddBankFunds {
+                    admin: self.admin.owner,
+                    bank_account: self.bank,
+                    mint: self.mint,
+                    admin_token_account: self.admin.tokens,
+                    vault_token_account: self.vault,
+                    token_program: token::ID,
+                },
+                crate::instruction::AddBankFunds { amount },
+            )
+            .unwrap();
+        }
+
+        fn operations(&self, wallet: &Wallet) -> crate::accounts::Operations {
+            crate::accounts::Operations {
+                clock: Clock::id(),
+                payer: wallet.owner,
+                bank_account: self.bank,
+                user_account: wallet.user,
+                mint: self.mint,
+                user_token_account: wallet.tokens,
+                vault_token_account: self.vault,
+                token_program: token::ID,
+                system_program: system_program::ID,
+            }
+        }
+
+        fn staking(&self, wallet: &Wallet) -> crate::accounts::Staking {
+            crate::accounts::Staking {
+                clock: Clock::id(),
+                payer: wallet.owner,
+                bank_account: self.bank,
+                user_account: wallet.user,
+                reward_recipient: None,
+                system_program: system_program::ID,
+            }
+        }
+
+        fn deposit(&mut self, wallet: &Wallet, amount: u64) -> std::result::Result<(), ProgramError> {
+            self.run(self.operations(wallet), crate::instruction::Deposit { amount })
+        }
+
+        fn withdraw(&mut self, wallet: &Wallet, amount: u64) -> std::result::Result<(), ProgramError> {
+            self.run(self.operations(wallet), crate::instruction::Withdraw { amount })
+        }
+
+        fn stake(&mut self, wallet: &Wallet, amount: u64) -> std::result::Result<(), ProgramError> {
+            self.run(self.staking(wallet), crate::instruction::Stake { amount })
+        }
+
+        fn claim_rewards(&mut self, wallet: &Wallet) -> std::result::Result<(), ProgramError> {
+            self.run(self.staking(wallet), crate::instruction::ClaimRewards {})
+        }
+
+        fn delete_user(&mut self, wallet: &Wallet, reward_recipient: Option<&Wallet>) -> std::result::Result<(), ProgramError> {
+            self.run(
+                crate::accounts::DeleteUser {
+                    clock: Clock::id(),
+                    payer: wallet.owner,
+                    bank_account: self.bank,
+                    user_account: wallet.user,
+                    mint: self.mint,
+                    user_token_account: wallet.tokens,
+                    vault_token_account: self.vault,
+                    token_program: token::ID,
+                    reward_recipient: reward_recipient.map(|recipient| recipient.user),
+                },
+                crate::instruction::DeleteUser {},
+            )
+        }
+
+        fn borrow(&mut self, wallet: &Wallet, amount: u64) -> std::result::Result<(), ProgramError> {
+            let loan_index = self.user_state(wallet).loan_count;
+            self.run(
+                crate::accounts::LoanOperations {
+                    clock: Clock::id(),
+                    payer: wallet.owner,
+                    bank_account: self.bank,
+                    user_account: wallet.user,
+                    loan_account: loan_address(&wallet.owner, loan_index),
+                    system_program: system_program::ID,
+                },
+                crate::instruction::Borrow {
+                    amount,
+                    max_acceptable_rate_bps: BASIS_POINTS_DIVISOR,
+                },
+            )
+        }
+
+        fn liquidate(&mut self, liquidator: &Wallet, borrower: &Wallet, loan_index: u64) -> std::result::Result<(), ProgramError> {
+            // Pass the insurance bucket once it exists
+            let insurance = bucket_address(INSURANCE_BUCKET_NAME);
+            self.run(
+                crate::accounts::Liquidation {
+                    clock: Clock::id(),
+                    liquidator: liquidator.owner,
+                    bank_account: self.bank,
+                    user_account: borrower.user,
+                    loan_account: loan_address(&borrower.owner, loan_index),
+                    liquidator_account: liquidator.user,
+                    insurance_bucket: Some(insurance).filter(|bucket| self.state::<Bucket>(bucket).is_some()),
+                },
+                crate::instruction::Liquidate { loan_index },
+            )
+        }
+
+        fn repay_all_loans(&mut self, wallet: &Wallet, loan_indexes: &[u64]) -> std::result::Result<(), ProgramError> {
+            let loans: Vec<Pubkey> = loan_indexes.iter().map(|index| loan_address(&wallet.owner, *index)).collect();
+            self.run_with(
+                crate::accounts::RepayAllLoans {
+                    clock: Clock::id(),
+                    payer: wallet.owner,
+                    bank_account: self.bank,
+                    user_account: wallet.user,
+                },
+                &loans,
+                crate::instruction::RepayAllLoans {},
+            )
+        }
+
+        fn user_view(&self, wallet: &Wallet) -> crate::accounts::UserView {
+            crate::accounts::UserView {
+                clock: Clock::id(),
+                bank_account: self.bank,
+                user_account: wallet.user,
+            }
+        }
+    }
+
+    fn program_error(error: ErrorCode) -> ProgramError {
+        ProgramError::Custom(error.into())
+    }
+
+    #[test]
+    fn zero_deposit_is_rejected() {
+        let err = validate_deposit_amount(0).unwrap_err();
+        assert_eq!(err, ErrorCode::InvalidAmount.into());
+        assert_eq!(
+            validate_deposit_amount(MIN_DEPOSIT_AMOUNT - 1).unwrap_err(),
+            ErrorCode::DepositTooSmall.into()
+        );
+        assert!(validate_deposit_amount(MIN_DEPOSIT_AMOUNT).is_ok());
+    }
+
+    #[test]
+    fn partial_unstake_restarts_accrual_on_the_remainder() {
+        const N: u64 = SLOTS_PER_DAY * 10;
+        const STAKE: u64 = 100_000_000_000;
+
+        let mut bank_state: Bank = zeroed();
+        bank_state.balance = 1_000_000_000_000;
+        bank_state.staking_apy_bps = STAKING_APY_BASIS_POINTS;
+        let mut bank = program_account(&bank_state);
+
+        let mut user = program_account(&zeroed::<User>());
+        user.reward_recipient = user.key();
+        user.staked_balance = STAKE;
+        user.stake_start_slot = 1;
+        user.stake_slot = 1;
+        user.last_update_slot = 1;
+        user.apy_index_checkpoint = projected_apy_index(&bank, 1).unwrap();
+        bank.staked_balance = STAKE;
+
+        let first = process_unstake(&mut bank, &mut user, &mut None, STAKE / 2, 1 + N, &mut 0).unwrap();
+        let second = process_unstake(&mut bank, &mut user, &mut None, STAKE / 2, 1 + 2 * N, &mut 0).unwrap();
+
+        // 100 staked for N slots, then the remaining 50 for N more
+        let full_period = |staked: u64| {
+            (staked as u128 * STAKING_APY_BASIS_POINTS as u128 * N as u128
+                / BASIS_POINTS_DIVISOR as u128
+                / SLOTS_PER_YEAR as u128) as u64
+        };
+        assert_eq!(first, full_period(STAKE));
+        assert_eq!(second, full_period(STAKE / 2));
+        assert_eq!(user.staked_balance, 0);
+        assert_eq!(user.balance, STAKE + first + second);
+    }
+
+    #[test]
+    fn concurrent_loans_aggregate_into_user_debt() {
+        let mut bank: Bank = zeroed();
+        let mut user: User = zeroed();
+        let year = SECONDS_PER_YEAR as i64;
+
+        let mut first = loan(1_000_000, 500, false);
+        first.loan_index = 0;
+        let mut second = loan(2_000_000, 1_300, false);
+        second.loan_index = 1;
+        second.start_timestamp = year / 2;
+        for open in [&first, &second] {
+            add_loan_weights(&mut bank, &mut user, open).unwrap();
+            user.lent_balance += open.principal;
+        }
+
+        let per_loan = |loan: &Loan| loan.principal + calculate_loan_owed_interest(loan, year).unwrap();
+        assert_eq!(calculate_loan_debt(&user, year).unwrap(), per_loan(&first) + per_loan(&second));
+
+        // Closing one loan leaves exactly the other's debt
+        remove_loan_weights(&mut bank, &mut user, &first).unwrap();
+        user.lent_balance -= first.principal;
+        assert_eq!(calculate_loan_debt(&user, year).unwrap(), per_loan(&second));
+    }
+
+    #[test]
+    fn aggregate_debt_matches_a_single_compounded_loan() {
+        let mut bank: Bank = zeroed();
+        let mut user: User = zeroed();
+        let year = SECONDS_PER_YEAR as i64;
+
+        let mut compounded = loan(1_000_000, 500, true);
+        compounded.start_timestamp = 1;
+        add_loan_weights(&mut bank, &mut user, &compounded).unwrap();
+        user.lent_balance = compounded.principal;
+
+        assert_eq!(
+            calculate_loan_debt(&user, year + 1).unwrap(),
+            compounded.principal + calculate_loan_owed_interest(&compounded, year + 1).unwrap()
+        );
+    }
+
+    #[test]
+    fn compound_interest_exceeds_simple_over_a_year() {
+        let year = SECONDS_PER_YEAR as i64;
+        let simple = calculate_accrued_interest(false, 1_000_000, year, 500).unwrap();
+        let compound = calculate_accrued_interest(true, 1_000_000, year, 500).unwrap();
+
+        assert_eq!(simple, 50_000);
+        // (1 + 0.05 / 365)^365 - 1 = 5.1267%
+        assert_eq!(compound, 51_267);
+    }
+
+    #[test]
+    fn loan_keeps_its_interest_model() {
+        let year = SECONDS_PER_YEAR as i64;
+        let simple = calculate_loan_owed_interest(&loan(1_000_000, 500, false), year).unwrap();
+        let compound = calculate_loan_owed_interest(&loan(1_000_000, 500, true), year).unwrap();
+
+        assert_eq!(simple, calculate_accrued_interest(false, 1_000_000, year, 500).unwrap());
+        assert_eq!(compound, calculate_accrued_interest(true, 1_000_000, year, 500).unwrap());
+    }
+
+    #[test]
+    fn carried_interest_is_owed_and_compounds() {
+        let mut simple = loan(1_000_000, 500, false);
+        simple.carried_interest = 10_000;
+        let mut compound = loan(1_000_000, 500, true);
+        compound.carried_interest = 10_000;
+        let year = SECONDS_PER_YEAR as i64;
+
+        assert_eq!(calculate_loan_owed_interest(&simple, 0).unwrap(), 10_000);
+        assert_eq!(calculate_loan_owed_interest(&simple, year).unwrap(), 60_000);
+        assert_eq!(
+            calculate_loan_owed_interest(&compound, year).unwrap(),
+            10_000 + calculate_accrued_interest(true, 1_010_000, year, 500).unwrap()
+        );
+    }
+    fn set_max_tx_outflow(test_bank: &mut TestBank, max_tx_outflow: u64) {
+        test_bank
+            .run(test_bank.admin_operation(), crate::instruction::SetMaxTxOutflow { max_tx_outflow })
+            .unwrap();
+    }
+
+    #[test]
+    fn large_borrow_trips_the_outflow_limit() {
+        let mut test_bank = TestBank::new();
+        test_bank.fund(100_000 * TOKEN);
+        let borrower = test_bank.wallet(10_000 * TOKEN);
+        test_bank.deposit(&borrower, 10_000 * TOKEN).unwrap();
+        set_max_tx_outflow(&mut test_bank, 1_000 * TOKEN);
+
+        assert_eq!(
+            test_bank.borrow(&borrower, 1_000 * TOKEN + 1),
+            Err(program_error(ErrorCode::TxOutflowExceeded))
+        );
+        test_bank.borrow(&borrower, 1_000 * TOKEN).unwrap();
+        // Each instruction gets the whole allowance, however much the slot has already seen
+        test_bank.borrow(&borrower, 1_000 * TOKEN).unwrap();
+        assert_eq!(test_bank.user_state(&borrower).lent_balance, 2_000 * TOKEN);
+    }
+
+    #[test]
+    fn large_reward_payout_trips_the_outflow_limit() {
+        let mut test_bank = TestBank::new();
+        test_bank.fund(100_000 * TOKEN);
+        let staker = test_bank.wallet(10_000 * TOKEN);
+        test_bank.deposit(&staker, 10_000 * TOKEN).unwrap();
+        test_bank.stake(&staker, 10_000 * TOKEN).unwrap();
+        test_bank.warp(SLOTS_PER_YEAR);
+
+        let reward = calculate_pending_reward(&test_bank.bank_state(), &test_bank.user_state(&staker), test_bank.slot)
+            .unwrap();
+        assert_eq!(reward, 500 * TOKEN);
+        set_max_tx_outflow(&mut test_bank, reward - 1);
+        assert_eq!(
+            test_bank.run(test_bank.staking(&staker), crate::instruction::ClaimRewards {}),
+            Err(program_error(ErrorCode::TxOutflowExceeded))
+        );
+
+        set_max_tx_outflow(&mut test_bank, reward);
+        test_bank.run(test_bank.staking(&staker), crate::instruction::ClaimRewards {}).unwrap();
+        assert_eq!(test_bank.user_state(&staker).balance, reward);
+    }
+
+    #[test]
+    fn outflow_limit_is_not_shared_between_callers() {
+        let mut test_bank = TestBank::new();
+        let first = test_bank.wallet(1_000 * TOKEN);
+        let second = test_bank.wallet(1_000 * TOKEN);
+        test_bank.deposit(&first, 1_000 * TOKEN).unwrap();
+        test_bank.deposit(&second, 1_000 * TOKEN).unwrap();
+        set_max_tx_outflow(&mut test_bank, 1_000 * TOKEN);
+
+        test_bank.withdraw(&first, 1_000 * TOKEN).unwrap();
+        test_bank.withdraw(&second, 1_000 * TOKEN).unwrap();
+        assert_eq!(test_bank.token_balance(&first.tokens), 1_000 * TOKEN);
+        assert_eq!(test_bank.token_balance(&second.tokens), 1_000 * TOKEN);
+    }
+    /// A borrower with 500 liquid and 500 staked who borrowed the 760 maximum and withdrew it,
+    /// leaving a health factor of exactly 1.0 until interest accrues
+    fn leveraged_borrower(test_bank: &mut TestBank) -> Wallet {
+        let borrower = test_bank.wallet(1_000 * TOKEN);
+        test_bank.deposit(&borrower, 1_000 * TOKEN).unwrap();
+        test_bank.stake(&borrower, 500 * TOKEN).unwrap();
+        test_bank.borrow(&borrower, 760 * TOKEN).unwrap();
+        test_bank.withdraw(&borrower, 760 * TOKEN).unwrap();
+        borrower
+    }
+
+    #[test]
+    fn healthy_loan_is_not_liquidatable() {
+        let mut test_bank = TestBank::new();
+        test_bank.fund(10_000 * TOKEN);
+        let liquidator = test_bank.wallet(0);
+        let borrower = test_bank.wallet(1_000 * TOKEN);
+        test_bank.deposit(&borrower, 1_000 * TOKEN).unwrap();
+        test_bank.borrow(&borrower, 500 * TOKEN).unwrap();
+        assert_eq!(
+            test_bank.liquidate(&liquidator, &borrower, 0),
+            Err(program_error(ErrorCode::NotLiquidatable))
+        );
+
+        let borrower = leveraged_borrower(&mut test_bank);
+        assert_eq!(
+            test_bank.liquidate(&liquidator, &borrower, 0),
+            Err(program_error(ErrorCode::NotLiquidatable))
+        );
+    }
+
+    #[test]
+    fn underwater_loan_is_seized_from_balance_then_stake() {
+        let mut test_bank = TestBank::new();
+        test_bank.fund(10_000 * TOKEN);
+        let liquidator = test_bank.wallet(1_000 * TOKEN);
+        test_bank.deposit(&liquidator, 1_000 * TOKEN).unwrap();
+        let borrower = leveraged_borrower(&mut test_bank);
+        test_bank.warp(SLOTS_PER_YEAR);
+        let before = test_bank.bank_state();
+        let loan_rent = test_bank.accounts[&loan_address(&borrower.owner, 0)].lamports();
+        let user_lamports = test_bank.accounts[&borrower.user].lamports();
+
+        test_bank.liquidate(&liquidator, &borrower, 0).unwrap();
+
+        // A year at 13% on 760 owes 98.8 interest; the 5% bonus on the 858.8 debt is 42.94,
+        // so 901.74 is seized: the 500 balance and 401.74 of the stake
+        let interest = 98_800_000_000;
+        let bonus = 42_940_000_000;
+        let user = test_bank.user_state(&borrower);
+        assert_eq!(user.balance, 0);
+        assert_eq!(user.staked_balance, 500 * TOKEN - 401_740_000_000);
+        assert_eq!(user.lent_balance, 0);
+        assert_eq!(user.locked_balance, 0);
+        assert_eq!(user.lifetime_interest_paid, interest);
+        assert_eq!(test_bank.user_state(&liquidator).balance, 1_000 * TOKEN + bonus);
+
+        let bank = test_bank.bank_state();
+        assert_eq!(bank.balance, before.balance + 760 * TOKEN + interest);
+        assert_eq!(bank.lent_balance, 0);
+        assert_eq!(bank.open_loans, 0);
+        assert_eq!(bank.staked_balance, before.staked_balance - 401_740_000_000);
+        assert_eq!(bank.total_deposits, before.total_deposits - 500 * TOKEN);
+        assert_eq!(bank.total_interest_collected, interest);
+        assert_eq!(bank.report_interest_collected, interest);
+
+        // The loan account is closed and its rent returned to the borrower's user account
+        assert!(test_bank.state::<Loan>(&loan_address(&borrower.owner, 0)).is_none());
+        assert_eq!(test_bank.accounts[&borrower.user].lamports(), user_lamports + loan_rent);
+    }
+
+    #[test]
+    fn repay_all_loans_settles_every_loan_in_one_call() {
+        let mut test_bank = TestBank::new();
+        test_bank.fund(10_000 * TOKEN);
+        let borrower = test_bank.wallet(1_000 * TOKEN);
+        test_bank.deposit(&borrower, 1_000 * TOKEN).unwrap();
+        test_bank.borrow(&borrower, 100 * TOKEN).unwrap();
+        test_bank.borrow(&borrower, 200 * TOKEN).unwrap();
+        test_bank.warp(SLOTS_PER_YEAR);
+
+        assert_eq!(
+            test_bank.repay_all_loans(&borrower, &[1]),
+            Err(program_error(ErrorCode::IncompleteLoanSet))
+        );
+        assert_eq!(
+            test_bank.repay_all_loans(&borrower, &[0, 0]),
+            Err(program_error(ErrorCode::IncompleteLoanSet))
+        );
+        test_bank.repay_all_loans(&borrower, &[0, 1]).unwrap();
+
+        // A year at 13%: 13 on the first loan and 26 on the second
+        let repaid: Vec<(u64, u64, u64)> = test_bank
+            .events::<RepayEvent>()
+            .iter()
+            .map(|event| (event.loan_index, event.principal, event.interest))
+            .collect();
+        assert_eq!(repaid, vec![(0, 100 * TOKEN, 13 * TOKEN), (1, 200 * TOKEN, 26 * TOKEN)]);
+
+        let user = test_bank.user_state(&borrower);
+        assert_eq!(user.balance, 1_300 * TOKEN - 339 * TOKEN);
+        assert_eq!(user.lent_balance, 0);
+        assert_eq!(user.locked_balance, 0);
+        assert_eq!(test_bank.bank_state().open_loans, 0);
+        assert!(test_bank.state::<Loan>(&loan_address(&borrower.owner, 0)).is_none());
+        assert!(test_bank.state::<Loan>(&loan_address(&borrower.owner, 1)).is_none());
+    }
+
+    #[test]
+    fn repay_all_loans_rejects_a_balance_short_of_the_total() {
+        let mut test_bank = TestBank::new();
+        test_bank.fund(10_000 * TOKEN);
+        let borrower = test_bank.wallet(400 * TOKEN);
+        test_bank.deposit(&borrower, 300 * TOKEN).unwrap();
+        test_bank.borrow(&borrower, 100 * TOKEN).unwrap();
+        test_bank.borrow(&borrower, 140 * TOKEN).unwrap();
+        test_bank.withdraw(&borrower, 240 * TOKEN).unwrap();
+        test_bank.warp(2 * SLOTS_PER_YEAR);
+
+        // Two years at 13% on 240 makes 302.4 owed against a 300 balance, though each loan alone is covered
+        assert_eq!(
+            test_bank.repay_all_loans(&borrower, &[0, 1]),
+            Err(program_error(ErrorCode::InsufficientBalance))
+        );
+        assert_eq!(test_bank.user_state(&borrower).lent_balance, 240 * TOKEN);
+        assert!(test_bank.state::<Loan>(&loan_address(&borrower.owner, 0)).is_some());
+
+        test_bank.deposit(&borrower, 10 * TOKEN).unwrap();
+        test_bank.repay_all_loans(&borrower, &[0, 1]).unwrap();
+        assert_eq!(test_bank.user_state(&borrower).balance, 310 * TOKEN - 302_400_000_000);
+    }
+
+    #[test]
+    fn is_liquidatable_flags_only_underwater_loans() {
+        let mut test_bank = TestBank::new();
+        test_bank.fund(10_000 * TOKEN);
+        let borrower = leveraged_borrower(&mut test_bank);
+
+        test_bank.run(test_bank.user_view(&borrower), crate::instruction::IsLiquidatable {}).unwrap();
+        let eligibility = &test_bank.events::<LiquidationEligibility>()[0];
+        assert!(!eligibility.liquidatable);
+        assert_eq!(eligibility.health_factor, LIQUIDATION_HEALTH_FACTOR_BPS);
+
+        // A year of 13% interest takes the 760 debt to 858.8 against 760 of collateral value
+        test_bank.warp(SLOTS_PER_YEAR);
+        test_bank.run(test_bank.user_view(&borrower), crate::instruction::IsLiquidatable {}).unwrap();
+        let eligibility = &test_bank.events::<LiquidationEligibility>()[0];
+        assert!(eligibility.liquidatable);
+        assert_eq!(eligibility.health_factor, 8_849);
+    }
+
+    #[test]
+    fn close_factor_liquidates_a_loan_in_steps() {
+        let mut test_bank = TestBank::new();
+        test_bank.fund(10_000 * TOKEN);
+        let liquidator = test_bank.wallet(0);
+        let borrower = leveraged_borrower(&mut test_bank);
+        test_bank
+            .run(test_bank.admin_operation(), crate::instruction::SetCloseFactor { close_factor_bps: 5_000 })
+            .unwrap();
+        test_bank.warp(SLOTS_PER_YEAR);
+
+        // Half of the 858.8 debt is 429.4, which clears the 98.8 interest and 330.6 of principal;
+        // 429.4 plus the 21.47 bonus comes out of the 500 balance
+        test_bank.liquidate(&liquidator, &borrower, 0).unwrap();
+        let loan: Loan = test_bank.state(&loan_address(&borrower.owner, 0)).unwrap();
+        assert_eq!(loan.principal, 429_400_000_000);
+        assert_eq!(loan.carried_interest, 0);
+        let user = test_bank.user_state(&borrower);
+        assert_eq!(user.balance, 500 * TOKEN - 450_870_000_000);
+        assert_eq!(user.staked_balance, 500 * TOKEN);
+        assert_eq!(user.lent_balance, 429_400_000_000);
+        assert_eq!(test_bank.user_state(&liquidator).balance, 21_470_000_000);
+        assert_eq!(test_bank.events::<LiquidationEvent>()[0].remaining_principal, 429_400_000_000);
+
+        // Still underwater, so a second call repays half of what is left
+        test_bank.liquidate(&liquidator, &borrower, 0).unwrap();
+        let loan: Loan = test_bank.state(&loan_address(&borrower.owner, 0)).unwrap();
+        assert_eq!(loan.principal, 214_700_000_000);
+        let user = test_bank.user_state(&borrower);
+        assert_eq!(user.balance, 0);
+        assert_eq!(user.staked_balance, 500 * TOKEN - (225_435_000_000 - 49_130_000_000));
+        assert_eq!(test_bank.user_state(&liquidator).balance, 21_470_000_000 + 10_735_000_000);
+        assert_eq!(test_bank.bank_state().open_loans, 1);
+    }
+
+    #[test]
+    fn liquidator_bonus_above_the_cap_goes_to_insurance() {
+        let mut test_bank = TestBank::new();
+        test_bank.fund(10_000 * TOKEN);
+        let liquidator = test_bank.wallet(0);
+        let borrower = leveraged_borrower(&mut test_bank);
+        test_bank
+            .run(test_bank.admin_operation(), crate::instruction::SetMaxLiquidatorBonus { max_liquidator_bonus: 10 * TOKEN })
+            .unwrap();
+        test_bank.warp(SLOTS_PER_YEAR);
+
+        // The 42.94 bonus exceeds the cap, so there must be an insurance bucket to take the excess
+        assert_eq!(
+            test_bank.liquidate(&liquidator, &borrower, 0),
+            Err(program_error(ErrorCode::InvalidAddress))
+        );
+
+        let insurance = bucket_address(INSURANCE_BUCKET_NAME);
+        test_bank
+            .run(
+                crate::accounts::CreateBucket {
+                    admin: test_bank.admin.owner,
+                    bank_account: test_bank.bank,
+                    bucket_account: insurance,
+                    system_program: system_program::ID,
+                },
+                crate::instruction::CreateBucket { name: INSURANCE_BUCKET_NAME.to_string() },
+            )
+            .unwrap();
+        let bank_balance = test_bank.bank_state().balance;
+        test_bank.liquidate(&liquidator, &borrower, 0).unwrap();
+
+        assert_eq!(test_bank.user_state(&liquidator).balance, 10 * TOKEN);
+        assert_eq!(test_bank.state::<Bucket>(&insurance).unwrap().balance, 32_940_000_000);
+        let bank = test_bank.bank_state();
+        assert_eq!(bank.bucketed_balance, 32_940_000_000);
+        assert_eq!(bank.balance, bank_balance + 858_800_000_000 + 32_940_000_000);
+        let event = &test_bank.events::<LiquidationEvent>()[0];
+        assert_eq!((event.seized, event.bonus, event.insured), (901_740_000_000, 10 * TOKEN, 32_940_000_000));
+    }
+
+    #[test]
+    fn clean_record_bonus_is_forfeited_by_liquidation() {
+        let mut test_bank = TestBank::new();
+        test_bank.fund(10_000 * TOKEN);
+        test_bank
+            .run(test_bank.admin_operation(), crate::instruction::SetCleanRecordBonus { clean_record_bonus_bps: 1_000 })
+            .unwrap();
+        let liquidator = test_bank.wallet(0);
+        let staker = test_bank.wallet(500 * TOKEN);
+        test_bank.deposit(&staker, 500 * TOKEN).unwrap();
+        test_bank.stake(&staker, 500 * TOKEN).unwrap();
+        let borrower = leveraged_borrower(&mut test_bank);
+        test_bank.warp(SLOTS_PER_YEAR);
+
+        // A year of APY on 500 tokens, and 10% more for the clean record
+        let base_reward = 500 * TOKEN * STAKING_APY_BASIS_POINTS / BASIS_POINTS_DIVISOR;
+        test_bank.claim_rewards(&staker).unwrap();
+        assert_eq!(test_bank.events::<ClaimRewardEvent>()[0].reward, base_reward * 11 / 10);
+        assert_eq!(test_bank.user_state(&staker).liquidation_count, 0);
+
+        // The borrower staked as long, but the liquidation settles the year without the bonus
+        test_bank.liquidate(&liquidator, &borrower, 0).unwrap();
+        assert_eq!(test_bank.user_state(&borrower).liquidation_count, 1);
+        test_bank.claim_rewards(&borrower).unwrap();
+        assert_eq!(test_bank.events::<ClaimRewardEvent>()[0].reward, base_reward);
+    }
+
+    #[test]
+    fn claims_below_the_minimum_reward_are_rejected() {
+        let mut test_bank = TestBank::new();
+        test_bank.fund(10_000 * TOKEN);
+        test_bank
+            .run(test_bank.admin_operation(), crate::instruction::SetMinClaimableReward { min_claimable_reward: TOKEN })
+            .unwrap();
+        let first = test_bank.wallet(1_000 * TOKEN);
+        let second = test_bank.wallet(1_000 * TOKEN);
+        for wallet in [&first, &second] {
+            test_bank.deposit(wallet, 1_000 * TOKEN).unwrap();
+            test_bank.stake(wallet, 1_000 * TOKEN).unwrap();
+        }
+
+        // A day of 5% on 1000 tokens is about 0.137, short of the 1 token minimum
+        test_bank.warp(SLOTS_PER_DAY);
+        assert_eq!(test_bank.claim_rewards(&first), Err(program_error(ErrorCode::RewardBelowMinimum)));
+
+        // Unstaking still pays out the small reward
+        test_bank.run(test_bank.staking(&second), crate::instruction::Unstake { amount: 1_000 * TOKEN }).unwrap();
+        assert_eq!(test_bank.user_state(&second).balance, 1_000 * TOKEN + 1_000 * TOKEN * 500 / 10_000 / 365);
+
+        // Ten days accrue about 1.37, which can be claimed
+        test_bank.warp(9 * SLOTS_PER_DAY);
+        test_bank.claim_rewards(&first).unwrap();
+        assert_eq!(test_bank.events::<ClaimRewardEvent>()[0].reward, 1_000 * TOKEN * 500 / 10_000 * 10 / 365);
+    }
+
+    #[test]
+    fn reward_funding_pulls_are_bounded_per_period() {
+        let mut test_bank = TestBank::new();
+        let source = Pubkey::new_unique();
+        let sponsor = Pubkey::new_unique();
+        test_bank.add_token_account(source, sponsor, 1_000 * TOKEN);
+        test_bank.approve(source, test_bank.bank, 1_000 * TOKEN);
+        test_bank
+            .run(
+                test_bank.admin_operation(),
+                crate::instruction::SetRewardFundingSource { source, limit: 100 * TOKEN },
+            )
+            .unwrap();
+        let pull = |test_bank: &mut TestBank, amount: u64| {
+            test_bank.run(
+                crate::accounts::PullRewardFunding {
+                    clock: Clock::id(),
+                    caller: Pubkey::new_unique(),
+                    bank_account: test_bank.bank,
+                    mint: test_bank.mint,
+                    funding_source: source,
+                    vault_token_account: test_bank.vault,
+                    token_program: token::ID,
+                },
+                crate::instruction::PullRewardFunding { amount },
+            )
+        };
+
+        // Anyone may pull up to the limit within a day
+        pull(&mut test_bank, 60 * TOKEN).unwrap();
+        pull(&mut test_bank, 40 * TOKEN).unwrap();
+        assert_eq!(pull(&mut test_bank, 1), Err(program_error(ErrorCode::RewardFundingLimitExceeded)));
+        assert_eq!(test_bank.bank_state().balance, 100 * TOKEN);
+        assert_eq!(test_bank.token_balance(&source), 900 * TOKEN);
+        assert_eq!(test_bank.token_balance(&test_bank.vault), 100 * TOKEN);
+
+        // A new period restores the limit, but one pull still cannot exceed it
+        test_bank.warp(SLOTS_PER_DAY);
+        assert_eq!(pull(&mut test_bank, 101 * TOKEN), Err(program_error(ErrorCode::RewardFundingLimitExceeded)));
+        pull(&mut test_bank, 100 * TOKEN).unwrap();
+        assert_eq!(test_bank.bank_state().balance, 200 * TOKEN);
+        assert_eq!(test_bank.events::<RewardFundingPulled>()[0].new_balance, 200 * TOKEN);
+    }
+
+    #[test]
+    fn deposit_cap_is_derived_from_mint_supply() {
+        let mut test_bank = TestBank::new();
+        let depositor = test_bank.wallet(9_000 * TOKEN);
+        let set_cap = |test_bank: &mut TestBank, supply_bps: u64| {
+            test_bank.run(
+                crate::accounts::DepositCapFromSupply {
+                    admin: test_bank.admin.owner,
+                    bank_account: test_bank.bank,
+                    mint: test_bank.mint,
+                },
+                crate::instruction::SetDepositCapFromSupply { supply_bps },
+            )
+        };
+
+        // The admin's million and the depositor's 9000 make a supply of 1,009,000
+        set_cap(&mut test_bank, 50).unwrap();
+        assert_eq!(test_bank.bank_state().max_deposit, 5_045 * TOKEN);
+        assert_eq!(test_bank.deposit(&depositor, 5_046 * TOKEN), Err(program_error(ErrorCode::AmountTooLarge)));
+        test_bank.deposit(&depositor, 5_045 * TOKEN).unwrap();
+
+        // Re-deriving picks up a supply that has since grown
+        test_bank.wallet(991_000 * TOKEN);
+        set_cap(&mut test_bank, 50).unwrap();
+        assert_eq!(test_bank.bank_state().max_deposit, 10_000 * TOKEN);
+        assert_eq!(set_cap(&mut test_bank, 10_001), Err(program_error(ErrorCode::InvalidAmount)));
+    }
+
+    #[test]
+    fn stake_pledged_to_a_loan_unlocks_as_it_is_repaid() {
+        let mut test_bank = TestBank::new();
+        test_bank.fund(10_000 * TOKEN);
+        let borrower = test_bank.wallet(1_000 * TOKEN);
+        test_bank.deposit(&borrower, 1_000 * TOKEN).unwrap();
+        test_bank.stake(&borrower, 500 * TOKEN).unwrap();
+
+        // 180 borrowed locks 225 of collateral, which 250 staked tokens cover at 90%
+        test_bank.borrow(&borrower, 180 * TOKEN).unwrap();
+        let user = test_bank.user_state(&borrower);
+        assert_eq!(user.locked_staked_balance, 250 * TOKEN);
+        let loan: Loan = test_bank.state(&loan_address(&borrower.owner, 0)).unwrap();
+        assert_eq!(loan.locked_stake, 250 * TOKEN);
+
+        test_bank.warp(MIN_STAKING_SLOTS);
+        let unstake = |test_bank: &mut TestBank, amount: u64| {
+            test_bank.run(test_bank.staking(&borrower), crate::instruction::Unstake { amount })
+        };
+        assert_eq!(unstake(&mut test_bank, 251 * TOKEN), Err(program_error(ErrorCode::InvalidCollateralRatio)));
+        unstake(&mut test_bank, 250 * TOKEN).unwrap();
+        assert_eq!(unstake(&mut test_bank, 1), Err(program_error(ErrorCode::InvalidCollateralRatio)));
+
+        // Repaying half the principal, after the interest, releases half the pledge
+        let loan: Loan = test_bank.state(&loan_address(&borrower.owner, 0)).unwrap();
+        let interest = calculate_loan_owed_interest(&loan, test_bank.now()).unwrap();
+        test_bank
+            .run(
+                crate::accounts::PartialRepay {
+                    clock: Clock::id(),
+                    payer: borrower.owner,
+                    bank_account: test_bank.bank,
+                    user_account: borrower.user,
+                    loan_account: loan_address(&borrower.owner, 0),
+                },
+                crate::instruction::RepayPartial { loan_index: 0, amount: interest + 90 * TOKEN },
+            )
+            .unwrap();
+        assert_eq!(test_bank.user_state(&borrower).locked_staked_balance, 125 * TOKEN);
+        assert_eq!(unstake(&mut test_bank, 126 * TOKEN), Err(program_error(ErrorCode::InvalidCollateralRatio)));
+        unstake(&mut test_bank, 125 * TOKEN).unwrap();
+
+        // Repaying the rest releases the whole position
+        test_bank.repay_all_loans(&borrower, &[0]).unwrap();
+        assert_eq!(test_bank.user_state(&borrower).locked_staked_balance, 0);
+        unstake(&mut test_bank, 125 * TOKEN).unwrap();
+        assert_eq!(test_bank.user_state(&borrower).staked_balance, 0);
+    }
+
+    #[test]
+    fn borrowing_power_weights_liquid_and_staked_collateral() {
+        let mut test_bank = TestBank::new();
+        test_bank.fund(10_000 * TOKEN);
+        let wallet = test_bank.wallet(1_000 * TOKEN);
+        test_bank.deposit(&wallet, 1_000 * TOKEN).unwrap();
+        test_bank.stake(&wallet, 400 * TOKEN).unwrap();
+
+        // 600 liquid at 80% is 480; 400 staked counts 360 of collateral, which at 80% is 288
+        test_bank.run(test_bank.user_view(&wallet), crate::instruction::GetTotalBorrowingPower {}).unwrap();
+        let power = &test_bank.events::<BorrowingPower>()[0];
+        assert_eq!(power.liquid_power, 480 * TOKEN);
+        assert_eq!(power.staked_power, 288 * TOKEN);
+        assert_eq!(power.total_power, 768 * TOKEN);
+        assert_eq!(power.available, 768 * TOKEN);
+
+        // The whole combined power can be borrowed, and nothing more
+        assert_eq!(test_bank.borrow(&wallet, 768 * TOKEN + 1), Err(program_error(ErrorCode::InvalidCollateralRatio)));
+        test_bank.borrow(&wallet, 768 * TOKEN).unwrap();
+    }
+
+    #[test]
+    fn portfolio_health_counts_borrowers_and_staked_collateral() {
+        let mut test_bank = TestBank::new();
+        test_bank.fund(10_000 * TOKEN);
+        let staker = test_bank.wallet(1_000 * TOKEN);
+        test_bank.deposit(&staker, 1_000 * TOKEN).unwrap();
+        test_bank.stake(&staker, 500 * TOKEN).unwrap();
+        test_bank.borrow(&staker, 200 * TOKEN).unwrap();
+        let two_loans = test_bank.wallet(500 * TOKEN);
+        test_bank.deposit(&two_loans, 500 * TOKEN).unwrap();
+        test_bank.borrow(&two_loans, 100 * TOKEN).unwrap();
+        test_bank.borrow(&two_loans, 100 * TOKEN).unwrap();
+        let saver = test_bank.wallet(300 * TOKEN);
+        test_bank.deposit(&saver, 300 * TOKEN).unwrap();
+        test_bank.warp(SLOTS_PER_YEAR);
+
+        test_bank
+            .run_with(
+                crate::accounts::AdminView {
+                    clock: Clock::id(),
+                    admin: test_bank.admin.owner,
+                    bank_account: test_bank.bank,
+                },
+                &[staker.user, two_loans.user, saver.user],
+                crate::instruction::GetPortfolioHealth {},
+            )
+            .unwrap();
+
+        // Each 200 of principal owes 26 after a year at 13%. Collateral is 700 liquid plus 90%
+        // of 500 staked for the first borrower, and 700 liquid for the second
+        let health = &test_bank.events::<PortfolioHealth>()[0];
+        assert_eq!(health.borrowers, 2);
+        assert_eq!(health.total_debt, 452 * TOKEN);
+        assert_eq!(health.total_collateral, 1_850 * TOKEN);
+        assert_eq!(health.collateralization_bps, 40_929);
+    }
+
+    #[test]
+    fn ui_amounts_use_the_mint_decimals() {
+        let mut test_bank = TestBank::with_decimals(6);
+        assert_eq!(test_bank.bank_state().decimals, 6);
+        let wallet = test_bank.wallet(10_000_000);
+        test_bank.deposit(&wallet, 2_500_000).unwrap();
+        assert_eq!(test_bank.events::<DepositEvent>()[0].ui_amount, "2.5");
+    }
+    /// A wallet with an empty balance and stake but a settled, unclaimed reward
+    fn unstaked_with_pending_reward(test_bank: &mut TestBank) -> Wallet {
+        let wallet = test_bank.wallet(500 * TOKEN);
+        test_bank.deposit(&wallet, 500 * TOKEN).unwrap();
+        test_bank
+            .run(test_bank.staking(&wallet), crate::instruction::StakeLocked { amount: 500 * TOKEN, lock_slots: 30 * SLOTS_PER_DAY })
+            .unwrap();
+        test_bank.warp(10 * SLOTS_PER_DAY);
+
+        // Settling moves the reward into pending_reward, which breaking the lock does not forfeit
+        test_bank
+            .run_with(
+                crate::accounts::SettleRewards { clock: Clock::id(), bank_account: test_bank.bank },
+                &[wallet.user],
+                crate::instruction::BatchSettleRewards {},
+            )
+            .unwrap();
+        test_bank.run(test_bank.staking(&wallet), crate::instruction::ForceUnstake { amount: 500 * TOKEN }).unwrap();
+        test_bank.withdraw(&wallet, 450 * TOKEN).unwrap();
+        let user = test_bank.user_state(&wallet);
+        assert_eq!((user.balance, user.staked_balance), (0, 0));
+        assert!(user.pending_reward > 0);
+        wallet
+    }
+
+    #[test]
+    fn delete_user_rejects_unclaimed_rewards_until_claimed() {
+        let mut test_bank = TestBank::new();
+        test_bank.fund(10_000 * TOKEN);
+        let wallet = unstaked_with_pending_reward(&mut test_bank);
+
+        assert_eq!(test_bank.delete_user(&wallet, None), Err(program_error(ErrorCode::UnclaimedRewards)));
+
+        let reward = test_bank.user_state(&wallet).pending_reward;
+        test_bank.claim_rewards(&wallet).unwrap();
+        test_bank.withdraw(&wallet, reward).unwrap();
+        test_bank.delete_user(&wallet, None).unwrap();
+        assert!(test_bank.state::<User>(&wallet.user).is_none());
+        assert_eq!(test_bank.token_balance(&wallet.tokens), 450 * TOKEN + reward);
+    }
+
+    #[test]
+    fn delete_user_pays_out_unclaimed_rewards_when_enabled() {
+        let mut test_bank = TestBank::new();
+        test_bank.fund(10_000 * TOKEN);
+        test_bank
+            .run(test_bank.admin_operation(), crate::instruction::SetPayRewardsOnDelete { pay: true })
+            .unwrap();
+
+        // Without a designated recipient the reward goes to the owner's token account
+        let wallet = unstaked_with_pending_reward(&mut test_bank);
+        let reward = test_bank.user_state(&wallet).pending_reward;
+        test_bank.delete_user(&wallet, None).unwrap();
+        assert!(test_bank.state::<User>(&wallet.user).is_none());
+        assert_eq!(test_bank.token_balance(&wallet.tokens), 450 * TOKEN + reward);
+
+        // A designated recipient is credited instead, and must be passed
+        let recipient = test_bank.wallet(0);
+        let wallet = unstaked_with_pending_reward(&mut test_bank);
+        test_bank
+            .run(
+                crate::accounts::SetRewardRecipient {
+                    payer: wallet.owner,
+                    bank_account: test_bank.bank,
+                    user_account: wallet.user,
+                    recipient_account: recipient.user,
+                },
+                crate::instruction::SetRewardRecipient {},
+            )
+            .unwrap();
+        let reward = test_bank.user_state(&wallet).pending_reward;
+        assert_eq!(test_bank.delete_user(&wallet, None), Err(program_error(ErrorCode::InvalidAddress)));
+        test_bank.delete_user(&wallet, Some(&recipient)).unwrap();
+        assert_eq!(test_bank.user_state(&recipient).balance, reward);
+        assert_eq!(test_bank.token_balance(&wallet.tokens), 450 * TOKEN);
+    }
+
+    #[test]
+    fn protocol_owned_balance_tracks_admin_funds_only() {
+        let mut test_bank = TestBank::new();
+        test_bank.fund(1_000 * TOKEN);
+        let bank = test_bank.bank_state();
+        assert_eq!((bank.balance, bank.protocol_owned_balance), (1_000 * TOKEN, 1_000 * TOKEN));
+
+        // Deposits are user obligations, not protocol equity
+        let wallet = test_bank.wallet(500 * TOKEN);
+        test_bank.deposit(&wallet, 500 * TOKEN).unwrap();
+        let bank = test_bank.bank_state();
+        assert_eq!((bank.balance, bank.protocol_owned_balance), (1_000 * TOKEN, 1_000 * TOKEN));
+        assert_eq!(bank.total_deposits, 500 * TOKEN);
+
+        // A locked-stake grant is paid out of protocol equity
+        test_bank
+            .run(
+                crate::accounts::GrantLockedStake {
+                    clock: Clock::id(),
+                    admin: test_bank.admin.owner,
+                    bank_account: test_bank.bank,
+                    user_account: wallet.user,
+                    reward_recipient: None,
+                },
+                crate::instruction::GrantLockedStake { amount: 200 * TOKEN, unlock_slot: 100 },
+            )
+            .unwrap();
+        let bank = test_bank.bank_state();
+        assert_eq!((bank.balance, bank.protocol_owned_balance), (800 * TOKEN, 800 * TOKEN));
+        assert_eq!(test_bank.user_state(&wallet).granted_stake, 200 * TOKEN);
+    }
+
+    /// A year of APY reward on 1000 staked tokens while `borrowed` is lent out of a 1000 token pool
+    fn dynamic_apy_reward(borrowed: u64) -> u64 {
+        let mut test_bank = TestBank::new();
+        test_bank.fund(1_000 * TOKEN);
+        test_bank
+            .run(
+                crate::accounts::AdminClockOperation {
+                    clock: Clock::id(),
+                    admin: test_bank.admin.owner,
+                    bank_account: test_bank.bank,
+                },
+                crate::instruction::SetDynamicApyParams { target_utilization_bps: 5_000, min_apy_bps: 200, max_apy_bps: 1_000 },
+            )
+            .unwrap();
+        let staker = test_bank.wallet(1_000 * TOKEN);
+        test_bank.deposit(&staker, 1_000 * TOKEN).unwrap();
+        test_bank.stake(&staker, 1_000 * TOKEN).unwrap();
+        if borrowed > 0 {
+            let borrower = test_bank.wallet(2 * borrowed);
+            test_bank.deposit(&borrower, 2 * borrowed).unwrap();
+            test_bank.borrow(&borrower, borrowed).unwrap();
+        }
+
+        test_bank.warp(SLOTS_PER_YEAR);
+        test_bank.claim_rewards(&staker).unwrap();
+        test_bank.events::<ClaimRewardEvent>()[0].reward
+    }
+
+    #[test]
+    fn dynamic_apy_follows_lending_utilization() {
+        // Nothing lent: the APY sits at the 10% maximum to attract borrowers
+        assert_eq!(dynamic_apy_reward(0), 100 * TOKEN);
+        // 25% utilization is half way to the target, so 10% - 0.5 * 8% = 6%
+        assert_eq!(dynamic_apy_reward(250 * TOKEN), 60 * TOKEN);
+        // At or beyond the 50% target the APY bottoms out at 2%
+        assert_eq!(dynamic_apy_reward(800 * TOKEN), 20 * TOKEN);
+    }
+
+    #[test]
+    fn reward_runway_is_the_pool_over_the_annual_liability() {
+        let mut test_bank = TestBank::new();
+        test_bank.fund(1_000 * TOKEN);
+        let runway = |test_bank: &mut TestBank| {
+            test_bank
+                .run(crate::accounts::BankView { bank_account: test_bank.bank }, crate::instruction::GetRewardRunway {})
+                .unwrap();
+            test_bank.events::<RewardRunway>().remove(0)
+        };
+
+        // Nothing staked owes nothing
+        let empty = runway(&mut test_bank);
+        assert!(empty.is_infinite);
+        assert_eq!(empty.runway_seconds, u64::MAX);
+
+        // 2000 staked at 5% owes 100 a year, which 1000 covers for ten years
+        let staker = test_bank.wallet(2_000 * TOKEN);
+        test_bank.deposit(&staker, 2_000 * TOKEN).unwrap();
+        test_bank.stake(&staker, 2_000 * TOKEN).unwrap();
+        let staked = runway(&mut test_bank);
+        assert!(!staked.is_infinite);
+        assert_eq!(staked.annual_liability, 100 * TOKEN);
+        assert_eq!(staked.runway_seconds, 10 * SECONDS_PER_YEAR);
+    }
+
+    #[test]
+    fn emission_is_split_pro_rata_between_stakers() {
+        let mut test_bank = TestBank::new();
+        test_bank.fund(1_000 * TOKEN);
+        test_bank
+            .run(
+                crate::accounts::AdminClockOperation {
+                    clock: Clock::id(),
+                    admin: test_bank.admin.owner,
+                    bank_account: test_bank.bank,
+                },
+                crate::instruction::SetRewardPerSlot { reward_per_slot: 1_000 },
+            )
+            .unwrap();
+        let early = test_bank.wallet(100 * TOKEN);
+        let late = test_bank.wallet(300 * TOKEN);
+        test_bank.deposit(&early, 100 * TOKEN).unwrap();
+        test_bank.deposit(&late, 300 * TOKEN).unwrap();
+
+        // The early staker earns the whole emission until the late one joins with three times the stake
+        test_bank.stake(&early, 100 * TOKEN).unwrap();
+        test_bank.warp(1_000);
+        test_bank.stake(&late, 300 * TOKEN).unwrap();
+        test_bank.warp(1_000);
+
+        test_bank.claim_rewards(&early).unwrap();
+        assert_eq!(test_bank.events::<ClaimRewardEvent>()[0].reward, 1_000_000 + 250_000);
+        test_bank.claim_rewards(&late).unwrap();
+        assert_eq!(test_bank.events::<ClaimRewardEvent>()[0].reward, 750_000);
+    }
 
-    #[account(
-        init,
-        space = 8 + Bank::INIT_SPACE,
-        payer = admin,
-        seeds = [b"bank"],
-        bump
-    )]
-    pub bank_account: Account<'info, Bank>,
+    #[test]
+    fn quoted_net_reward_matches_the_unstake_payout() {
+        let mut test_bank = TestBank::new();
+        test_bank.fund(1_000 * TOKEN);
+        test_bank
+            .run(test_bank.admin_operation(), crate::instruction::SetCleanRecordBonus { clean_record_bonus_bps: 1_000 })
+            .unwrap();
+        let wallet = test_bank.wallet(1_000 * TOKEN);
+        test_bank.deposit(&wallet, 1_000 * TOKEN).unwrap();
 
-    pub system_program: Program<'info, System>,
-}
+        // A 30-day tier that lapses part way through, on top of the clean-record bonus
+        test_bank
+            .run(test_bank.staking(&wallet), crate::instruction::StakeLocked { amount: 1_000 * TOKEN, lock_slots: 30 * SLOTS_PER_DAY })
+            .unwrap();
+        test_bank.warp(45 * SLOTS_PER_DAY);
 
-#[derive(Accounts)]
-pub struct InitializeUser<'info> {
-    #[account(mut)]
-    pub payer: Signer<'info>,
+        test_bank
+            .run(test_bank.user_view(&wallet), crate::instruction::QuoteNetReward { amount: 400 * TOKEN })
+            .unwrap();
+        let quote = test_bank.events::<NetRewardQuote>().remove(0);
+        let balance = test_bank.user_state(&wallet).balance;
+        test_bank.run(test_bank.staking(&wallet), crate::instruction::Unstake { amount: 400 * TOKEN }).unwrap();
 
-    #[account(
-        mut,
-        seeds = [b"bank"],
-        bump
-    )]
-    pub bank_account: Account<'info, Bank>,
+        assert!(quote.net_reward > 0);
+        assert_eq!(test_bank.events::<UnstakeEvent>()[0].reward, quote.net_reward);
+        assert_eq!(test_bank.user_state(&wallet).balance, balance + 400 * TOKEN + quote.net_reward);
+    }
+    /// Every token in the vault is owed either to the bank or to a user, liquid or staked
+    fn assert_vault_reconciles(test_bank: &TestBank, wallets: &[&Wallet]) {
+        let owed_to_users: u64 = wallets
+            .iter()
+            .filter_map(|wallet| test_bank.state::<User>(&wallet.user))
+            .map(|user| user.balance + user.staked_balance)
+            .sum();
+        assert_eq!(
+            test_bank.token_balance(&test_bank.vault),
+            test_bank.bank_state().balance + owed_to_users
+        );
+    }
 
-    #[account(
-        init,
-        payer = payer,
-        space = 8 + User::INIT_SPACE,
-        seeds = [b"user", payer.key().as_ref()],
-        bump,
-    )]
-    pub user_account: Account<'info, User>,
+    #[test]
+    fn vault_holds_the_bank_balance_plus_user_balances() {
+        let mut test_bank = TestBank::new();
+        test_bank.fund(1_000 * TOKEN);
+        let staker = test_bank.wallet(500 * TOKEN);
+        let saver = test_bank.wallet(300 * TOKEN);
+        let wallets = [&staker, &saver];
+        assert_vault_reconciles(&test_bank, &wallets);
 
-    pub system_program: Program<'info, System>,
-}
+        test_bank.deposit(&staker, 500 * TOKEN).unwrap();
+        test_bank.deposit(&saver, 300 * TOKEN).unwrap();
+        assert_vault_reconciles(&test_bank, &wallets);
 
-#[derive(Accounts)]
-pub struct DeleteUser<'info> {
-    #[account(mut)]
-    pub payer: Signer<'info>,
+        test_bank.stake(&staker, 400 * TOKEN).unwrap();
+        test_bank.warp(SLOTS_PER_YEAR);
+        test_bank.run(test_bank.staking(&staker), crate::instruction::Unstake { amount: 400 * TOKEN }).unwrap();
+        assert_eq!(test_bank.events::<UnstakeEvent>()[0].reward, 20 * TOKEN);
+        assert_vault_reconciles(&test_bank, &wallets);
 
-    #[account(
-        mut,
-        seeds = [b"bank"],
-        bump
-    )]
-    pub bank_account: Account<'info, Bank>,
+        test_bank.withdraw(&staker, 520 * TOKEN).unwrap();
+        test_bank.withdraw(&saver, 300 * TOKEN).unwrap();
+        assert_vault_reconciles(&test_bank, &wallets);
 
-    #[account(
-        mut,
-        seeds = [b"user", payer.key().as_ref()],
-        bump,
-        close = payer,
-    )]
-    pub user_account: Account<'info, User>,
-}
+        test_bank.delete_user(&saver, None).unwrap();
+        assert_vault_reconciles(&test_bank, &wallets);
+        assert_eq!(test_bank.token_balance(&test_bank.vault), 980 * TOKEN);
+    }
 
-#[derive(Accounts)]
-pub struct CheckBalance<'info> {
-    #[account(
-        seeds = [b"user", user_account.owner.as_ref()],
-        bump
-    )]
-    pub user_account: Account<'info, User>,
-}
+    #[test]
+    fn upgrade_mode_blocks_mutations_but_not_views() {
+        let mut test_bank = TestBank::new();
+        test_bank.fund(1_000 * TOKEN);
+        let wallet = test_bank.wallet(1_000 * TOKEN);
+        test_bank.deposit(&wallet, 500 * TOKEN).unwrap();
+        test_bank.stake(&wallet, 200 * TOKEN).unwrap();
+        test_bank.borrow(&wallet, 100 * TOKEN).unwrap();
+        test_bank.warp(SLOTS_PER_DAY);
+        let set_upgrade_mode = |test_bank: &mut TestBank, enabled: bool| {
+            test_bank
+                .run(
+                    crate::accounts::UpgradeModeOperation { admin: test_bank.admin.owner, bank_account: test_bank.bank },
+                    crate::instruction::SetUpgradeMode { enabled },
+                )
+                .unwrap();
+        };
+        set_upgrade_mode(&mut test_bank, true);
 
-#[derive(Accounts)]
-pub struct Operations<'info> {
-    #[account(mut)]
-    pub payer: Signer<'info>,
+        let upgrading = Err(program_error(ErrorCode::UpgradeInProgress));
+        assert_eq!(test_bank.deposit(&wallet, 100 * TOKEN), upgrading);
+        assert_eq!(test_bank.withdraw(&wallet, 100 * TOKEN), upgrading);
+        assert_eq!(test_bank.stake(&wallet, 100 * TOKEN), upgrading);
+        assert_eq!(test_bank.run(test_bank.staking(&wallet), crate::instruction::Unstake { amount: 100 * TOKEN }), upgrading);
+        assert_eq!(test_bank.claim_rewards(&wallet), upgrading);
+        assert_eq!(test_bank.borrow(&wallet, 10 * TOKEN), upgrading);
+        assert_eq!(test_bank.repay_all_loans(&wallet, &[0]), upgrading);
+        assert_eq!(test_bank.delete_user(&wallet, None), upgrading);
+        assert_eq!(
+            test_bank.run(test_bank.admin_operation(), crate::instruction::SetMaxTxOutflow { max_tx_outflow: TOKEN }),
+            upgrading
+        );
+        assert_eq!(
+            test_bank.run(
+                crate::accounts::AddBankFunds {
+                    admin: test_bank.admin.owner,
+                    bank_account: test_bank.bank,
+                    mint: test_bank.mint,
+                    admin_token_account: test_bank.admin.tokens,
+                    vault_token_account: test_bank.vault,
+                    token_program: token::ID,
+                },
+                crate::instruction::AddBankFunds { amount: TOKEN },
+            ),
+            upgrading
+        );
 
-    #[account(
-        mut,
-        seeds = [b"bank"],
-        bump
-    )]
-    pub bank_account: Account<'info, Bank>,
+        // Read instructions keep working
+        test_bank
+            .run(crate::accounts::CheckBalance { user_account: wallet.user }, crate::instruction::CheckBalance {})
+            .unwrap();
+        assert_eq!(test_bank.events::<BalanceChecked>()[0].balance, 400 * TOKEN);
+        test_bank.run(test_bank.user_view(&wallet), crate::instruction::GetPendingReward {}).unwrap();
+        test_bank.run(test_bank.user_view(&wallet), crate::instruction::IsLiquidatable {}).unwrap();
+        assert!(!test_bank.events::<LiquidationEligibility>()[0].liquidatable);
+        test_bank
+            .run(crate::accounts::BankView { bank_account: test_bank.bank }, crate::instruction::GetBankStats {})
+            .unwrap();
 
-    #[account(
-        mut,
-        seeds = [b"user", payer.key().as_ref()],
-        bump,
-        constraint = user_account.owner == payer.key() @ ErrorCode::Unauthorized
-    )]
-    pub user_account: Account<'info, User>,
+        // Leaving upgrade mode lets mutations through again
+        set_upgrade_mode(&mut test_bank, false);
+        test_bank.deposit(&wallet, 100 * TOKEN).unwrap();
+        test_bank.claim_rewards(&wallet).unwrap();
+    }
 
-    pub system_program: Program<'info, System>,
-}
+    #[test]
+    fn rewards_follow_the_designated_recipient() {
+        let mut test_bank = TestBank::new();
+        test_bank.fund(1_000 * TOKEN);
+        let staker = test_bank.wallet(1_000 * TOKEN);
+        let recipient = test_bank.wallet(0);
+        test_bank.deposit(&staker, 1_000 * TOKEN).unwrap();
+        test_bank.stake(&staker, 1_000 * TOKEN).unwrap();
+        let set_reward_recipient = |test_bank: &mut TestBank, recipient: &Wallet| {
+            test_bank
+                .run(
+                    crate::accounts::SetRewardRecipient {
+                        payer: staker.owner,
+                        bank_account: test_bank.bank,
+                        user_account: staker.user,
+                        recipient_account: recipient.user,
+                    },
+                    crate::instruction::SetRewardRecipient {},
+                )
+                .unwrap();
+        };
+        set_reward_recipient(&mut test_bank, &recipient);
+        assert_eq!(test_bank.user_state(&staker).reward_recipient, recipient.user);
 
-#[derive(Accounts)]
-pub struct LoanOperations<'info> {
-    pub clock: Sysvar<'info, Clock>,
+        // The recipient must be passed once one is designated
+        test_bank.warp(10 * SLOTS_PER_DAY);
+        assert_eq!(test_bank.claim_rewards(&staker), Err(program_error(ErrorCode::InvalidAddress)));
+        let redirected = crate::accounts::Staking { reward_recipient: Some(recipient.user), ..test_bank.staking(&staker) };
+        test_bank.run(redirected, crate::instruction::ClaimRewards {}).unwrap();
+        let reward = 1_000 * TOKEN * 500 / 10_000 * 10 / 365;
+        assert_eq!(test_bank.user_state(&recipient).balance, reward);
+        assert_eq!(test_bank.user_state(&staker).balance, 0);
 
-    #[account(mut)]
-    pub payer: Signer<'info>,
+        // Pointing the recipient back at the staker's own account resets routing
+        set_reward_recipient(&mut test_bank, &staker);
+        test_bank.warp(10 * SLOTS_PER_DAY);
+        test_bank.claim_rewards(&staker).unwrap();
+        assert_eq!(test_bank.user_state(&staker).balance, reward);
+        assert_eq!(test_bank.user_state(&recipient).balance, reward);
+    }
 
-    #[account(
-        mut,
-        seeds = [b"bank"],
-        bump
-    )]
-    pub bank_account: Account<'info, Bank>,
+    #[test]
+    fn deposits_stop_at_the_tvl_cap() {
+        let mut test_bank = TestBank::new();
+        test_bank
+            .run(test_bank.admin_operation(), crate::instruction::SetMaxTvl { max_tvl: 1_000 * TOKEN })
+            .unwrap();
+        let wallet = test_bank.wallet(2_000 * TOKEN);
+        test_bank.deposit(&wallet, 600 * TOKEN).unwrap();
 
-    #[account(
-        mut,
-        seeds = [b"user", payer.key().as_ref()],
-        bump,
-        constraint = user_account.owner == payer.key() @ ErrorCode::Unauthorized
-    )]
-    pub user_account: Account<'info, User>,
+        // Staking re-uses deposited funds and does not count again
+        test_bank.stake(&wallet, 600 * TOKEN).unwrap();
+        test_bank.deposit(&wallet, 400 * TOKEN).unwrap();
+        assert_eq!(test_bank.bank_state().total_deposits, 1_000 * TOKEN);
+        assert_eq!(test_bank.deposit(&wallet, TOKEN), Err(program_error(ErrorCode::TvlCapExceeded)));
 
-    pub system_program: Program<'info, System>,
-}
+        // Withdrawals free up room under the cap
+        test_bank.withdraw(&wallet, 100 * TOKEN).unwrap();
+        test_bank.deposit(&wallet, 100 * TOKEN).unwrap();
+        assert_eq!(test_bank.deposit(&wallet, TOKEN), Err(program_error(ErrorCode::TvlCapExceeded)));
+    }
 
-#[derive(Accounts)]
-pub struct Staking<'info> {
-    pub clock: Sysvar<'info, Clock>,
+    #[test]
+    fn early_repayment_within_the_window_earns_the_rebate() {
+        let mut test_bank = TestBank::new();
+        test_bank.fund(10_000 * TOKEN);
+        test_bank
+            .run(
+                test_bank.admin_operation(),
+                crate::instruction::SetEarlyRepayRebate { rebate_bps: 5_000, window_seconds: 100 * 24 * 60 * 60 },
+            )
+            .unwrap();
+        let early = test_bank.wallet(1_000 * TOKEN);
+        let late = test_bank.wallet(1_000 * TOKEN);
+        for borrower in [&early, &late] {
+            test_bank.deposit(borrower, 1_000 * TOKEN).unwrap();
+            test_bank.borrow(borrower, 100 * TOKEN).unwrap();
+        }
 
-    #[account(mut)]
-    pub payer: Signer<'info>,
+        // 73 days at 13% is 2.6 interest, half of it rebated inside the window
+        test_bank.warp(73 * SLOTS_PER_DAY);
+        test_bank.repay_all_loans(&early, &[0]).unwrap();
+        assert_eq!(test_bank.events::<RepayEvent>()[0].interest, 13 * TOKEN / 10);
 
-    #[account(
-        mut,
-        seeds = [b"bank"],
-        bump
-    )]
-    pub bank_account: Account<'info, Bank>,
+        // Past the window the full year of interest is owed
+        test_bank.warp(292 * SLOTS_PER_DAY);
+        test_bank.repay_all_loans(&late, &[0]).unwrap();
+        assert_eq!(test_bank.events::<RepayEvent>()[0].interest, 13 * TOKEN);
+    }
 
-    #[account(
-        mut,
-        seeds = [b"user", payer.key().as_ref()],
-        bump,
-        constraint = user_account.owner == payer.key() @ ErrorCode::Unauthorized
-    )]
-    pub user_account: Account<'info, User>,
+    #[test]
+    fn delegate_withdrawals_are_bounded_by_the_allowance_per_period() {
+        let mut test_bank = TestBank::new();
+        let owner = test_bank.wallet(1_000 * TOKEN);
+        let delegate = test_bank.wallet(0);
+        test_bank.deposit(&owner, 1_000 * TOKEN).unwrap();
+        test_bank
+            .run(
+                crate::accounts::UserSettings { payer: owner.owner, bank_account: test_bank.bank, user_account: owner.user },
+                crate::instruction::SetDelegateAllowance {
+                    delegate: delegate.owner,
+                    allowance: 100 * TOKEN,
+                    period_slots: 10 * SLOTS_PER_DAY,
+                },
+            )
+            .unwrap();
+        let delegated_withdraw = |test_bank: &mut TestBank, amount: u64| {
+            test_bank.run(
+                crate::accounts::DelegatedWithdraw {
+                    clock: Clock::id(),
+                    delegate: delegate.owner,
+                    bank_account: test_bank.bank,
+                    user_account: owner.user,
+                    mint: test_bank.mint,
+                    destination_token_account: delegate.tokens,
+                    vault_token_account: test_bank.vault,
+                    token_program: token::ID,
+                },
+                crate::instruction::DelegatedWithdraw { amount },
+            )
+        };
 
-    pub system_program: Program<'info, System>,
-}
+        delegated_withdraw(&mut test_bank, 60 * TOKEN).unwrap();
+        assert_eq!(test_bank.events::<DelegatedWithdrawEvent>()[0].remaining_allowance, 40 * TOKEN);
+        assert_eq!(delegated_withdraw(&mut test_bank, 50 * TOKEN), Err(program_error(ErrorCode::AllowanceExceeded)));
+        delegated_withdraw(&mut test_bank, 40 * TOKEN).unwrap();
+        assert_eq!(delegated_withdraw(&mut test_bank, TOKEN), Err(program_error(ErrorCode::AllowanceExceeded)));
 
-#[derive(Accounts)]
-pub struct FundTransfer<'info> {
-    #[account(mut)]
-    pub payer: Signer<'info>,
+        // The allowance is restored once the period has elapsed
+        test_bank.warp(10 * SLOTS_PER_DAY);
+        delegated_withdraw(&mut test_bank, 100 * TOKEN).unwrap();
+        assert_eq!(test_bank.token_balance(&delegate.tokens), 200 * TOKEN);
+        assert_eq!(test_bank.user_state(&owner).balance, 800 * TOKEN);
+    }
 
-    #[account(
-        mut,
-        seeds = [b"user", payer.key().as_ref()],
-        bump,
-        constraint = from_user.owner == payer.key() @ ErrorCode::Unauthorized
-    )]
-    pub from_user: Account<'info, User>,
+    #[test]
+    fn apy_boost_only_applies_inside_its_window() {
+        let mut test_bank = TestBank::new();
+        test_bank.fund(1_000 * TOKEN);
+        let staker = test_bank.wallet(3_650 * TOKEN);
+        test_bank.deposit(&staker, 3_650 * TOKEN).unwrap();
+        test_bank.stake(&staker, 3_650 * TOKEN).unwrap();
+        test_bank
+            .run(
+                crate::accounts::GrantApyBoost {
+                    clock: Clock::id(),
+                    admin: test_bank.admin.owner,
+                    bank_account: test_bank.bank,
+                    user_account: staker.user,
+                },
+                crate::instruction::GrantApyBoost { boost_bps: 500, boost_until_slot: test_bank.slot + 10 * SLOTS_PER_DAY },
+            )
+            .unwrap();
 
-    #[account(
-        mut,
-        seeds = [b"user", to_user.owner.as_ref()],
-        bump,
-    )]
-    pub to_user: Account<'info, User>,
+        // 5% on 3650 tokens is 0.5 a day, doubled while the boost lasts
+        test_bank.warp(5 * SLOTS_PER_DAY);
+        test_bank.claim_rewards(&staker).unwrap();
+        assert_eq!(test_bank.events::<ClaimRewardEvent>()[0].reward, 5 * TOKEN);
 
-    pub system_program: Program<'info, System>,
-}
+        // Five more boosted days, then ten at the base rate
+        test_bank.warp(15 * SLOTS_PER_DAY);
+        test_bank.claim_rewards(&staker).unwrap();
+        assert_eq!(test_bank.events::<ClaimRewardEvent>()[0].reward, 5 * TOKEN + 5 * TOKEN);
+    }
 
-#[derive(Accounts)]
-pub struct AdminOperation<'info> {
-    #[account(mut)]
-    pub admin: Signer<'info>,
+    #[test]
+    fn borrowing_stops_at_max_open_loans() {
+        let mut test_bank = TestBank::new();
+        test_bank.fund(10_000 * TOKEN);
+        test_bank
+            .run(test_bank.admin_operation(), crate::instruction::SetMaxOpenLoans { max_open_loans: 2 })
+            .unwrap();
+        let borrowers: Vec<Wallet> = (0..3).map(|_| test_bank.wallet(1_000 * TOKEN)).collect();
+        for borrower in &borrowers {
+            test_bank.deposit(borrower, 1_000 * TOKEN).unwrap();
+        }
+        test_bank.borrow(&borrowers[0], 100 * TOKEN).unwrap();
+        test_bank.borrow(&borrowers[1], 100 * TOKEN).unwrap();
+        assert_eq!(test_bank.borrow(&borrowers[2], 100 * TOKEN), Err(program_error(ErrorCode::TooManyOpenLoans)));
+        assert_eq!(test_bank.bank_state().open_loans, 2);
 
-    #[account(
-        mut,
-        seeds = [b"bank"],
-        bump,
-        constraint = bank_account.admin == admin.key() @ ErrorCode::Unauthorized
-    )]
-    pub bank_account: Account<'info, Bank>,
+        // Repaying a loan frees a slot for the next borrower
+        test_bank.repay_all_loans(&borrowers[0], &[0]).unwrap();
+        test_bank.borrow(&borrowers[2], 100 * TOKEN).unwrap();
+        assert_eq!(test_bank.bank_state().open_loans, 2);
+    }
 
-    pub system_program: Program<'info, System>,
-}
+    #[test]
+    fn reward_follows_the_time_weighted_stake_across_top_ups_and_partial_unstakes() {
+        let mut test_bank = TestBank::new();
+        test_bank.fund(1_000 * TOKEN);
+        let staker = test_bank.wallet(7_300 * TOKEN);
+        test_bank.deposit(&staker, 7_300 * TOKEN).unwrap();
+        let unstake = |test_bank: &mut TestBank, amount: u64| {
+            test_bank.run(test_bank.staking(&staker), crate::instruction::Unstake { amount }).unwrap();
+        };
+        let rewards_paid = |test_bank: &TestBank| test_bank.bank_state().total_rewards_paid;
 
-// Data structures
-#[account]
-#[derive(InitSpace)]
-pub struct Bank {
-    pub admin: Pubkey,
-    pub balance: u64,
-    pub staked_balance: u64,
-    pub lent_balance: u64,
-    pub total_users: u64,
-    pub is_operational: bool,
-}
+        // 5% a year is 0.25 a day on 1825 tokens; each top-up settles the reward so far
+        test_bank.stake(&staker, 1_825 * TOKEN).unwrap();
+        test_bank.warp(10 * SLOTS_PER_DAY);
+        test_bank.stake(&staker, 1_825 * TOKEN).unwrap();
+        assert_eq!(rewards_paid(&test_bank), 25 * TOKEN / 10);
+        test_bank.warp(10 * SLOTS_PER_DAY);
+        test_bank.stake(&staker, 3_650 * TOKEN).unwrap();
+        assert_eq!(rewards_paid(&test_bank), 25 * TOKEN / 10 + 5 * TOKEN);
+        test_bank.warp(10 * SLOTS_PER_DAY);
+        unstake(&mut test_bank, 3_650 * TOKEN);
+        assert_eq!(rewards_paid(&test_bank), 25 * TOKEN / 10 + 5 * TOKEN + 10 * TOKEN);
 
-#[account]
-#[derive(InitSpace)]
-pub struct User {
-    pub owner: Pubkey,
-    pub balance: u64,
-    pub staked_balance: u64,
-    pub stake_slot: u64,
-    pub lent_balance: u64,
-    pub loan_timestamp: i64,
-}
+        // The remainder of a partial unstake keeps accruing at its reduced size
+        test_bank.warp(10 * SLOTS_PER_DAY);
+        unstake(&mut test_bank, 1_825 * TOKEN);
+        test_bank.warp(10 * SLOTS_PER_DAY);
+        test_bank.claim_rewards(&staker).unwrap();
 
-// Events
-#[event]
-pub struct BalanceChecked {
-    pub user: Pubkey,
-    pub balance: u64,
-    pub staked_balance: u64,
-    pub lent_balance: u64,
-}
+        // 10 days each at 1825, 3650, 7300, 3650 and 1825 staked
+        let integral: u64 = [1_825, 3_650, 7_300, 3_650, 1_825].iter().sum::<u64>() * TOKEN * 10;
+        assert_eq!(rewards_paid(&test_bank), integral * 500 / 10_000 / 365);
+        assert_eq!(test_bank.user_state(&staker).balance, 7_300 * TOKEN - 1_825 * TOKEN + 25 * TOKEN);
+    }
 
-#[event]
-pub struct DepositEvent {
-    pub user: Pubkey,
-    pub amount: u64,
-    pub new_balance: u64,
-}
+    #[test]
+    fn loans_below_the_interest_free_threshold_accrue_nothing() {
+        let mut test_bank = TestBank::new();
+        test_bank.fund(10_000 * TOKEN);
+        test_bank
+            .run(test_bank.admin_operation(), crate::instruction::SetInterestFreeLoanThreshold { threshold: 100 * TOKEN })
+            .unwrap();
+        let below = test_bank.wallet(1_000 * TOKEN);
+        let at = test_bank.wallet(1_000 * TOKEN);
+        test_bank.deposit(&below, 1_000 * TOKEN).unwrap();
+        test_bank.deposit(&at, 1_000 * TOKEN).unwrap();
+        test_bank.borrow(&below, 100 * TOKEN - 1).unwrap();
+        test_bank.borrow(&at, 100 * TOKEN).unwrap();
+        test_bank.warp(SLOTS_PER_YEAR);
 
-#[event]
-pub struct WithdrawEvent {
-    pub user: Pubkey,
-    pub amount: u64,
-    pub new_balance: u64,
-}
+        test_bank.repay_all_loans(&below, &[0]).unwrap();
+        assert_eq!(test_bank.events::<RepayEvent>()[0].interest, 0);
 
-#[event]
-pub struct StakeEvent {
-    pub user: Pubkey,
-    pub amount: u64,
-    pub total_staked: u64,
-}
+        // The threshold itself is already charged the lending rate
+        test_bank.repay_all_loans(&at, &[0]).unwrap();
+        assert_eq!(test_bank.events::<RepayEvent>()[0].interest, 13 * TOKEN);
+    }
 
-#[event]
-pub struct UnstakeEvent {
-    pub user: Pubkey,
-    pub amount: u64,
-    pub reward: u64,
-    pub remaining_staked: u64,
-}
+    #[test]
+    fn apy_change_applies_only_from_the_change_onward() {
+        let mut test_bank = TestBank::new();
+        test_bank.fund(1_000 * TOKEN);
+        let staker = test_bank.wallet(3_650 * TOKEN);
+        test_bank.deposit(&staker, 3_650 * TOKEN).unwrap();
+        test_bank.stake(&staker, 3_650 * TOKEN).unwrap();
+        test_bank.warp(10 * SLOTS_PER_DAY);
+        test_bank
+            .run(
+                crate::accounts::AdminClockOperation {
+                    clock: Clock::id(),
+                    admin: test_bank.admin.owner,
+                    bank_account: test_bank.bank,
+                },
+                crate::instruction::SetStakingApy { new_bps: 1_000 },
+            )
+            .unwrap();
+        let changed = &test_bank.events::<ApyChanged>()[0];
+        assert_eq!((changed.old_bps, changed.new_bps), (500, 1_000));
 
-#[event]
-pub struct BorrowEvent {
-    pub user: Pubkey,
-    pub amount: u64,
-    pub collateral_used: u64,
-}
+        // Ten days at 0.5 a day under the old rate, then ten at 1 a day
+        test_bank.warp(10 * SLOTS_PER_DAY);
+        test_bank.claim_rewards(&staker).unwrap();
+        assert_eq!(test_bank.events::<ClaimRewardEvent>()[0].reward, 5 * TOKEN + 10 * TOKEN);
+    }
 
-#[event]
-pub struct RepayEvent {
-    pub user: Pubkey,
-    pub principal: u64,
-    pub interest: u64,
-    pub total_repayment: u64,
-}
+    #[test]
+    fn lock_tiers_raise_the_apy_until_the_lock_ends() {
+        let mut test_bank = TestBank::new();
+        test_bank.fund(1_000 * TOKEN);
+        let staker = test_bank.wallet(3_650 * TOKEN);
+        let long_staker = test_bank.wallet(3_650 * TOKEN);
+        test_bank.deposit(&staker, 3_650 * TOKEN).unwrap();
+        test_bank.deposit(&long_staker, 3_650 * TOKEN).unwrap();
+        let stake_locked = |test_bank: &mut TestBank, wallet: &Wallet, lock_slots: u64| {
+            test_bank
+                .run(test_bank.staking(wallet), crate::instruction::StakeLocked { amount: 3_650 * TOKEN, lock_slots })
+                .unwrap();
+            let event = &test_bank.events::<StakeEvent>()[0];
+            (event.lock_tier, event.lock_end_slot)
+        };
+        let start = test_bank.slot;
+        assert_eq!(stake_locked(&mut test_bank, &staker, 30 * SLOTS_PER_DAY), (1, start + 30 * SLOTS_PER_DAY));
+        assert_eq!(stake_locked(&mut test_bank, &long_staker, 90 * SLOTS_PER_DAY), (2, start + 90 * SLOTS_PER_DAY));
 
-#[event]
-pub struct TransferEvent {
-    pub from: Pubkey,
-    pub to: Pubkey,
-    pub amount: u64,
-}
+        // The lock holds the whole position until lock_end_slot
+        let unstake = |test_bank: &mut TestBank| {
+            test_bank.run(test_bank.staking(&staker), crate::instruction::Unstake { amount: 3_650 * TOKEN })
+        };
+        test_bank.warp(29 * SLOTS_PER_DAY);
+        assert_eq!(unstake(&mut test_bank), Err(program_error(ErrorCode::MinimumStakingPeriodNotMet)));
 
-#[event]
-pub struct BankStatusChanged {
-    pub admin: Pubkey,
-    pub is_operational: bool,
-}
+        // 30 days at 1.25x the 0.5 a day base rate, then 10 unlocked days at the base rate
+        test_bank.warp(11 * SLOTS_PER_DAY);
+        unstake(&mut test_bank).unwrap();
+        assert_eq!(test_bank.events::<UnstakeEvent>()[0].reward, 30 * TOKEN / 2 * 5 / 4 + 5 * TOKEN);
+    }
 
-#[event]
-pub struct BankFundsAdded {
-    pub admin: Pubkey,
-    pub amount: u64,
-    pub new_balance: u64,
+    #[test]
+    fn staked_collateral_backs_borrowing_and_stays_locked() {
+        let mut test_bank = TestBank::new();
+        test_bank.fund(10_000 * TOKEN);
+        let borrower = test_bank.wallet(1_000 * TOKEN);
+        test_bank.deposit(&borrower, 1_000 * TOKEN).unwrap();
+        test_bank.stake(&borrower, 500 * TOKEN).unwrap();
+        test_bank.warp(SLOTS_PER_DAY);
+
+        // 500 liquid plus 90% of 500 staked at an 80% ratio, well past the 400 the balance alone allows
+        assert_eq!(test_bank.borrow(&borrower, 760 * TOKEN + 1), Err(program_error(ErrorCode::InvalidCollateralRatio)));
+        test_bank.borrow(&borrower, 700 * TOKEN).unwrap();
+
+        // The 875 locked is backed by all 450 of stake collateral and 425 of the 1200 balance
+        let unstake = |test_bank: &mut TestBank, amount: u64| {
+            test_bank.run(test_bank.staking(&borrower), crate::instruction::Unstake { amount })
+        };
+        assert_eq!(unstake(&mut test_bank, TOKEN), Err(program_error(ErrorCode::InvalidCollateralRatio)));
+        assert_eq!(test_bank.withdraw(&borrower, 775 * TOKEN + 1), Err(program_error(ErrorCode::InvalidCollateralRatio)));
+        assert_eq!(test_bank.withdraw(&borrower, 1_200 * TOKEN + 1), Err(program_error(ErrorCode::InsufficientBalance)));
+        test_bank.withdraw(&borrower, 775 * TOKEN).unwrap();
+
+        // Repaying the loan releases the stake
+        test_bank.deposit(&borrower, 400 * TOKEN).unwrap();
+        test_bank.repay_all_loans(&borrower, &[0]).unwrap();
+        assert_eq!(test_bank.user_state(&borrower).locked_staked_balance, 0);
+        unstake(&mut test_bank, 500 * TOKEN).unwrap();
+    }
 }
\ No newline at end of file